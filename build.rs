@@ -0,0 +1,11 @@
+use std::error::Error;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // build without a system-wide protoc
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    // the generated `connect` needs the 2021 prelude, and a client is as
+    // easily made from a `Channel`
+    tonic_build::configure().build_transport(false).compile_protos(&["proto/kvs.proto"], &["proto"])?;
+
+    Ok(())
+}
@@ -0,0 +1,320 @@
+//! An async client for the JSON protocol, for use on a tokio runtime
+use crate::client::{in_keyspace, outcome, timed_out};
+use crate::errors::{KvError, Result};
+use crate::{KvRequest, KvResponse, JSON_PROTOCOL_VERSION};
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::io;
+use std::ops::Bound;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// Largest JSON message accepted
+const MAX_MESSAGE_LEN: usize = 64 * 1024 * 1024;
+
+/// Bytes read from the socket at a time
+const READ_CHUNK: usize = 8 * 1024;
+
+/// Reads a stream of JSON values, which like the protocol itself need not be
+/// separated by anything, without blocking the runtime
+pub(crate) struct JsonReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    scan: Scan,
+}
+
+impl<R: AsyncRead + Unpin> JsonReader<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        JsonReader {
+            reader,
+            buf: Vec::new(),
+            scan: Scan::default(),
+        }
+    }
+
+    /// Reads the next value, or `None` if the stream ended between values
+    pub(crate) async fn next<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        loop {
+            if self.scan.pos == 0 {
+                let blank = self.buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+                self.buf.drain(..blank);
+            }
+            // a value is only parsed once all of it has arrived
+            if self.scan.complete(&self.buf) {
+                return self.parse().map(Some);
+            }
+            if self.buf.len() > MAX_MESSAGE_LEN {
+                return Err(KvError::MalformedRequest);
+            }
+
+            let mut chunk = [0; READ_CHUNK];
+            let read = self.reader.read(&mut chunk).await?;
+            if read == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                // a number or literal may end with the stream
+                return self.parse().map(Some).map_err(|e| match e {
+                    KvError::Serde(e) if e.is_eof() => {
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "Stream ended within a value").into()
+                    }
+                    e => e,
+                });
+            }
+            self.buf.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    /// Parses the value at the start of the buffer and removes it
+    fn parse<T: DeserializeOwned>(&mut self) -> Result<T> {
+        let mut values = serde_json::Deserializer::from_slice(&self.buf).into_iter::<T>();
+        let value = values.next().ok_or(KvError::MalformedRequest)??;
+        let used = values.byte_offset();
+        self.buf.drain(..used);
+        self.scan = Scan::default();
+
+        Ok(value)
+    }
+}
+
+/// Looks for the end of the value at the start of a buffer, carrying on
+/// from where it stopped as more of the value arrives
+#[derive(Default)]
+struct Scan {
+    pos: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl Scan {
+    /// Whether `buf`, which starts with a value, holds all of it. A number
+    /// or literal is known to be complete once a byte follows it.
+    fn complete(&mut self, buf: &[u8]) -> bool {
+        while let Some(&byte) = buf.get(self.pos) {
+            self.pos += 1;
+            if self.in_string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => {
+                        self.in_string = false;
+                        if self.depth == 0 {
+                            return true;
+                        }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' => {
+                    self.depth = self.depth.saturating_sub(1);
+                    if self.depth == 0 {
+                        return true;
+                    }
+                }
+                _ if self.depth == 0 && self.pos > 1 && !is_scalar_byte(byte) => return true,
+                _ => {}
+            }
+        }
+
+        false
+    }
+}
+
+fn is_scalar_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'+' || byte == b'.'
+}
+
+/// Async counterpart of `KvsClient`: a connection to a `KvServer` or an
+/// `AsyncKvServer` that carries any number of requests, each answered before
+/// the next is sent, without holding up a thread while it waits.
+pub struct AsyncKvsClient {
+    reader: JsonReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+    keyspace: Option<String>,
+    timeout: Option<Duration>,
+    /// Whether `KvRequest::Hello` has been answered, as with `KvsClient`
+    greeted: bool,
+    broken: bool,
+}
+
+impl AsyncKvsClient {
+    /// Connects to the server at `addr`
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        // requests are small and sent as soon as they are ready
+        stream.set_nodelay(true)?;
+        let (reader, writer) = stream.into_split();
+
+        Ok(AsyncKvsClient {
+            reader: JsonReader::new(reader),
+            writer: BufWriter::new(writer),
+            keyspace: None,
+            timeout: None,
+            greeted: false,
+            broken: false,
+        })
+    }
+
+    /// Sets how long a request may take, from sending it to receiving the
+    /// response, before failing with `KvError::Timeout`. `None`, the default,
+    /// waits indefinitely.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Runs later requests against the named keyspace, or outside of any
+    /// keyspace for `None`
+    pub fn set_keyspace(&mut self, keyspace: Option<&str>) {
+        self.keyspace = keyspace.map(String::from);
+    }
+
+    /// Whether an earlier request left the connection unusable, as with `KvsClient`
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// Gets the value of a key, or `None` if it does not exist
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.request(KvRequest::Get(key)).await
+    }
+
+    /// Sets the value of a key
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.request(KvRequest::Set(key, value)).await.map(|_| ())
+    }
+
+    /// Removes a key, failing with `KvError::KeyNotFound` if it does not exist
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        self.request(KvRequest::Rm(key)).await.map(|_| ())
+    }
+
+    /// Returns up to `limit` key-value pairs within the bounds, in key order
+    pub async fn scan(
+        &mut self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let pairs = self.request(KvRequest::Scan(start, end, limit)).await?;
+
+        Ok(serde_json::from_str(&pairs.ok_or(KvError::InternalError)?)?)
+    }
+
+    /// Runs the requests in order with a single round trip, returning the
+    /// outcome of each as `request` would
+    pub async fn batch(&mut self, requests: Vec<KvRequest>) -> Result<Vec<Result<Option<String>>>> {
+        match self.call(KvRequest::Batch(requests)).await? {
+            KvResponse::Batch(responses) => Ok(responses.into_iter().map(outcome).collect()),
+            response => outcome(response).and(Err(KvError::InternalError)),
+        }
+    }
+
+    /// Sends a request that is answered with a single response, returning the
+    /// value it carries
+    pub async fn request(&mut self, request: KvRequest) -> Result<Option<String>> {
+        outcome(self.call(request).await?)
+    }
+
+    /// Sends a request whose responses are streamed, such as
+    /// `KvRequest::Watch`, and returns them as they arrive. The stream takes
+    /// over the connection and is not subject to the timeout.
+    pub async fn stream(mut self, request: KvRequest) -> Result<AsyncResponses> {
+        self.check_connected()?;
+        within(self.timeout, async {
+            self.send(request).await?;
+            self.greet().await
+        })
+        .await?;
+
+        Ok(AsyncResponses {
+            reader: self.reader,
+            _writer: self.writer,
+        })
+    }
+
+    async fn call(&mut self, request: KvRequest) -> Result<KvResponse> {
+        self.check_connected()?;
+        let res = within(self.timeout, self.exchange(request)).await;
+        if res.is_err() {
+            self.broken = true;
+        }
+
+        res
+    }
+
+    fn check_connected(&self) -> Result<()> {
+        if self.broken {
+            let err = io::Error::new(io::ErrorKind::NotConnected, "Connection lost by an earlier request");
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+
+    async fn exchange(&mut self, request: KvRequest) -> Result<KvResponse> {
+        self.send(request).await?;
+        self.greet().await?;
+        self.receive().await
+    }
+
+    async fn greet(&mut self) -> Result<()> {
+        if !self.greeted {
+            self.receive().await?;
+            self.greeted = true;
+        }
+
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<KvResponse> {
+        self.reader
+            .next()
+            .await?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Server closed the connection").into())
+    }
+
+    async fn send(&mut self, request: KvRequest) -> Result<()> {
+        if !self.greeted {
+            let hello = KvRequest::Hello(JSON_PROTOCOL_VERSION);
+            self.writer.write_all(&serde_json::to_vec(&hello)?).await?;
+        }
+        let request = in_keyspace(&self.keyspace, request);
+        self.writer.write_all(&serde_json::to_vec(&request)?).await?;
+        self.writer.flush().await?;
+
+        Ok(())
+    }
+}
+
+/// Runs one exchange with the server, failing with `KvError::Timeout` if it
+/// does not finish in time
+async fn within<T, F: Future<Output = Result<T>>>(timeout: Option<Duration>, exchange: F) -> Result<T> {
+    let res = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, exchange).await.unwrap_or(Err(KvError::Timeout)),
+        None => exchange.await,
+    };
+
+    res.map_err(timed_out)
+}
+
+/// The responses to a streaming request sent by `AsyncKvsClient::stream`
+pub struct AsyncResponses {
+    reader: JsonReader<OwnedReadHalf>,
+    /// Kept open, as the server ends the stream once the client stops sending
+    _writer: BufWriter<OwnedWriteHalf>,
+}
+
+impl AsyncResponses {
+    /// Waits for the next response, or returns `None` once the server has
+    /// closed the connection
+    pub async fn next(&mut self) -> Option<Result<KvResponse>> {
+        self.reader.next().await.transpose()
+    }
+}
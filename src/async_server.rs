@@ -0,0 +1,235 @@
+//! Kv Server on an async runtime
+//!
+//! `AsyncKvServer` speaks the same JSON protocol as `KvServer`, plain or
+//! tagged, but serves every connection as a task on non-blocking sockets, so
+//! that idle and persistent connections take no thread. Engine calls, which
+//! block, run on the runtime's blocking pool, and each streaming request on a
+//! thread of its own. Tagged requests are always answered in order. The
+//! binary protocol and the other front ends are only served by `KvServer`.
+use crate::async_client::JsonReader;
+use crate::errors::{KvError, Result};
+use crate::kv_engine::KvsEngine;
+use crate::pubsub::Broker;
+use crate::server::{outcome, Incoming, Outcome, ShutdownHandle, STREAM_TICK};
+use crate::kv_protocol::encode_json;
+use crate::{KvRequest, KvResponse, JSON_PROTOCOL_VERSION};
+use slog::Logger;
+use std::fmt::Display;
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, Notify};
+
+/// How long a connection may go without sending a request before it is closed
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Responses a streaming request may get ahead of a client that is slow to read them
+const STREAM_BUFFER: usize = 1024;
+
+/// The Kv Server, on the tokio runtime it is run on
+pub struct AsyncKvServer<E: KvsEngine> {
+    engine: E,
+    logger: Logger,
+    shutdown: ShutdownHandle,
+    broker: Broker,
+    idle_timeout: Duration,
+    backup_dir: Option<PathBuf>,
+}
+
+impl<E: KvsEngine> AsyncKvServer<E> {
+    /// Create new server
+    pub fn new(engine: E, logger: Logger) -> Self {
+        AsyncKvServer {
+            engine,
+            logger,
+            shutdown: ShutdownHandle::default(),
+            broker: Broker::default(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            backup_dir: None,
+        }
+    }
+
+    /// Sets how long a connection may stay idle between requests before the
+    /// server closes it. Streaming connections are not subject to it.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Lets clients request backups, as `KvServer::set_backup_dir` does
+    pub fn set_backup_dir<D: Into<PathBuf>>(&mut self, dir: D) {
+        self.backup_dir = Some(dir.into());
+    }
+
+    /// Returns a handle that can be used to stop the server once it is running
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Listens on the given address until a shutdown is requested, then
+    /// flushes and closes the engine. Must be awaited on a tokio runtime.
+    pub async fn run<A: Display + ToSocketAddrs>(self, addr: A) -> Result<()> {
+        debug!(&self.logger, "Listening on {}", &addr);
+        let listener = TcpListener::bind(addr).await?;
+        let stopped = Arc::new(Notify::new());
+        let stop = stopped.clone();
+        self.shutdown.on_shutdown(move || stop.notify_one());
+
+        while !self.shutdown.is_requested() {
+            let stream = tokio::select! {
+                _ = stopped.notified() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        error!(&self.logger, "Failed to accept connection: {}", e);
+                        continue;
+                    }
+                },
+            };
+            let (engine, broker, backup_dir) = (self.engine.clone(), self.broker.clone(), self.backup_dir.clone());
+            let idle_timeout = self.idle_timeout;
+            tokio::spawn(async move {
+                let _ = handle_client(engine, broker, backup_dir, stream, idle_timeout).await;
+            });
+        }
+
+        info!(&self.logger, "Shutting down, closing engine");
+        self.broker.close();
+        let engine = self.engine.clone();
+        blocking(move || engine.close()).await
+    }
+}
+
+/// Serves the requests of one connection in order until the client closes it
+/// or stays idle for too long. A streaming request takes over the connection.
+async fn handle_client<E: KvsEngine>(
+    engine: E,
+    broker: Broker,
+    backup_dir: Option<PathBuf>,
+    stream: TcpStream,
+    idle_timeout: Duration,
+) -> Result<()> {
+    // responses are small and written as soon as they are ready
+    stream.set_nodelay(true)?;
+    let (reader, writer) = stream.into_split();
+    let mut requests = JsonReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    // the version of the protocol the client speaks, until it says otherwise
+    let mut version = 1;
+
+    loop {
+        let incoming = match tokio::time::timeout(idle_timeout, requests.next::<Incoming>()).await {
+            Ok(Ok(Some(incoming))) => incoming,
+            // the client went away or timed out
+            Ok(Ok(None)) | Ok(Err(KvError::Io(_))) | Err(_) => return Ok(()),
+            // the rest of the stream cannot be trusted after malformed input
+            Ok(Err(e)) => return send(&mut writer, None, KvResponse::Error(e.into()), version).await,
+        };
+        let (id, req) = match incoming {
+            Incoming::Tagged(tagged) => (Some(tagged.id), tagged.request),
+            Incoming::Plain(req) => (None, req),
+        };
+
+        if let KvRequest::Hello(requested) = req {
+            version = requested.clamp(1, JSON_PROTOCOL_VERSION);
+            send(&mut writer, id, KvResponse::Success(Some(version.to_string())), version).await?;
+            continue;
+        }
+        if let KvRequest::Subscribe(patterns) = req {
+            let subscription = broker.subscribe(patterns);
+            let mut lagged = false;
+            let next = move |timeout| match subscription.recv_timeout(timeout) {
+                Ok((channel, message)) => Ok(KvResponse::Message(channel, message)),
+                // a subscriber that fell behind is told so before the connection is closed
+                Err(RecvTimeoutError::Disconnected) if subscription.lagged() && !lagged => {
+                    lagged = true;
+                    Ok(KvResponse::Error(KvError::Lagged.into()))
+                }
+                Err(e) => Err(e),
+            };
+            send(&mut writer, id, KvResponse::Success(None), version).await?;
+            return forward(&mut requests, &mut writer, id, next, version).await;
+        }
+
+        let (engine, broker, backup_dir) = (engine.clone(), broker.clone(), backup_dir.clone());
+        let outcome = blocking(move || Ok(outcome(&engine, &broker, backup_dir.as_deref(), req))).await?;
+        match outcome {
+            Outcome::Response(response) => send(&mut writer, id, response, version).await?,
+            Outcome::Stream(ack, mut changes) => {
+                if ack {
+                    send(&mut writer, id, KvResponse::Success(None), version).await?;
+                }
+                let next = move |timeout| changes.recv_timeout(timeout);
+                return forward(&mut requests, &mut writer, id, next, version).await;
+            }
+        }
+    }
+}
+
+/// Writes the responses of a streaming request as they come, until they end
+/// or the client goes away. They block while waiting, so a thread of their
+/// own produces them rather than the blocking pool, which they could exhaust.
+/// `next` waits at most the given time for a response, so that the thread
+/// stops soon after the client has gone.
+async fn forward<F>(
+    requests: &mut JsonReader<OwnedReadHalf>,
+    writer: &mut BufWriter<OwnedWriteHalf>,
+    id: Option<u64>,
+    mut next: F,
+    version: u16,
+) -> Result<()>
+where
+    F: FnMut(Duration) -> std::result::Result<KvResponse, RecvTimeoutError> + Send + 'static,
+{
+    let (tx, mut rx) = mpsc::channel(STREAM_BUFFER);
+    thread::spawn(move || loop {
+        match next(STREAM_TICK) {
+            Ok(response) => {
+                if tx.blocking_send(response).is_err() {
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) if tx.is_closed() => break,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    });
+    loop {
+        tokio::select! {
+            response = rx.recv() => match response {
+                Some(response) => send(writer, id, response, version).await?,
+                None => return Ok(()),
+            },
+            // requests sent during a stream are not answered, so reading only
+            // tells when the client has gone
+            incoming = requests.next::<Incoming>() => match incoming {
+                Ok(Some(_)) => {}
+                Ok(None) | Err(_) => return Ok(()),
+            },
+        }
+    }
+}
+
+/// Writes a response in the version of the protocol the client speaks
+async fn send(writer: &mut BufWriter<OwnedWriteHalf>, id: Option<u64>, response: KvResponse, version: u16) -> Result<()> {
+    writer.write_all(&encode_json(id, response, version)?).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Runs `f` on the runtime's blocking pool
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| KvError::Io(io::Error::other(e)))?
+}
@@ -0,0 +1,324 @@
+//! Online full and incremental backup and restore of a `KvStore`
+use crate::changes::{write_change_log, CHANGES_FILE};
+use crate::errors::{KvError, Result};
+use crate::fsck;
+use crate::kv::{format_log_path, keyspace_names, log_generations, KvStore, LogSnapshot, KEYSPACE_DIR};
+use crate::kv_engine::KvsEngine;
+use crate::secondary_index::INDEX_FILE;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const MANIFEST: &str = "backup.manifest";
+
+/// A position in the store's logs
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BackupPosition {
+    /// Generation of the log file
+    pub gen: u64,
+    /// Byte offset within the log file
+    pub offset: u64,
+}
+
+/// A log file, or part of one, copied into a backup
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupFile {
+    /// Generation of the log file
+    pub gen: u64,
+    /// Offset in the original log file of the first copied byte
+    pub offset: u64,
+    /// Number of bytes copied
+    pub len: u64,
+    /// CRC32 of the copied bytes
+    pub checksum: u32,
+}
+
+/// Describes the contents of a backup directory
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// Where this backup continues from, `None` for a full backup
+    pub since: Option<BackupPosition>,
+    /// Position just past the last byte copied
+    pub end: BackupPosition,
+    /// The copied log files, in generation order
+    pub files: Vec<BackupFile>,
+}
+
+impl KvStore {
+    /// Writes a consistent copy of the store into `dir` while it keeps serving
+    /// requests. The generations present when the backup starts are pinned so
+    /// compaction cannot delete them, and only the prefix of the active log
+    /// written so far is copied.
+    pub fn backup_to(&self, dir: impl AsRef<Path>) -> Result<BackupManifest> {
+        self.write_backup(dir.as_ref(), None)
+    }
+
+    /// Writes only the records added since the backup in `previous` into `dir`.
+    ///
+    /// Generations are append-only, so this is the tail of the log that was
+    /// active during the previous backup plus every newer generation. If that
+    /// log has since been compacted away a full backup is written instead,
+    /// which `restore_chain` treats as a new base.
+    pub fn backup_incremental(
+        &self,
+        previous: impl AsRef<Path>,
+        dir: impl AsRef<Path>,
+    ) -> Result<BackupManifest> {
+        self.write_backup(dir.as_ref(), Some(previous.as_ref()))
+    }
+
+    /// Backs up the store's logs followed by each of its keyspaces, which are
+    /// written to `keyspaces/<name>` inside the backup. The store and its
+    /// keyspaces are pinned together, so the backup is of a single point in
+    /// time. The manifest is written last so that an interrupted backup is
+    /// never mistaken for a complete one.
+    fn write_backup(&self, dir: &Path, previous: Option<&Path>) -> Result<BackupManifest> {
+        let since = match previous {
+            Some(previous) => Some(read_manifest(previous)?.end),
+            None => None,
+        };
+        std::fs::create_dir_all(dir)?;
+        if dir.join(MANIFEST).exists() || !log_generations(dir)?.is_empty() {
+            return Err(KvError::VerificationFailed(format!(
+                "{} already contains a backup",
+                dir.display()
+            )));
+        }
+
+        let names = self.keyspaces()?;
+        let keyspaces = names.iter().map(|name| self.keyspace(name)).collect::<Result<Vec<_>>>()?;
+        let stores: Vec<&KvStore> = std::iter::once(self).chain(&keyspaces).collect();
+        let mut snapshots = KvStore::pin_snapshots(&stores)?.into_iter();
+
+        let manifest = copy_snapshot(&snapshots.next().ok_or(KvError::InternalError)?, dir, since)?;
+        for (name, snapshot) in names.iter().zip(snapshots) {
+            let since = match previous.map(|p| p.join(KEYSPACE_DIR).join(name)) {
+                Some(previous) if previous.join(MANIFEST).exists() => Some(read_manifest(previous)?.end),
+                _ => None,
+            };
+            let keyspace_dir = dir.join(KEYSPACE_DIR).join(name);
+            std::fs::create_dir_all(&keyspace_dir)?;
+            let keyspace_manifest = copy_snapshot(&snapshot, &keyspace_dir, since)?;
+            write_manifest(&keyspace_dir, &keyspace_manifest)?;
+        }
+
+        write_manifest(dir, &manifest)?;
+
+        Ok(manifest)
+    }
+
+    /// Validates the backup in `backup_dir` and installs it as a new store at
+    /// `store_path`, which must not already contain a store.
+    pub fn restore(backup_dir: impl AsRef<Path>, store_path: impl AsRef<Path>) -> Result<()> {
+        KvStore::restore_chain(&[backup_dir], store_path)
+    }
+
+    /// Validates a full backup followed by a chain of incremental backups and
+    /// installs the result as a new store at `store_path`, which must not
+    /// already contain a store. Each incremental must continue exactly where
+    /// the previous backup in the chain ended.
+    pub fn restore_chain(backups: &[impl AsRef<Path>], store_path: impl AsRef<Path>) -> Result<()> {
+        let store_path = store_path.as_ref();
+        if backups.is_empty() {
+            return Err(KvError::VerificationFailed("no backups to restore".to_owned()));
+        }
+        if store_path.exists() && store_path.read_dir()?.next().is_some() {
+            return Err(KvError::VerificationFailed(format!(
+                "{} is not empty",
+                store_path.display()
+            )));
+        }
+
+        // stage next to the target so the final rename stays on one filesystem
+        let staging = staging_path(store_path);
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging)?;
+        }
+        std::fs::create_dir_all(&staging)?;
+        if let Err(e) = stage_store(backups, &staging) {
+            std::fs::remove_dir_all(&staging)?;
+            return Err(e);
+        }
+
+        if store_path.exists() {
+            std::fs::remove_dir(store_path)?;
+        }
+        std::fs::rename(&staging, store_path)?;
+
+        Ok(())
+    }
+}
+
+/// Copies the logs of the snapshot into `dir`, only from `since` on if it
+/// is still part of the snapshot
+fn copy_snapshot(snapshot: &LogSnapshot, dir: &Path, since: Option<BackupPosition>) -> Result<BackupManifest> {
+    let since = since.filter(|pos| snapshot.logs.iter().any(|&(gen, _)| gen == pos.gen));
+    let (end_gen, end_len) = *snapshot.logs.last().ok_or(KvError::InternalError)?;
+    let mut manifest = BackupManifest {
+        since,
+        end: BackupPosition { gen: end_gen, offset: end_len },
+        files: Vec::new(),
+    };
+    for &(gen, len) in &snapshot.logs {
+        let offset = match since {
+            Some(pos) if gen < pos.gen => continue,
+            Some(pos) if gen == pos.gen => pos.offset,
+            _ => 0,
+        };
+        if offset > len {
+            return Err(KvError::VerificationFailed(format!(
+                "{}.log is shorter than the previous backup",
+                gen
+            )));
+        }
+        let mut src = File::open(format_log_path(&snapshot.store_path, gen))?;
+        src.seek(SeekFrom::Start(offset))?;
+        let dst = File::create(format_log_path(dir, gen))?;
+        let checksum = copy_with_checksum(src.take(len - offset), &dst)?;
+        dst.sync_all()?;
+        manifest.files.push(BackupFile { gen, offset, len: len - offset, checksum });
+    }
+    copy_index_definitions(&snapshot.store_path, dir)?;
+    write_change_log(dir, &snapshot.changes)?;
+
+    Ok(manifest)
+}
+
+/// Stages the store and every keyspace in the most recent backup of the chain
+fn stage_store(backups: &[impl AsRef<Path>], staging: &Path) -> Result<()> {
+    stage_chain(backups, staging)?;
+
+    let last = backups.last().ok_or(KvError::InternalError)?.as_ref();
+    for name in keyspace_names(last)? {
+        let chain: Vec<PathBuf> = backups
+            .iter()
+            .map(|b| b.as_ref().join(KEYSPACE_DIR).join(&name))
+            .filter(|p| p.exists())
+            .collect();
+        let keyspace_staging = staging.join(KEYSPACE_DIR).join(&name);
+        std::fs::create_dir_all(&keyspace_staging)?;
+        stage_chain(&chain, &keyspace_staging)?;
+    }
+
+    Ok(())
+}
+
+/// Replays every backup in the chain into `staging` and checks the result
+fn stage_chain(backups: &[impl AsRef<Path>], staging: &Path) -> Result<()> {
+    let mut end: Option<BackupPosition> = None;
+    for backup_dir in backups {
+        let backup_dir = backup_dir.as_ref();
+        let manifest = read_manifest(backup_dir)?;
+        match (manifest.since, end) {
+            // a full backup starts the chain over
+            (None, _) => {
+                for gen in log_generations(staging)? {
+                    std::fs::remove_file(format_log_path(staging, gen))?;
+                }
+            }
+            (Some(since), Some(end)) if since == end => {}
+            _ => {
+                return Err(KvError::VerificationFailed(format!(
+                    "{} does not continue the previous backup",
+                    backup_dir.display()
+                )))
+            }
+        }
+
+        for file in &manifest.files {
+            let log_path = format_log_path(staging, file.gen);
+            let dst = OpenOptions::new().create(true).append(true).open(&log_path)?;
+            if dst.metadata()?.len() != file.offset {
+                return Err(KvError::VerificationFailed(format!(
+                    "{}.log in {} does not continue the previous backup",
+                    file.gen,
+                    backup_dir.display()
+                )));
+            }
+            let src = File::open(format_log_path(backup_dir, file.gen))?;
+            let checksum = copy_with_checksum(src.take(file.len), &dst)?;
+            dst.sync_all()?;
+            if dst.metadata()?.len() != file.offset + file.len || checksum != file.checksum {
+                return Err(KvError::VerificationFailed(format!(
+                    "{}.log in {} does not match the backup manifest",
+                    file.gen,
+                    backup_dir.display()
+                )));
+            }
+        }
+        end = Some(manifest.end);
+    }
+
+    if let Some(last) = backups.last() {
+        copy_index_definitions(last.as_ref(), staging)?;
+        copy_change_log(last.as_ref(), staging)?;
+    }
+
+    let report = fsck::check(staging)?;
+    if let Some(problem) = report.problems.first() {
+        return Err(KvError::VerificationFailed(problem.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Reads the manifest of the backup in `dir`
+pub fn read_manifest(dir: impl AsRef<Path>) -> Result<BackupManifest> {
+    let file = File::open(dir.as_ref().join(MANIFEST))?;
+
+    Ok(serde_json::from_reader(file)?)
+}
+
+fn write_manifest(dir: &Path, manifest: &BackupManifest) -> Result<()> {
+    let tmp_path = dir.join(format!("{}.tmp", MANIFEST));
+    let tmp = File::create(&tmp_path)?;
+    serde_json::to_writer(&tmp, manifest)?;
+    tmp.sync_all()?;
+    std::fs::rename(&tmp_path, dir.join(MANIFEST))?;
+
+    Ok(())
+}
+
+/// Copies the secondary index definitions, the indexes themselves are
+/// rebuilt when the store is opened
+fn copy_index_definitions(from: &Path, to: &Path) -> Result<()> {
+    let definitions = from.join(INDEX_FILE);
+    if definitions.exists() {
+        std::fs::copy(definitions, to.join(INDEX_FILE))?;
+    }
+
+    Ok(())
+}
+
+/// Copies the change log, so that the restored store numbers its changes on
+/// from the backup. Backups taken before they held one restore without it.
+fn copy_change_log(from: &Path, to: &Path) -> Result<()> {
+    let log = from.join(CHANGES_FILE);
+    if log.exists() {
+        std::fs::copy(log, to.join(CHANGES_FILE))?;
+    }
+
+    Ok(())
+}
+
+fn staging_path(store_path: &Path) -> PathBuf {
+    let mut name = store_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".restore");
+    store_path.with_file_name(name)
+}
+
+fn copy_with_checksum(mut src: impl Read, mut dst: &File) -> Result<u32> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = [0; 8192];
+    loop {
+        let n = src.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        dst.write_all(&buf[..n])?;
+    }
+
+    Ok(hasher.finalize())
+}
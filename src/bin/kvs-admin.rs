@@ -0,0 +1,437 @@
+extern crate clap;
+#[macro_use]
+extern crate slog;
+extern crate slog_async;
+extern crate slog_term;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use kvs::bulk_load;
+use kvs::export::{self, Format, KeyRange};
+use kvs::fsck;
+use kvs::migrate::{self, MigrationReport};
+use kvs::{KvRequest, KvStore, KvsClient, KvsEngine, Result, SledEngine};
+use slog::Drain;
+use slog::Logger;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::ops::Bound;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+
+const MIGRATE_CHECKPOINT: &str = "migrate.checkpoint";
+
+fn valid_engine(engine: String) -> std::result::Result<(), String> {
+    if engine == "kvs" || engine == "sled" {
+        return Ok(());
+    }
+    Err(String::from("Only kvs or sled are supported as an engine"))
+}
+
+fn valid_format(format: String) -> std::result::Result<(), String> {
+    match format.parse::<Format>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(String::from("Only jsonl, csv or bin are supported as a format")),
+    }
+}
+
+fn valid_ip(ip: String) -> std::result::Result<(), String> {
+    match ip.to_socket_addrs() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(String::from("Invalid address provided")),
+    }
+}
+
+fn init_logger() -> Logger {
+    let decorator = slog_term::TermDecorator::new().stderr().build();
+    let drain = slog_term::CompactFormat::new(decorator).build().fuse();
+    let drain = slog_async::Async::new(drain).build().fuse();
+
+    slog::Logger::root(drain, o!())
+}
+
+fn main() -> Result<()> {
+    let logger = init_logger();
+    info!(logger, "Kvs admin started"; "version" => env!("CARGO_PKG_VERSION"));
+
+    let engine_arg = Arg::with_name("engine")
+        .long("engine")
+        .value_name("ENGINE")
+        .default_value("kvs")
+        .validator(valid_engine);
+    let keyspace_arg = Arg::with_name("keyspace")
+        .long("keyspace")
+        .value_name("NAME")
+        .help("Use the keys of this keyspace rather than the store's own");
+    let format_arg = Arg::with_name("format")
+        .long("format")
+        .value_name("FORMAT")
+        .default_value("jsonl")
+        .validator(valid_format);
+    let matches = App::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .author("Junxuan")
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Copy every key from one store into another")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("ENGINE")
+                        .required(true)
+                        .validator(valid_engine),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("ENGINE")
+                        .required(true)
+                        .validator(valid_engine),
+                )
+                .arg(Arg::with_name("src").required(true).index(1))
+                .arg(Arg::with_name("dst").required(true).index(2)),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .about("Check a kvs store for damaged records")
+                .arg(Arg::with_name("dir").required(true).index(1))
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .value_name("OUT")
+                        .help("Write the salvageable records into a new store at OUT"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Write a consistent backup of a kvs store")
+                .arg(
+                    Arg::with_name("addr")
+                        .long("addr")
+                        .value_name("ADDR")
+                        .validator(valid_ip)
+                        .help(
+                            "Ask a running server to write the backup instead of opening the store. \
+                             DIR and PREVIOUS then name backups within the server's --backup-dir",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("incremental")
+                        .long("incremental")
+                        .value_name("PREVIOUS")
+                        .help("Only copy what changed since the backup in PREVIOUS"),
+                )
+                .arg(Arg::with_name("store").required_unless("addr").index(1))
+                .arg(Arg::with_name("dir").required_unless("addr").index(2)),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Validate a backup and install it as a new kvs store")
+                .arg(
+                    Arg::with_name("incremental")
+                        .long("incremental")
+                        .value_name("DIR")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Incremental backup to apply after the base, in order"),
+                )
+                .arg(Arg::with_name("backup").required(true).index(1))
+                .arg(Arg::with_name("store").required(true).index(2)),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write the contents of a store to a file")
+                .arg(&engine_arg)
+                .arg(&format_arg)
+                .arg(&keyspace_arg)
+                .arg(Arg::with_name("prefix").long("prefix").value_name("PREFIX"))
+                .arg(Arg::with_name("start").long("start").value_name("KEY"))
+                .arg(Arg::with_name("end").long("end").value_name("KEY"))
+                .arg(Arg::with_name("store").required(true).index(1))
+                .arg(Arg::with_name("file").index(2).help("Defaults to stdout")),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Load the records in a file into a store")
+                .arg(&engine_arg)
+                .arg(&format_arg)
+                .arg(&keyspace_arg)
+                .arg(Arg::with_name("store").required(true).index(1))
+                .arg(Arg::with_name("file").index(2).help("Defaults to stdin")),
+        )
+        .subcommand(
+            SubCommand::with_name("bulk-load")
+                .about("Write a file straight into a new compacted generation of a kvs store")
+                .arg(&format_arg)
+                .arg(&keyspace_arg)
+                .arg(
+                    Arg::with_name("sorted")
+                        .long("sorted")
+                        .help("The file is already sorted by key, stream it instead of sorting in memory"),
+                )
+                .arg(Arg::with_name("store").required(true).index(1))
+                .arg(Arg::with_name("file").index(2).help("Defaults to stdin")),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        ("migrate", Some(m)) => run_migrate(m, &logger),
+        ("fsck", Some(m)) => run_fsck(m, &logger),
+        ("backup", Some(m)) => run_backup(m, &logger),
+        ("restore", Some(m)) => run_restore(m, &logger),
+        ("export", Some(m)) => run_export(m, &logger),
+        ("import", Some(m)) => run_import(m, &logger),
+        ("bulk-load", Some(m)) => run_bulk_load(m, &logger),
+        _ => std::process::exit(1),
+    }
+}
+
+fn run_migrate(m: &ArgMatches, logger: &Logger) -> Result<()> {
+    let from = m.value_of("from").unwrap();
+    let to = m.value_of("to").unwrap();
+    let src = m.value_of("src").unwrap();
+    let dst = m.value_of("dst").unwrap();
+    info!(logger, "Migrating store"; "from" => from, "to" => to, "src" => src, "dst" => dst);
+
+    if !Path::new(src).exists() {
+        eprintln!("Source store {} does not exist", src);
+        std::process::exit(1);
+    }
+    if !compatible_engine(from, src) || !compatible_engine(to, dst) {
+        eprintln!("Store does not match the given engine");
+        std::process::exit(1);
+    }
+
+    let report = match (from, to) {
+        ("sled", "sled") => migrate_stores(SledEngine::open(src)?, SledEngine::open(dst)?, dst),
+        ("sled", _) => migrate_stores(SledEngine::open(src)?, KvStore::open(dst)?, dst),
+        (_, "sled") => migrate_stores(KvStore::open(src)?, SledEngine::open(dst)?, dst),
+        _ => migrate_stores(KvStore::open(src)?, KvStore::open(dst)?, dst),
+    };
+
+    match report {
+        Ok(report) => {
+            info!(logger, "Migration complete"; "keys" => report.count);
+            println!("Migrated {} keys, checksum {:08x}", report.count, report.checksum);
+            for (name, keyspace) in &report.keyspaces {
+                println!("Migrated {} keys of keyspace {}, checksum {:08x}", keyspace.count, name, keyspace.checksum);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            error!(logger, "Migration failed: {}", e);
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_fsck(m: &ArgMatches, logger: &Logger) -> Result<()> {
+    let dir = m.value_of("dir").unwrap();
+    info!(logger, "Checking store"; "dir" => dir);
+    if !Path::new(dir).exists() || !compatible_engine("kvs", dir) {
+        eprintln!("{} is not a kvs store", dir);
+        std::process::exit(1);
+    }
+
+    let report = match m.value_of("repair") {
+        Some(out) => fsck::repair(dir, out)?,
+        None => fsck::check(dir)?,
+    };
+    print_fsck_report("", &report);
+    for (name, keyspace) in &report.keyspaces {
+        print_fsck_report(&format!("keyspace {}: ", name), keyspace);
+    }
+    if let Some(out) = m.value_of("repair") {
+        let live_keys = report.live_keys + report.keyspaces.iter().map(|(_, k)| k.live_keys).sum::<u64>();
+        println!("Wrote {} live keys to {}", live_keys, out);
+    }
+
+    if report.is_clean() {
+        Ok(())
+    } else {
+        std::process::exit(2);
+    }
+}
+
+fn print_fsck_report(label: &str, report: &fsck::FsckReport) {
+    for problem in &report.problems {
+        println!("{}{}", label, problem);
+    }
+    println!(
+        "{}{} generations, {} records, {} live keys, {} problems",
+        label,
+        report.generations.len(),
+        report.records,
+        report.live_keys,
+        report.problems.len()
+    );
+}
+
+fn run_backup(m: &ArgMatches, logger: &Logger) -> Result<()> {
+    let previous = m.value_of("incremental");
+    let res = match (m.value_of("addr"), m.value_of("store")) {
+        (Some(addr), _) => {
+            // without a store, the only positional argument is the backup's name
+            let dir = match m.value_of("dir").or_else(|| m.value_of("store")) {
+                Some(dir) => dir,
+                None => {
+                    eprintln!("Name the backup to write within the server's backup directory");
+                    std::process::exit(1);
+                }
+            };
+            info!(logger, "Requesting backup"; "addr" => addr, "dir" => dir);
+            let request = match previous {
+                Some(previous) => KvRequest::IncrementalBackup(previous.to_string(), dir.to_string()),
+                None => KvRequest::Backup(dir.to_string()),
+            };
+            KvsClient::connect(addr)?.request(request).map(|_| ()).map_err(|err| err.to_string())
+        }
+        (None, Some(store)) => {
+            let dir = m.value_of("dir").unwrap();
+            info!(logger, "Backing up store"; "store" => store, "dir" => dir);
+            if !Path::new(store).exists() || !compatible_engine("kvs", store) {
+                eprintln!("{} is not a kvs store", store);
+                std::process::exit(1);
+            }
+            let store = KvStore::open(store)?;
+            match previous {
+                Some(previous) => store.backup_incremental(previous, dir),
+                None => store.backup_to(dir),
+            }
+            .map(|manifest| info!(logger, "Backup complete"; "files" => manifest.files.len()))
+                .map_err(|e| e.to_string())
+        }
+        _ => unreachable!(),
+    };
+
+    res.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    Ok(())
+}
+
+fn run_restore(m: &ArgMatches, logger: &Logger) -> Result<()> {
+    let backup = m.value_of("backup").unwrap();
+    let store = m.value_of("store").unwrap();
+    let mut chain = vec![backup];
+    chain.extend(m.values_of("incremental").into_iter().flatten());
+    info!(logger, "Restoring backup"; "backup" => backup, "incrementals" => chain.len() - 1, "store" => store);
+
+    if let Err(e) = KvStore::restore_chain(&chain, store) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_export(m: &ArgMatches, logger: &Logger) -> Result<()> {
+    let engine = m.value_of("engine").unwrap();
+    let store = m.value_of("store").unwrap();
+    let format = m.value_of("format").unwrap().parse()?;
+    let range = KeyRange {
+        start: m.value_of("start").map_or(Bound::Unbounded, |k| Bound::Included(k.to_string())),
+        end: m.value_of("end").map_or(Bound::Unbounded, |k| Bound::Excluded(k.to_string())),
+        prefix: m.value_of("prefix").map(str::to_string),
+    };
+    info!(logger, "Exporting store"; "engine" => engine, "store" => store);
+    if !Path::new(store).exists() || !compatible_engine(engine, store) {
+        eprintln!("{} is not a {} store", store, engine);
+        std::process::exit(1);
+    }
+
+    let writer: Box<dyn io::Write> = match m.value_of("file") {
+        Some(file) => Box::new(BufWriter::new(File::create(file)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    let keyspace = m.value_of("keyspace");
+    let count = match engine {
+        "sled" => export::export(&in_keyspace(SledEngine::open(store)?, keyspace, false)?, &range, format, writer)?,
+        _ => export::export(&in_keyspace(KvStore::open(store)?, keyspace, false)?, &range, format, writer)?,
+    };
+    info!(logger, "Export complete"; "records" => count);
+
+    Ok(())
+}
+
+fn run_import(m: &ArgMatches, logger: &Logger) -> Result<()> {
+    let engine = m.value_of("engine").unwrap();
+    let store = m.value_of("store").unwrap();
+    let format = m.value_of("format").unwrap().parse()?;
+    info!(logger, "Importing into store"; "engine" => engine, "store" => store);
+    if !compatible_engine(engine, store) {
+        eprintln!("{} is not a {} store", store, engine);
+        std::process::exit(1);
+    }
+
+    let reader: Box<dyn io::Read> = match m.value_of("file") {
+        Some(file) => Box::new(File::open(file)?),
+        None => Box::new(io::stdin()),
+    };
+    let keyspace = m.value_of("keyspace");
+    let count = match engine {
+        "sled" => export::import(&in_keyspace(SledEngine::open(store)?, keyspace, true)?, format, reader)?,
+        _ => export::import(&in_keyspace(KvStore::open(store)?, keyspace, true)?, format, reader)?,
+    };
+    info!(logger, "Import complete"; "records" => count);
+
+    Ok(())
+}
+
+fn run_bulk_load(m: &ArgMatches, logger: &Logger) -> Result<()> {
+    let store = m.value_of("store").unwrap();
+    let format = m.value_of("format").unwrap().parse()?;
+    info!(logger, "Bulk loading into store"; "store" => store);
+    if !compatible_engine("kvs", store) {
+        eprintln!("{} is not a kvs store", store);
+        std::process::exit(1);
+    }
+
+    let reader: Box<dyn io::Read> = match m.value_of("file") {
+        Some(file) => Box::new(File::open(file)?),
+        None => Box::new(io::stdin()),
+    };
+    let dir = match m.value_of("keyspace") {
+        Some(name) => bulk_load::keyspace_dir(store, name)?,
+        None => PathBuf::from(store),
+    };
+    let records = export::records(format, reader)?;
+    let res = if m.is_present("sorted") {
+        bulk_load::bulk_load_sorted(dir, records)
+    } else {
+        bulk_load::bulk_load(dir, records)
+    };
+
+    match res {
+        Ok(report) => {
+            info!(logger, "Bulk load complete"; "gen" => report.gen, "keys" => report.keys);
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Opens the named keyspace, if any, creating it only when `create` is set
+fn in_keyspace<E: KvsEngine>(engine: E, keyspace: Option<&str>, create: bool) -> Result<E> {
+    match keyspace {
+        Some(name) if create => engine.keyspace(name),
+        Some(name) => engine.existing_keyspace(name),
+        None => Ok(engine),
+    }
+}
+
+fn migrate_stores<S: KvsEngine, D: KvsEngine>(src: S, dst: D, dst_path: &str) -> Result<MigrationReport> {
+    migrate::migrate(&src, &dst, Path::new(dst_path).join(MIGRATE_CHECKPOINT))
+}
+
+fn compatible_engine(engine: &str, path: &str) -> bool {
+    let db_path = Path::new(path).join("db");
+    if !Path::new(path).exists() {
+        return true;
+    }
+
+    db_path.exists() == (engine == "sled")
+}
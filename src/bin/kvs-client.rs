@@ -4,11 +4,11 @@ extern crate slog;
 extern crate slog_async;
 extern crate slog_term;
 
-use clap::{App, Arg, SubCommand};
-use kvs::{KvError, KvRequest, KvResponse, Result};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use kvs::changes::ChangeOp;
+use kvs::{KvError, KvRequest, KvResponse, KvsClient, Result, Watched};
 use slog::Drain;
-use std::io::{self, BufReader, BufWriter};
-use std::net::{TcpStream, ToSocketAddrs};
+use std::net::ToSocketAddrs;
 
 fn init_logger() -> slog::Logger {
     let decorator = slog_term::TermDecorator::new().stderr().build();
@@ -25,6 +25,20 @@ fn valid_ip(ip: String) -> std::result::Result<(), String> {
     }
 }
 
+fn valid_integer(n: String) -> std::result::Result<(), String> {
+    match n.parse::<i64>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(String::from("Must be an integer")),
+    }
+}
+
+fn valid_size(size: String) -> std::result::Result<(), String> {
+    match size.parse::<usize>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(String::from("Must be a non-negative integer")),
+    }
+}
+
 fn main() -> Result<()> {
     let logger = init_logger();
     info!(logger, "Kvs client started"; "version" => env!("CARGO_PKG_VERSION"));
@@ -34,46 +48,215 @@ fn main() -> Result<()> {
         .value_name("ADDR")
         .default_value("127.0.0.1:4000")
         .validator(valid_ip);
+    let delta_arg = Arg::with_name("delta")
+        .index(2)
+        .default_value("1")
+        .allow_hyphen_values(true)
+        .validator(valid_integer);
+    let keyspace_arg = Arg::with_name("keyspace")
+        .long("keyspace")
+        .value_name("KEYSPACE");
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author("Junxuan")
         .subcommand(
             SubCommand::with_name("set")
                 .arg(&address_arg)
+                .arg(&keyspace_arg)
                 .arg(Arg::with_name("key").required(true).index(1))
                 .arg(Arg::with_name("value").required(true).index(2)),
         )
         .subcommand(
             SubCommand::with_name("get")
                 .arg(&address_arg)
+                .arg(&keyspace_arg)
                 .arg(Arg::with_name("key").required(true).index(1)),
         )
         .subcommand(
             SubCommand::with_name("rm")
                 .arg(&address_arg)
+                .arg(&keyspace_arg)
+                .arg(Arg::with_name("key").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("append")
+                .arg(&address_arg)
+                .arg(&keyspace_arg)
+                .arg(Arg::with_name("key").required(true).index(1))
+                .arg(Arg::with_name("value").required(true).index(2)),
+        )
+        .subcommand(
+            SubCommand::with_name("getrange")
+                .arg(&address_arg)
+                .arg(&keyspace_arg)
+                .arg(Arg::with_name("key").required(true).index(1))
+                .arg(Arg::with_name("offset").required(true).index(2).validator(valid_size))
+                .arg(Arg::with_name("len").required(true).index(3).validator(valid_size)),
+        )
+        .subcommand(
+            SubCommand::with_name("hset")
+                .arg(&address_arg)
+                .arg(&keyspace_arg)
+                .arg(Arg::with_name("key").required(true).index(1))
+                .arg(Arg::with_name("field").required(true).index(2))
+                .arg(Arg::with_name("value").required(true).index(3)),
+        )
+        .subcommand(
+            SubCommand::with_name("hget")
+                .arg(&address_arg)
+                .arg(&keyspace_arg)
+                .arg(Arg::with_name("key").required(true).index(1))
+                .arg(Arg::with_name("field").required(true).index(2)),
+        )
+        .subcommand(
+            SubCommand::with_name("hgetall")
+                .arg(&address_arg)
+                .arg(&keyspace_arg)
+                .arg(Arg::with_name("key").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("lpush")
+                .arg(&address_arg)
+                .arg(&keyspace_arg)
+                .arg(Arg::with_name("key").required(true).index(1))
+                .arg(Arg::with_name("value").required(true).index(2)),
+        )
+        .subcommand(
+            SubCommand::with_name("rpop")
+                .arg(&address_arg)
+                .arg(&keyspace_arg)
                 .arg(Arg::with_name("key").required(true).index(1)),
         )
+        .subcommand(
+            SubCommand::with_name("lrange")
+                .arg(&address_arg)
+                .arg(&keyspace_arg)
+                .arg(Arg::with_name("key").required(true).index(1))
+                .arg(
+                    Arg::with_name("start")
+                        .required(true)
+                        .index(2)
+                        .allow_hyphen_values(true)
+                        .validator(valid_integer),
+                )
+                .arg(
+                    Arg::with_name("stop")
+                        .required(true)
+                        .index(3)
+                        .allow_hyphen_values(true)
+                        .validator(valid_integer),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sadd")
+                .arg(&address_arg)
+                .arg(&keyspace_arg)
+                .arg(Arg::with_name("key").required(true).index(1))
+                .arg(Arg::with_name("value").required(true).index(2)),
+        )
+        .subcommand(
+            SubCommand::with_name("smembers")
+                .arg(&address_arg)
+                .arg(&keyspace_arg)
+                .arg(Arg::with_name("key").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("create-index")
+                .arg(&address_arg)
+                .arg(&keyspace_arg)
+                .arg(Arg::with_name("name").required(true).index(1))
+                .arg(Arg::with_name("path").required(true).index(2)),
+        )
+        .subcommand(
+            SubCommand::with_name("drop-index")
+                .arg(&address_arg)
+                .arg(&keyspace_arg)
+                .arg(Arg::with_name("name").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("query-index")
+                .arg(&address_arg)
+                .arg(&keyspace_arg)
+                .arg(Arg::with_name("name").required(true).index(1))
+                .arg(Arg::with_name("value").required(true).index(2)),
+        )
+        .subcommand(
+            SubCommand::with_name("changes")
+                .arg(&address_arg)
+                .arg(&keyspace_arg)
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("SEQ")
+                        .default_value("0")
+                        .validator(valid_size),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .arg(&address_arg)
+                .arg(&keyspace_arg)
+                .arg(Arg::with_name("key").required(true).index(1))
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .help("Watch every key starting with KEY"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("publish")
+                .arg(&address_arg)
+                .arg(Arg::with_name("channel").required(true).index(1))
+                .arg(Arg::with_name("message").required(true).index(2)),
+        )
+        .subcommand(
+            SubCommand::with_name("subscribe")
+                .arg(&address_arg)
+                .arg(
+                    Arg::with_name("pattern")
+                        .required(true)
+                        .multiple(true)
+                        .index(1)
+                        .help("Channel name, or pattern where * and ? are wildcards"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("incr")
+                .arg(&address_arg)
+                .arg(&keyspace_arg)
+                .arg(Arg::with_name("key").required(true).index(1))
+                .arg(delta_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("decr")
+                .arg(&address_arg)
+                .arg(&keyspace_arg)
+                .arg(Arg::with_name("key").required(true).index(1))
+                .arg(delta_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("drop-keyspace")
+                .arg(&address_arg)
+                .arg(Arg::with_name("keyspace").required(true).index(1)),
+        )
         .subcommand(SubCommand::with_name("version"))
         .get_matches();
 
     match matches.subcommand() {
         ("get", Some(m)) => {
             let key = m.value_of("key").unwrap().to_string();
-            let addr = m.value_of("addr").unwrap();
-            let connection = new_connection(addr, &logger)?;
+            let mut client = connect(m, &logger)?;
             info!(logger, "Get key: {}", key);
-            let res = handle_get(connection, key)?;
-            match res {
-                KvResponse::Success(value) => {
-                    if let Some(v) = value {
-                        println!("{}", v);
-                        Ok(())
-                    } else {
-                        println!("Key not found");
-                        std::process::exit(0);
-                    }
+            match client.get(key) {
+                Ok(Some(value)) => {
+                    println!("{}", value);
+                    Ok(())
+                }
+                Ok(None) | Err(KvError::KeyNotFound) => {
+                    println!("Key not found");
+                    std::process::exit(0);
                 }
-                KvResponse::Error(err) => {
+                Err(err) => {
                     println!("{}", err);
                     std::process::exit(1);
                 }
@@ -82,10 +265,9 @@ fn main() -> Result<()> {
         ("set", Some(m)) => {
             let key = m.value_of("key").unwrap().to_string();
             let value = m.value_of("value").unwrap().to_string();
-            let addr = m.value_of("addr").unwrap();
-            let connection = new_connection(addr, &logger)?;
+            let mut client = connect(m, &logger)?;
             info!(logger, "Set key: {} to value: {}", key, value);
-            if let KvResponse::Error(err) = handle_set(connection, key, value)? {
+            if let Err(err) = client.set(key, value) {
                 println!("{}", err);
                 std::process::exit(1);
             } else {
@@ -94,52 +276,194 @@ fn main() -> Result<()> {
         }
         ("rm", Some(m)) => {
             let key = m.value_of("key").unwrap().to_string();
-            let addr = m.value_of("addr").unwrap();
-            let connection = new_connection(addr, &logger)?;
+            let mut client = connect(m, &logger)?;
             info!(logger, "Remove key: {}", key);
-            if let KvResponse::Error(err) = handle_rm(connection, key)? {
+            if let Err(err) = client.remove(key) {
                 eprintln!("{}", err);
                 std::process::exit(1);
             } else {
                 Ok(())
             }
         }
+        ("append", Some(m)) => {
+            let key = m.value_of("key").unwrap().to_string();
+            let value = m.value_of("value").unwrap().to_string();
+            let mut client = connect(m, &logger)?;
+            info!(logger, "Append to key: {} value: {}", key, value);
+            print_value(client.request(KvRequest::Append(key, value)))
+        }
+        ("getrange", Some(m)) => {
+            let key = m.value_of("key").unwrap().to_string();
+            let offset = m.value_of("offset").unwrap().parse::<usize>().unwrap();
+            let len = m.value_of("len").unwrap().parse::<usize>().unwrap();
+            let mut client = connect(m, &logger)?;
+            info!(logger, "Get range of key: {} from {} length {}", key, offset, len);
+            match client.request(KvRequest::GetRange(key, offset, len)) {
+                Ok(None) => {
+                    println!("Key not found");
+                    Ok(())
+                }
+                res => print_value(res),
+            }
+        }
+        (cmd @ "hset", Some(m))
+        | (cmd @ "hget", Some(m))
+        | (cmd @ "hgetall", Some(m))
+        | (cmd @ "lpush", Some(m))
+        | (cmd @ "rpop", Some(m))
+        | (cmd @ "lrange", Some(m))
+        | (cmd @ "sadd", Some(m))
+        | (cmd @ "smembers", Some(m)) => {
+            let key = m.value_of("key").unwrap().to_string();
+            let arg = |name| m.value_of(name).unwrap().to_string();
+            let index = |name| m.value_of(name).unwrap().parse::<i64>().unwrap();
+            let mut client = connect(m, &logger)?;
+            info!(logger, "{} key: {}", cmd, key);
+            let req = match cmd {
+                "hset" => KvRequest::HSet(key, arg("field"), arg("value")),
+                "hget" => KvRequest::HGet(key, arg("field")),
+                "hgetall" => KvRequest::HGetAll(key),
+                "lpush" => KvRequest::LPush(key, arg("value")),
+                "rpop" => KvRequest::RPop(key),
+                "lrange" => KvRequest::LRange(key, index("start"), index("stop")),
+                "sadd" => KvRequest::SAdd(key, arg("value")),
+                _ => KvRequest::SMembers(key),
+            };
+            print_value(client.request(req))
+        }
+        (cmd @ "create-index", Some(m)) | (cmd @ "drop-index", Some(m)) | (cmd @ "query-index", Some(m)) => {
+            let name = m.value_of("name").unwrap().to_string();
+            let arg = |name| m.value_of(name).unwrap().to_string();
+            let mut client = connect(m, &logger)?;
+            info!(logger, "{} index: {}", cmd, name);
+            let req = match cmd {
+                "create-index" => KvRequest::CreateIndex(name, arg("path")),
+                "drop-index" => KvRequest::DropIndex(name),
+                _ => KvRequest::QueryIndex(name, arg("value")),
+            };
+            print_value(client.request(req))
+        }
+        ("changes", Some(m)) => {
+            let from = m.value_of("from").unwrap().parse::<u64>().unwrap();
+            let client = connect(m, &logger)?;
+            info!(logger, "Streaming changes from {}", from);
+            for res in client.stream(KvRequest::Changes(from))? {
+                match res {
+                    Ok(KvResponse::Change(change)) => println!("{}", serde_json::to_string(&change)?),
+                    Ok(KvResponse::Error(err)) => exit_with(err.into()),
+                    Ok(_) => return Err(KvError::InternalError),
+                    Err(err) => exit_with(err),
+                }
+            }
+            Ok(())
+        }
+        ("watch", Some(m)) => {
+            let key = m.value_of("key").unwrap().to_string();
+            let watched = if m.is_present("prefix") {
+                Watched::Prefix(key)
+            } else {
+                Watched::Key(key)
+            };
+            let client = connect(m, &logger)?;
+            info!(logger, "Watching {:?}", watched);
+            for res in client.stream(KvRequest::Watch(watched))? {
+                match res {
+                    Ok(KvResponse::Success(_)) => debug!(logger, "Watch established"),
+                    Ok(KvResponse::Change(change)) => match change.op {
+                        ChangeOp::Set(value) => println!("set {} {}", change.key, value),
+                        ChangeOp::Append(value) => println!("append {} {}", change.key, value),
+                        ChangeOp::Remove => println!("rm {}", change.key),
+                        ChangeOp::Modified(value_type) => println!("modified {} {}", change.key, value_type),
+                    },
+                    Ok(KvResponse::Error(err)) => exit_with(err.into()),
+                    Ok(_) => return Err(KvError::InternalError),
+                    Err(err) => exit_with(err),
+                }
+            }
+            Ok(())
+        }
+        ("publish", Some(m)) => {
+            let channel = m.value_of("channel").unwrap().to_string();
+            let message = m.value_of("message").unwrap().to_string();
+            let mut client = connect(m, &logger)?;
+            info!(logger, "Publish to channel: {}", channel);
+            match client.request(KvRequest::Publish(channel, message)) {
+                Ok(receivers) => {
+                    println!("{}", receivers.unwrap_or_default());
+                    Ok(())
+                }
+                Err(err) => exit_with(err),
+            }
+        }
+        ("subscribe", Some(m)) => {
+            let patterns: Vec<String> = m.values_of("pattern").unwrap().map(String::from).collect();
+            let client = connect(m, &logger)?;
+            info!(logger, "Subscribing to {:?}", patterns);
+            for res in client.stream(KvRequest::Subscribe(patterns))? {
+                match res {
+                    Ok(KvResponse::Success(_)) => debug!(logger, "Subscribed"),
+                    Ok(KvResponse::Message(channel, message)) => println!("{} {}", channel, message),
+                    Ok(KvResponse::Error(err)) => exit_with(err.into()),
+                    Ok(_) => return Err(KvError::InternalError),
+                    Err(err) => exit_with(err),
+                }
+            }
+            Ok(())
+        }
+        (cmd @ "incr", Some(m)) | (cmd @ "decr", Some(m)) => {
+            let key = m.value_of("key").unwrap().to_string();
+            let delta = m.value_of("delta").unwrap().parse::<i64>().unwrap();
+            let mut client = connect(m, &logger)?;
+            info!(logger, "{} key: {} by {}", cmd, key, delta);
+            let req = match cmd {
+                "incr" => KvRequest::Incr(key, delta),
+                _ => KvRequest::Decr(key, delta),
+            };
+            match client.request(req) {
+                Ok(value) => {
+                    println!("{}", value.unwrap_or_default());
+                    Ok(())
+                }
+                Err(err) => exit_with(err),
+            }
+        }
+        ("drop-keyspace", Some(m)) => {
+            let name = m.value_of("keyspace").unwrap().to_string();
+            let mut client = connect(m, &logger)?;
+            info!(logger, "Drop keyspace: {}", name);
+            print_value(client.request(KvRequest::DropKeyspace(name)))
+        }
         _ => std::process::exit(1),
     }
 }
 
-fn new_connection(addr: &str, logger: &slog::Logger) -> io::Result<TcpStream> {
+/// Connects to the server given by `--addr`, using the keyspace given by `--keyspace`
+fn connect(m: &ArgMatches, logger: &slog::Logger) -> Result<KvsClient> {
+    let addr = m.value_of("addr").unwrap();
     info!(logger, "Parsed configuration"; "addr" => addr);
 
-    let connection = TcpStream::connect(addr)?;
+    let mut client = KvsClient::connect(addr)?;
     debug!(logger, "Connected to {}", addr);
-    Ok(connection)
-}
+    // requests outside of keyspaces, such as dropping one, are left as they are
+    client.set_keyspace(m.value_of("keyspace"));
 
-fn handle_get(connection: TcpStream, key: String) -> Result<KvResponse> {
-    let req = KvRequest::Get(key);
-    send_request(connection, req)
+    Ok(client)
 }
 
-fn handle_set(connection: TcpStream, key: String, value: String) -> Result<KvResponse> {
-    let req = KvRequest::Set(key, value);
-    send_request(connection, req)
-}
-
-fn handle_rm(connection: TcpStream, key: String) -> Result<KvResponse> {
-    let req = KvRequest::Rm(key);
-    send_request(connection, req)
+/// Prints the value the server responded with, if any
+fn print_value(res: Result<Option<String>>) -> Result<()> {
+    match res {
+        Ok(value) => {
+            if let Some(v) = value {
+                println!("{}", v);
+            }
+            Ok(())
+        }
+        Err(err) => exit_with(err),
+    }
 }
 
-fn send_request(connection: TcpStream, request: KvRequest) -> Result<KvResponse> {
-    let writer = BufWriter::new(&connection);
-    let reader = BufReader::new(&connection);
-
-    serde_json::to_writer(writer, &request)?;
-
-    let mut response_stream =
-        serde_json::Deserializer::from_reader(reader).into_iter::<KvResponse>();
-    let res = response_stream.next().ok_or(KvError::InternalError)??;
-
-    Ok(res)
+fn exit_with(err: KvError) -> ! {
+    eprintln!("{}", err);
+    std::process::exit(1);
 }
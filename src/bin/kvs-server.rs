@@ -1,16 +1,18 @@
 extern crate clap;
+extern crate ctrlc;
 #[macro_use]
 extern crate slog;
 extern crate slog_async;
 extern crate slog_term;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 use kvs::server::KvServer;
-use kvs::{KvStore, Result, SharedQueueThreadPool, SledEngine, ThreadPool};
+use kvs::{KvError, KvStore, KvsEngine, Result, SharedQueueThreadPool, SledEngine, ThreadPool};
 use slog::Drain;
 use slog::Logger;
 use std::net::ToSocketAddrs;
 use std::path::Path;
+use std::time::Duration;
 
 fn valid_engine(engine: String) -> std::result::Result<(), String> {
     if engine == "kvs" || engine == "sled" {
@@ -28,6 +30,20 @@ fn valid_ip(ip: String) -> std::result::Result<(), String> {
     }
 }
 
+fn valid_seconds(secs: String) -> std::result::Result<(), String> {
+    match secs.parse::<u64>() {
+        Ok(secs) if secs > 0 => Ok(()),
+        _ => Err(String::from("Must be a positive number of seconds")),
+    }
+}
+
+fn valid_count(count: String) -> std::result::Result<(), String> {
+    match count.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(()),
+        _ => Err(String::from("Must be a positive number")),
+    }
+}
+
 fn init_logger() -> Logger {
     let decorator = slog_term::TermDecorator::new().stderr().build();
     let drain = slog_term::CompactFormat::new(decorator).build().fuse();
@@ -57,6 +73,49 @@ fn main() -> Result<()> {
                 .default_value("kvs")
                 .validator(valid_engine),
         )
+        .arg(
+            Arg::with_name("resp-addr")
+                .long("resp-addr")
+                .value_name("ADDR")
+                .help("Also serve Redis clients on this address")
+                .validator(valid_ip),
+        )
+        .arg(
+            Arg::with_name("http-addr")
+                .long("http-addr")
+                .value_name("ADDR")
+                .help("Also serve the HTTP/JSON gateway on this address")
+                .validator(valid_ip),
+        )
+        .arg(
+            Arg::with_name("grpc-addr")
+                .long("grpc-addr")
+                .value_name("ADDR")
+                .help("Also serve gRPC on this address")
+                .validator(valid_ip),
+        )
+        .arg(
+            Arg::with_name("backup-dir")
+                .long("backup-dir")
+                .value_name("DIR")
+                .help("Accept backup requests, writing the backups within this directory"),
+        )
+        .arg(
+            Arg::with_name("idle-timeout")
+                .long("idle-timeout")
+                .value_name("SECONDS")
+                .default_value("30")
+                .help("Close connections that send no request for this long")
+                .validator(valid_seconds),
+        )
+        .arg(
+            Arg::with_name("max-connections")
+                .long("max-connections")
+                .value_name("COUNT")
+                .default_value("1024")
+                .help("Close new connections while this many are served")
+                .validator(valid_count),
+        )
         .get_matches();
 
     let engine = matches.value_of("engine").unwrap();
@@ -68,21 +127,49 @@ fn main() -> Result<()> {
 
     if !compatible_engine(engine, store_path) {
         eprintln!("Server started with incompatible engine.");
+        eprintln!("Use `kvs-admin migrate` to convert the existing store.");
         error!(logger, "{} engine incompatible with existing store", engine);
         std::process::exit(1)
     }
     info!(logger, "Loading store from {}", store_path);
 
     match engine {
-        "sled" => {
-            let server = KvServer::new(SledEngine::open(store_path)?, pool, logger);
-            let _ = server.run(addr);
-        }
-        _ => {
-            let server = KvServer::new(KvStore::open(store_path)?, pool, logger);
-            let _ = server.run(addr);
-        }
-    };
+        "sled" => run_server(SledEngine::open(store_path)?, pool, logger, &matches),
+        _ => run_server(KvStore::open(store_path)?, pool, logger, &matches),
+    }
+}
+
+fn run_server<E: KvsEngine>(
+    engine: E,
+    pool: SharedQueueThreadPool,
+    logger: Logger,
+    matches: &ArgMatches,
+) -> Result<()> {
+    let mut server = KvServer::new(engine, pool, logger.clone());
+    let idle_timeout = matches.value_of("idle-timeout").unwrap().parse().unwrap();
+    server.set_idle_timeout(Duration::from_secs(idle_timeout));
+    server.set_max_connections(matches.value_of("max-connections").unwrap().parse().unwrap());
+    if let Some(resp_addr) = matches.value_of("resp-addr") {
+        info!(logger, "Serving RESP"; "addr" => resp_addr);
+        server.set_resp_addr(resp_addr)?;
+    }
+    if let Some(http_addr) = matches.value_of("http-addr") {
+        info!(logger, "Serving HTTP"; "addr" => http_addr);
+        server.set_http_addr(http_addr)?;
+    }
+    if let Some(grpc_addr) = matches.value_of("grpc-addr") {
+        info!(logger, "Serving gRPC"; "addr" => grpc_addr);
+        server.set_grpc_addr(grpc_addr)?;
+    }
+    if let Some(backup_dir) = matches.value_of("backup-dir") {
+        info!(logger, "Accepting backup requests"; "dir" => backup_dir);
+        server.set_backup_dir(backup_dir);
+    }
+    let handle = server.shutdown_handle();
+    ctrlc::set_handler(move || handle.shutdown()).map_err(|_| KvError::InternalError)?;
+
+    server.run(matches.value_of("addr").unwrap())?;
+    info!(logger, "Server stopped");
 
     Ok(())
 }
@@ -0,0 +1,228 @@
+//! Length-prefixed binary protocol, spoken beside the JSON one
+//!
+//! A connection starts with a handshake: the client sends `MAGIC` followed by
+//! the highest protocol version it speaks as a big-endian `u16`, and the
+//! server answers with `MAGIC` and the version both will use. Since `MAGIC`
+//! cannot begin a JSON value, the server tells the two protocols apart by the
+//! first byte a client sends.
+//!
+//! After the handshake every message is a frame:
+//!
+//! | field   | size | contents                                        |
+//! |---------|------|-------------------------------------------------|
+//! | length  | 4    | bytes following this field, big-endian          |
+//! | opcode  | 1    | what the frame carries, see the `OP_` constants |
+//! | flags   | 1    | see the `FLAG_` constants                       |
+//! | id      | 8    | request ID, echoed in the responses, big-endian |
+//! | payload | rest | depends on the opcode                           |
+//!
+//! The most common requests and responses have their own opcodes with a
+//! compact payload. Every other message is carried as JSON by `OP_REQUEST`
+//! or `OP_RESPONSE`, so the binary protocol covers all of `KvRequest`.
+use crate::errors::{KvError, Result};
+use crate::kv_protocol::LegacyResponse;
+use crate::{KvRequest, KvResponse};
+use std::io::{self, Read, Write};
+
+/// Opens a binary connection. The first byte is not valid at the start of JSON.
+pub const MAGIC: [u8; 4] = [0xB1, b'k', b'v', b's'];
+
+/// The newest protocol version this library speaks. Version 1 sends errors as
+/// a plain message; version 2 sends them as a `ServerError`.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// Largest frame accepted, not counting the length field
+pub const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+
+/// Size of the opcode, flags and id fields
+const HEADER_LEN: usize = 10;
+
+/// Get a key, the payload being the key
+pub const OP_GET: u8 = 0x01;
+/// Set a key, the payload being the key length as a big-endian `u32`, the key and the value
+pub const OP_SET: u8 = 0x02;
+/// Remove a key, the payload being the key
+pub const OP_RM: u8 = 0x03;
+/// Any other request, the payload being a JSON `KvRequest`
+pub const OP_REQUEST: u8 = 0x0F;
+/// A successful response, with the value as payload if `FLAG_VALUE` is set
+pub const OP_OK: u8 = 0x80;
+/// An error response, the payload being a JSON `ServerError`
+pub const OP_ERROR: u8 = 0x81;
+/// Any other response, the payload being a JSON `KvResponse`
+pub const OP_RESPONSE: u8 = 0x8F;
+
+/// On a request: the server may respond before the requests sent ahead of it
+pub const FLAG_UNORDERED: u8 = 0x01;
+/// On an `OP_OK` response: the payload holds a value, which may be empty
+pub const FLAG_VALUE: u8 = 0x02;
+
+/// A single message of the binary protocol
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// What the frame carries
+    pub opcode: u8,
+    /// Modifiers of the opcode
+    pub flags: u8,
+    /// The request ID
+    pub id: u64,
+    /// Contents, encoded according to the opcode
+    pub payload: Vec<u8>,
+}
+
+/// Sends the client's side of the handshake and returns the version the
+/// server chose
+pub fn client_handshake<S: Read + Write>(stream: &mut S) -> Result<u16> {
+    stream.write_all(&MAGIC)?;
+    stream.write_all(&PROTOCOL_VERSION.to_be_bytes())?;
+    stream.flush()?;
+
+    let mut reply = [0; 6];
+    stream.read_exact(&mut reply)?;
+    let version = u16::from_be_bytes([reply[4], reply[5]]);
+    if reply[..4] != MAGIC || version == 0 || version > PROTOCOL_VERSION {
+        return Err(KvError::UnsupportedVersion(version));
+    }
+
+    Ok(version)
+}
+
+/// Answers a client's handshake, choosing the newest version both speak
+pub fn server_handshake<S: Read + Write>(stream: &mut S) -> Result<u16> {
+    let mut hello = [0; 6];
+    stream.read_exact(&mut hello)?;
+    if hello[..4] != MAGIC {
+        return Err(KvError::MalformedRequest);
+    }
+    let version = u16::from_be_bytes([hello[4], hello[5]]).min(PROTOCOL_VERSION);
+    stream.write_all(&MAGIC)?;
+    stream.write_all(&version.to_be_bytes())?;
+    stream.flush()?;
+    if version == 0 {
+        return Err(KvError::UnsupportedVersion(version));
+    }
+
+    Ok(version)
+}
+
+/// Reads the next frame, or `None` if the stream ended between frames
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Frame>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len);
+    if len < HEADER_LEN as u32 || len > MAX_FRAME_LEN {
+        return Err(KvError::MalformedRequest);
+    }
+
+    // the length comes from the peer, so grow the buffer as bytes arrive
+    // rather than allocating it all up front
+    let mut buf = Vec::with_capacity(HEADER_LEN);
+    reader.take(u64::from(len)).read_to_end(&mut buf)?;
+    if buf.len() < len as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    let mut id = [0; 8];
+    id.copy_from_slice(&buf[2..HEADER_LEN]);
+
+    Ok(Some(Frame {
+        opcode: buf[0],
+        flags: buf[1],
+        id: u64::from_be_bytes(id),
+        payload: buf.split_off(HEADER_LEN),
+    }))
+}
+
+/// Writes a frame, leaving flushing to the caller
+pub fn write_frame<W: Write>(writer: &mut W, frame: &Frame) -> Result<()> {
+    let len = HEADER_LEN + frame.payload.len();
+    if len > MAX_FRAME_LEN as usize {
+        return Err(KvError::MalformedRequest);
+    }
+    writer.write_all(&(len as u32).to_be_bytes())?;
+    writer.write_all(&[frame.opcode, frame.flags])?;
+    writer.write_all(&frame.id.to_be_bytes())?;
+    writer.write_all(&frame.payload)?;
+
+    Ok(())
+}
+
+/// Encodes a request with the given ID
+pub fn encode_request(id: u64, request: &KvRequest, unordered: bool) -> Result<Frame> {
+    let (opcode, payload) = match request {
+        KvRequest::Get(key) => (OP_GET, key.as_bytes().to_vec()),
+        KvRequest::Rm(key) => (OP_RM, key.as_bytes().to_vec()),
+        KvRequest::Set(key, value) => {
+            let mut payload = Vec::with_capacity(4 + key.len() + value.len());
+            payload.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            payload.extend_from_slice(key.as_bytes());
+            payload.extend_from_slice(value.as_bytes());
+            (OP_SET, payload)
+        }
+        request => (OP_REQUEST, serde_json::to_vec(request)?),
+    };
+    let flags = if unordered { FLAG_UNORDERED } else { 0 };
+
+    Ok(Frame { opcode, flags, id, payload })
+}
+
+/// Decodes a request frame into its ID, the request, and whether it may be
+/// answered out of order
+pub fn decode_request(frame: Frame) -> Result<(u64, KvRequest, bool)> {
+    let request = match frame.opcode {
+        OP_GET => KvRequest::Get(utf8(frame.payload)?),
+        OP_RM => KvRequest::Rm(utf8(frame.payload)?),
+        OP_SET => {
+            let mut payload = frame.payload;
+            if payload.len() < 4 {
+                return Err(KvError::MalformedRequest);
+            }
+            let key_len = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+            if payload.len() - 4 < key_len {
+                return Err(KvError::MalformedRequest);
+            }
+            let value = payload.split_off(4 + key_len);
+            let key = payload.split_off(4);
+            KvRequest::Set(utf8(key)?, utf8(value)?)
+        }
+        OP_REQUEST => serde_json::from_slice(&frame.payload)?,
+        _ => return Err(KvError::MalformedRequest),
+    };
+
+    Ok((frame.id, request, frame.flags & FLAG_UNORDERED != 0))
+}
+
+/// Encodes a response to the request with the given ID for a peer speaking
+/// the given version of the protocol
+pub fn encode_response(id: u64, response: &KvResponse, version: u16) -> Result<Frame> {
+    let (opcode, flags, payload) = match response {
+        KvResponse::Success(None) => (OP_OK, 0, Vec::new()),
+        KvResponse::Success(Some(value)) => (OP_OK, FLAG_VALUE, value.as_bytes().to_vec()),
+        KvResponse::Error(err) if version < 2 => (OP_ERROR, 0, serde_json::to_vec(&err.message)?),
+        KvResponse::Error(err) => (OP_ERROR, 0, serde_json::to_vec(err)?),
+        response if version < 2 => (OP_RESPONSE, 0, serde_json::to_vec(&LegacyResponse::from(response))?),
+        response => (OP_RESPONSE, 0, serde_json::to_vec(response)?),
+    };
+
+    Ok(Frame { opcode, flags, id, payload })
+}
+
+/// Decodes a response frame into the ID of its request and the response
+pub fn decode_response(frame: Frame) -> Result<(u64, KvResponse)> {
+    let response = match frame.opcode {
+        OP_OK if frame.flags & FLAG_VALUE != 0 => KvResponse::Success(Some(utf8(frame.payload)?)),
+        OP_OK => KvResponse::Success(None),
+        OP_ERROR => KvResponse::Error(serde_json::from_slice(&frame.payload)?),
+        OP_RESPONSE => serde_json::from_slice(&frame.payload)?,
+        _ => return Err(KvError::MalformedRequest),
+    };
+
+    Ok((frame.id, response))
+}
+
+fn utf8(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|_| KvError::MalformedRequest)
+}
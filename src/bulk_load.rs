@@ -0,0 +1,139 @@
+//! Loading large data sets straight into `KvStore` generation files
+use crate::errors::{KvError, Result};
+use crate::kv::{
+    check_keyspace_name, format_hint_path, format_log_path, lock_dir, log_generations, Command, Hint, KEYSPACE_DIR,
+};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Outcome of a bulk load
+#[derive(Debug, Clone, PartialEq)]
+pub struct BulkLoadReport {
+    /// Generation the data was installed as
+    pub gen: u64,
+    /// Number of distinct keys written
+    pub keys: u64,
+    /// Size of the new log file in bytes
+    pub bytes: u64,
+}
+
+/// Writes the pairs, which must be sorted by key, as a single compacted
+/// generation with a hint file and installs it into the store at `dir`.
+///
+/// Fails if the store is open, as it would not see the loaded keys and could
+/// write to the new generation itself. The new generation is numbered
+/// after every existing one, so loaded values replace existing ones. When a
+/// key repeats the last value wins. Fails without touching the store if the
+/// keys are out of order.
+pub fn bulk_load_sorted<I>(dir: impl AsRef<Path>, pairs: I) -> Result<BulkLoadReport>
+where
+    I: IntoIterator<Item = Result<(String, String)>>,
+{
+    let dir = dir.as_ref();
+    std::fs::create_dir_all(dir)?;
+    let _lock = lock_dir(dir)?;
+    let gen = log_generations(dir)?.last().map_or(1, |gen| gen + 1);
+    let log_tmp = tmp_path(format_log_path(dir, gen));
+    let hint_tmp = tmp_path(format_hint_path(dir, gen));
+
+    let res = write_generation(&log_tmp, &hint_tmp, pairs);
+    let (keys, bytes) = match res {
+        Ok(written) => written,
+        Err(e) => {
+            let _ = std::fs::remove_file(&log_tmp);
+            let _ = std::fs::remove_file(&hint_tmp);
+            return Err(e);
+        }
+    };
+
+    // the log is renamed last, a store without it ignores the hint
+    std::fs::rename(&hint_tmp, format_hint_path(dir, gen))?;
+    std::fs::rename(&log_tmp, format_log_path(dir, gen))?;
+    File::open(dir)?.sync_all()?;
+
+    Ok(BulkLoadReport { gen, keys, bytes })
+}
+
+/// The directory of the named keyspace of the store at `dir`, which can be
+/// loaded into like the store itself
+pub fn keyspace_dir(dir: impl AsRef<Path>, name: &str) -> Result<PathBuf> {
+    check_keyspace_name(name)?;
+
+    Ok(dir.as_ref().join(KEYSPACE_DIR).join(name))
+}
+
+/// Like `bulk_load_sorted`, but accepts the pairs in any order by sorting them
+/// in memory first.
+pub fn bulk_load<I>(dir: impl AsRef<Path>, pairs: I) -> Result<BulkLoadReport>
+where
+    I: IntoIterator<Item = Result<(String, String)>>,
+{
+    let mut sorted = BTreeMap::new();
+    for pair in pairs {
+        let (key, value) = pair?;
+        sorted.insert(key, value);
+    }
+
+    bulk_load_sorted(dir, sorted.into_iter().map(Ok))
+}
+
+fn write_generation<I>(log_path: &Path, hint_path: &Path, pairs: I) -> Result<(u64, u64)>
+where
+    I: IntoIterator<Item = Result<(String, String)>>,
+{
+    let log = File::create(log_path)?;
+    let mut writer = BufWriter::new(&log);
+    let mut hint = Hint::default();
+    let mut pending: Option<(String, String)> = None;
+    let mut pos = 0;
+
+    // hold back each pair until the next key is seen so duplicates collapse
+    for pair in pairs {
+        let (key, value) = pair?;
+        if let Some((last_key, last_value)) = pending.take() {
+            if key < last_key {
+                return Err(KvError::VerificationFailed(format!(
+                    "key {:?} is out of order after {:?}",
+                    key, last_key
+                )));
+            }
+            if key != last_key {
+                pos = write_record(&mut writer, &mut hint, pos, last_key, last_value)?;
+            }
+        }
+        pending = Some((key, value));
+    }
+    if let Some((key, value)) = pending {
+        pos = write_record(&mut writer, &mut hint, pos, key, value)?;
+    }
+    writer.flush()?;
+    drop(writer);
+    log.sync_all()?;
+
+    hint.log_len = pos;
+    let hint_file = File::create(hint_path)?;
+    let mut writer = BufWriter::new(&hint_file);
+    serde_json::to_writer(&mut writer, &hint)?;
+    writer.flush()?;
+    drop(writer);
+    hint_file.sync_all()?;
+
+    Ok((hint.entries.len() as u64, pos))
+}
+
+fn write_record<W: Write>(writer: &mut W, hint: &mut Hint, pos: u64, key: String, value: String) -> Result<u64> {
+    let serialized = serde_json::to_vec(&Command::Set(key.clone(), value))?;
+    writer.write_all(&serialized)?;
+    let len = serialized.len() as u64;
+    hint.entries.push((key, pos, len));
+
+    Ok(pos + len)
+}
+
+fn tmp_path(path: PathBuf) -> PathBuf {
+    let mut name = path.into_os_string();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
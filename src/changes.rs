@@ -0,0 +1,364 @@
+//! Change data capture for `KvStore`
+//!
+//! Every mutation is given a sequence number and appended to a change log
+//! next to the store's logs. The change log is kept separately so that it is
+//! unaffected by compaction, and only its most recent entries are retained.
+//! Saving a change is not part of the mutation it records: a change that
+//! could not be saved is still handed to subscribers, and the change log is
+//! restored from the retained changes the next time it is rewritten.
+use crate::errors::{KvError, Result};
+use crate::kv_engine::ValueType;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// File in a store directory holding the retained changes
+pub(crate) const CHANGES_FILE: &str = "changes";
+
+/// Number of changes that can still be subscribed to after they were made
+const RETAINED_CHANGES: usize = 1024;
+
+/// Number of changes a subscriber may fall behind by before it is disconnected
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// A mutation of a key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    /// Position of the change in the store's history, starting at 1
+    pub seq: u64,
+    /// The key that was changed
+    pub key: String,
+    /// What happened to the key
+    pub op: ChangeOp,
+}
+
+/// The kind of mutation recorded by a `Change`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ChangeOp {
+    /// The key was set to the value
+    Set(String),
+    /// The value was appended to the key's value
+    Append(String),
+    /// The key was removed
+    Remove,
+    /// The hash, list or set at the key, of the given type, was modified
+    Modified(ValueType),
+}
+
+/// The changes of a store from a given sequence number on, created by
+/// `KvsEngine::subscribe`.
+///
+/// Iteration blocks until the next change is made. It ends when the store is
+/// closed, or when the subscriber falls too far behind, which `lagged` tells
+/// apart. A subscriber that lagged can subscribe again from the sequence
+/// number after the last change it saw.
+pub struct ChangeStream {
+    backlog: std::vec::IntoIter<Change>,
+    receiver: Receiver<Change>,
+    from: u64,
+    lagged: Arc<AtomicBool>,
+}
+
+impl ChangeStream {
+    /// Whether changes were dropped because the subscriber could not keep up
+    pub fn lagged(&self) -> bool {
+        self.lagged.load(Ordering::SeqCst)
+    }
+
+    /// Waits at most `timeout` for the next change, so that a subscriber can
+    /// stop waiting when it is no longer interested. Fails with
+    /// `Disconnected` where iteration would end.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> result::Result<Change, RecvTimeoutError> {
+        if let Some(change) = self.backlog.next() {
+            return Ok(change);
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            let change = self.receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))?;
+            if change.seq >= self.from {
+                return Ok(change);
+            }
+        }
+    }
+}
+
+impl Iterator for ChangeStream {
+    type Item = Change;
+
+    fn next(&mut self) -> Option<Change> {
+        if let Some(change) = self.backlog.next() {
+            return Some(change);
+        }
+        loop {
+            let change = self.receiver.recv().ok()?;
+            if change.seq >= self.from {
+                return Some(change);
+            }
+        }
+    }
+}
+
+/// Records the changes of one store and hands them out to subscribers
+#[derive(Debug)]
+pub(crate) struct ChangeFeed {
+    state: Arc<Mutex<FeedState>>,
+}
+
+#[derive(Debug)]
+struct FeedState {
+    /// Path of the change log and the log opened for appending, `None` for a
+    /// feed kept in memory
+    log: Option<(PathBuf, File)>,
+    next_seq: u64,
+    tail: VecDeque<Change>,
+    /// Lines in the change log, which is rewritten once it holds twice the retained changes
+    lines: usize,
+    subscribers: Vec<Subscriber>,
+    /// The thread rewriting the change log, if any
+    rewriter: Option<JoinHandle<()>>,
+    /// First failure to save the changes, reported by `close`
+    error: Option<KvError>,
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    sender: SyncSender<Change>,
+    lagged: Arc<AtomicBool>,
+}
+
+impl ChangeFeed {
+    /// Opens the change log of the store at `dir`, rewriting it with just the
+    /// retained changes. A torn final line left by a crash is dropped.
+    pub(crate) fn open(dir: &Path) -> Result<Self> {
+        let path = dir.join(CHANGES_FILE);
+        let mut tail = VecDeque::new();
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                match serde_json::from_str::<Change>(&line?) {
+                    Ok(change) => tail.push_back(change),
+                    Err(_) => break,
+                }
+                if tail.len() > RETAINED_CHANGES {
+                    tail.pop_front();
+                }
+            }
+        }
+        let next_seq = tail.back().map_or(1, |change| change.seq + 1);
+        let file = rewrite(&path, &tail)?;
+
+        Ok(ChangeFeed {
+            state: Arc::new(Mutex::new(FeedState {
+                log: Some((path, file)),
+                next_seq,
+                lines: tail.len(),
+                tail,
+                subscribers: Vec::new(),
+                rewriter: None,
+                error: None,
+            })),
+        })
+    }
+
+    /// Starts a feed that is not saved, so its sequence numbers start over
+    /// every time it is created
+    pub(crate) fn in_memory() -> Self {
+        ChangeFeed {
+            state: Arc::new(Mutex::new(FeedState {
+                log: None,
+                next_seq: 1,
+                lines: 0,
+                tail: VecDeque::new(),
+                subscribers: Vec::new(),
+                rewriter: None,
+                error: None,
+            })),
+        }
+    }
+
+    /// Assigns the next sequence number to a mutation, saves it and sends it
+    /// to the subscribers. Must be called in the order the mutations are logged,
+    /// once they are. A failure to save the change does not undo the mutation,
+    /// so it is kept for `close` to report instead.
+    pub(crate) fn record(&self, key: &str, op: ChangeOp) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let change = Change {
+            seq: state.next_seq,
+            key: key.to_owned(),
+            op,
+        };
+        if let Some((_, file)) = &mut state.log {
+            state.lines += 1;
+            if let Err(e) = append(file, &change) {
+                state.error.get_or_insert(e);
+                // the rewrite saves the change along with the others
+                state.lines = 2 * RETAINED_CHANGES;
+            }
+        }
+        state.next_seq += 1;
+
+        // a subscriber that has fallen behind is dropped rather than stalling writes
+        state.subscribers.retain(|subscriber| match subscriber.sender.try_send(change.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                subscriber.lagged.store(true, Ordering::SeqCst);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+        state.tail.push_back(change);
+        if state.tail.len() > RETAINED_CHANGES {
+            state.tail.pop_front();
+        }
+        // the new log is synced without holding up the writers
+        if state.lines >= 2 * RETAINED_CHANGES && state.rewriter.is_none() {
+            let feed = self.state.clone();
+            state.rewriter = Some(thread::spawn(move || rewrite_in_background(&feed)));
+        }
+    }
+
+    /// The retained changes, oldest first
+    pub(crate) fn retained(&self) -> VecDeque<Change> {
+        self.state.lock().unwrap().tail.clone()
+    }
+
+    /// Sequence number of the most recent change, 0 if there have been none
+    pub(crate) fn last_sequence(&self) -> u64 {
+        self.state.lock().unwrap().next_seq - 1
+    }
+
+    /// Returns the retained changes from `from` on, followed by every later one
+    pub(crate) fn subscribe(&self, from: u64) -> Result<ChangeStream> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(oldest) = state.tail.front() {
+            if from < oldest.seq && oldest.seq > 1 {
+                return Err(KvError::SequenceUnavailable(oldest.seq));
+            }
+        }
+        let backlog: Vec<Change> = state.tail.iter().filter(|c| c.seq >= from).cloned().collect();
+        let (sender, receiver) = sync_channel(SUBSCRIBER_BUFFER);
+        let lagged = Arc::new(AtomicBool::new(false));
+        state.subscribers.push(Subscriber {
+            sender,
+            lagged: lagged.clone(),
+        });
+
+        Ok(ChangeStream {
+            backlog: backlog.into_iter(),
+            receiver,
+            from,
+            lagged,
+        })
+    }
+
+    /// Ends every subscription, waiting for the change log to be rewritten if
+    /// it is. Fails if any change could not be saved.
+    pub(crate) fn close(&self) -> Result<()> {
+        let rewriter = {
+            let mut state = self.state.lock().unwrap();
+            state.subscribers.clear();
+            state.rewriter.take()
+        };
+        if let Some(rewriter) = rewriter {
+            rewriter.join().map_err(|_| KvError::InternalError)?;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if let Some((_, file)) = &state.log {
+            file.sync_data()?;
+        }
+        match state.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ChangeFeed {
+    /// Lets a rewrite finish, so that it cannot replace the change log of a
+    /// feed opened after this one
+    fn drop(&mut self) {
+        let rewriter = self.state.lock().unwrap().rewriter.take();
+        if let Some(rewriter) = rewriter {
+            let _ = rewriter.join();
+        }
+    }
+}
+
+/// Writes the changes as the change log of the store at `dir`, so that the
+/// store numbers its changes on from them when it is opened
+pub(crate) fn write_change_log(dir: &Path, changes: &VecDeque<Change>) -> Result<()> {
+    rewrite(&dir.join(CHANGES_FILE), changes).map(|_| ())
+}
+
+fn append(file: &mut File, change: &Change) -> Result<()> {
+    let mut line = serde_json::to_vec(change)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+
+    Ok(())
+}
+
+/// Writes the changes to a temporary file next to the change log and syncs it
+fn write_tmp(path: &Path, changes: &VecDeque<Change>) -> Result<(PathBuf, File)> {
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    for change in changes {
+        append(&mut tmp, change)?;
+    }
+    tmp.sync_all()?;
+
+    Ok((tmp_path, tmp))
+}
+
+/// Replaces the change log with the given changes, returning it opened for appending
+fn rewrite(path: &Path, changes: &VecDeque<Change>) -> Result<File> {
+    let (tmp_path, _) = write_tmp(path, changes)?;
+    std::fs::rename(&tmp_path, path)?;
+
+    Ok(OpenOptions::new().append(true).open(path)?)
+}
+
+/// Rewrites the change log of a feed with its retained changes. Only the
+/// changes recorded while the rewrite was synced are written under the lock.
+fn rewrite_in_background(feed: &Mutex<FeedState>) {
+    let (path, changes) = match &*feed.lock().unwrap() {
+        FeedState {
+            log: Some((path, _)),
+            tail,
+            ..
+        } => (path.clone(), tail.clone()),
+        _ => return,
+    };
+    let tmp = write_tmp(&path, &changes);
+
+    let mut state = feed.lock().unwrap();
+    let state = &mut *state;
+    // a handle no one has taken to join is no longer needed
+    state.rewriter = None;
+    let saved = changes.back().map_or(0, |change| change.seq);
+    let res = tmp.and_then(|(tmp_path, mut tmp)| {
+        for change in state.tail.iter().filter(|change| change.seq > saved) {
+            append(&mut tmp, change)?;
+        }
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(OpenOptions::new().append(true).open(&path)?)
+    });
+    match (res, &mut state.log) {
+        (Ok(file), Some((_, log))) => *log = file,
+        (Err(e), _) => {
+            state.error.get_or_insert(e);
+        }
+        _ => {}
+    }
+    // after a failure the rewrite is tried again once as many changes were recorded
+    state.lines = state.tail.len();
+}
@@ -0,0 +1,260 @@
+//! A client for the JSON protocol spoken by `KvServer`
+use crate::errors::{KvError, Result};
+use crate::{KvRequest, KvResponse, JSON_PROTOCOL_VERSION};
+use serde_json::de::IoRead;
+use serde_json::StreamDeserializer;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::ops::Bound;
+use std::time::Duration;
+
+type Deserializer = StreamDeserializer<'static, IoRead<BufReader<TcpStream>>, KvResponse>;
+
+/// A connection to a `KvServer` that carries any number of requests, each
+/// answered before the next is sent. Errors the server runs into come back
+/// as the `KvError` it reported.
+///
+/// Once a request fails to reach the server or to get an answer, including
+/// by timing out, the connection can no longer tell which response belongs
+/// to which request, and every later request fails.
+pub struct KvsClient {
+    writer: BufWriter<TcpStream>,
+    responses: Deserializer,
+    keyspace: Option<String>,
+    /// Whether `KvRequest::Hello` has been answered
+    greeted: bool,
+    broken: bool,
+}
+
+impl KvsClient {
+    /// Connects to the server at `addr`
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        KvsClient::from_stream(TcpStream::connect(addr)?)
+    }
+
+    /// Connects to the server at `addr`, giving up on each address it
+    /// resolves to after `timeout`
+    pub fn connect_timeout<A: ToSocketAddrs>(addr: A, timeout: Duration) -> Result<Self> {
+        let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "Address resolved to nothing");
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return KvsClient::from_stream(stream),
+                Err(e) => last_err = e,
+            }
+        }
+
+        Err(timed_out(last_err.into()))
+    }
+
+    fn from_stream(stream: TcpStream) -> Result<Self> {
+        // requests are small and sent as soon as they are ready
+        stream.set_nodelay(true)?;
+        let reader = BufReader::new(stream.try_clone()?);
+
+        Ok(KvsClient {
+            writer: BufWriter::new(stream),
+            responses: serde_json::Deserializer::from_reader(reader).into_iter(),
+            keyspace: None,
+            greeted: false,
+            broken: false,
+        })
+    }
+
+    /// Sets how long to wait for the server to take a request and to answer
+    /// it, failing with `KvError::Timeout` after that. `None`, the default,
+    /// waits indefinitely.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        let stream = self.writer.get_ref();
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+
+        Ok(())
+    }
+
+    /// Runs later requests against the named keyspace, or outside of any
+    /// keyspace for `None`
+    pub fn set_keyspace(&mut self, keyspace: Option<&str>) {
+        self.keyspace = keyspace.map(String::from);
+    }
+
+    /// Whether an earlier request left the connection unusable
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// Checks, without waiting, that the connection is still usable: no
+    /// earlier request broke it, and the server has neither closed it, as it
+    /// does with idle connections, nor sent anything that was not asked for
+    pub fn is_healthy(&self) -> bool {
+        if self.broken {
+            return false;
+        }
+        let stream = self.writer.get_ref();
+        if stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let res = stream.peek(&mut [0; 1]);
+        let restored = stream.set_nonblocking(false).is_ok();
+
+        restored && matches!(res, Err(ref e) if e.kind() == io::ErrorKind::WouldBlock)
+    }
+
+    /// Gets the value of a key, or `None` if it does not exist
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.request(KvRequest::Get(key))
+    }
+
+    /// Sets the value of a key
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.request(KvRequest::Set(key, value)).map(|_| ())
+    }
+
+    /// Removes a key, failing with `KvError::KeyNotFound` if it does not exist
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.request(KvRequest::Rm(key)).map(|_| ())
+    }
+
+    /// Returns up to `limit` key-value pairs within the bounds, in key order
+    pub fn scan(&mut self, start: Bound<String>, end: Bound<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let pairs = self.request(KvRequest::Scan(start, end, limit))?;
+
+        Ok(serde_json::from_str(&pairs.ok_or(KvError::InternalError)?)?)
+    }
+
+    /// Runs the requests in order with a single round trip, returning the
+    /// outcome of each as `request` would
+    pub fn batch(&mut self, requests: Vec<KvRequest>) -> Result<Vec<Result<Option<String>>>> {
+        match self.call(KvRequest::Batch(requests))? {
+            KvResponse::Batch(responses) => Ok(responses.into_iter().map(outcome).collect()),
+            response => outcome(response).and(Err(KvError::InternalError)),
+        }
+    }
+
+    /// Sends a request that is answered with a single response, returning the
+    /// value it carries
+    pub fn request(&mut self, request: KvRequest) -> Result<Option<String>> {
+        outcome(self.call(request)?)
+    }
+
+    /// Sends a request whose responses are streamed, such as
+    /// `KvRequest::Watch`, and returns them as they arrive. The stream takes
+    /// over the connection.
+    pub fn stream(mut self, request: KvRequest) -> Result<Responses> {
+        self.send(request)?;
+        self.greet()?;
+
+        Ok(Responses {
+            inner: self.responses,
+        })
+    }
+
+    fn call(&mut self, request: KvRequest) -> Result<KvResponse> {
+        self.send(request)?;
+        self.greet()?;
+        self.receive()
+    }
+
+    /// Reads the answer to `KvRequest::Hello`, which goes out with the first
+    /// request. A server that only speaks version 1 answers it with an error
+    /// and keeps sending errors as their message.
+    fn greet(&mut self) -> Result<()> {
+        if !self.greeted {
+            self.receive()?;
+            self.greeted = true;
+        }
+
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<KvResponse> {
+        let res = match self.responses.next() {
+            Some(Ok(response)) => Ok(response),
+            Some(Err(err)) => Err(from_serde(err)),
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Server closed the connection").into()),
+        };
+        if res.is_err() {
+            self.broken = true;
+        }
+
+        res.map_err(timed_out)
+    }
+
+    fn send(&mut self, request: KvRequest) -> Result<()> {
+        if self.broken {
+            let err = io::Error::new(io::ErrorKind::NotConnected, "Connection lost by an earlier request");
+            return Err(err.into());
+        }
+        let request = in_keyspace(&self.keyspace, request);
+        let hello = if self.greeted { None } else { Some(KvRequest::Hello(JSON_PROTOCOL_VERSION)) };
+        let res = hello
+            .iter()
+            .chain(Some(&request))
+            .try_for_each(|request| serde_json::to_writer(&mut self.writer, request))
+            .map_err(from_serde)
+            .and_then(|_| Ok(self.writer.flush()?));
+        if res.is_err() {
+            self.broken = true;
+        }
+
+        res.map_err(timed_out)
+    }
+}
+
+/// The responses to a streaming request, ending when the server closes the
+/// connection
+pub struct Responses {
+    inner: Deserializer,
+}
+
+impl Iterator for Responses {
+    type Item = Result<KvResponse>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|res| res.map_err(from_serde).map_err(timed_out))
+    }
+}
+
+/// Wraps the request to run against the keyspace, unless it does not belong to one
+pub(crate) fn in_keyspace(keyspace: &Option<String>, request: KvRequest) -> KvRequest {
+    let belongs = !matches!(
+        request,
+        KvRequest::Publish(..)
+            | KvRequest::Subscribe(_)
+            | KvRequest::Keyspace(..)
+            | KvRequest::DropKeyspace(_)
+            | KvRequest::Backup(_)
+            | KvRequest::IncrementalBackup(..)
+            | KvRequest::Hello(_)
+    );
+    match keyspace {
+        Some(name) if belongs => KvRequest::Keyspace(name.clone(), Box::new(request)),
+        _ => request,
+    }
+}
+
+/// The value of a successful response, or the error the server reported
+pub(crate) fn outcome(response: KvResponse) -> Result<Option<String>> {
+    match response {
+        KvResponse::Success(value) => Ok(value),
+        KvResponse::Error(err) => Err(err.into()),
+        KvResponse::Change(_) | KvResponse::Message(..) | KvResponse::Batch(_) => Err(KvError::InternalError),
+    }
+}
+
+/// Keeps the I/O error behind a failure to read or write JSON
+fn from_serde(err: serde_json::Error) -> KvError {
+    match err.io_error_kind() {
+        Some(kind) => KvError::Io(io::Error::new(kind, err)),
+        None => KvError::Serde(err),
+    }
+}
+
+/// Reports a request that ran out of time as `KvError::Timeout`
+pub(crate) fn timed_out(err: KvError) -> KvError {
+    match err {
+        KvError::Io(ref e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+            KvError::Timeout
+        }
+        err => err,
+    }
+}
@@ -0,0 +1,223 @@
+//! A pool of `KvsClient` connections shared between threads
+use crate::client::KvsClient;
+use crate::errors::{KvError, Result};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a new connection to be accepted
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a connection beyond the minimum is kept while unused. Shorter
+/// than the server's default idle timeout, so the pool usually closes them
+/// before the server does.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Times an idempotent request is sent again after a retryable failure
+const DEFAULT_RETRIES: u32 = 3;
+
+/// Wait before the first retry, doubled for each one after it
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Longest wait between retries
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Persistent connections to one `KvServer`, shared by any number of threads.
+///
+/// The pool keeps at least `min_size` connections open and opens more as
+/// they are needed, up to `max_size`, so that it is fixed when both are
+/// equal and elastic otherwise. A request waits for a free connection when
+/// all of them are in use.
+///
+/// Before a connection is handed out it is checked, and replaced if the
+/// server closed it or an earlier request broke it. `get` and `set` are sent
+/// again, after a growing wait, when they fail in a way that a retry may fix.
+/// Other requests are sent only once, since repeating a request whose
+/// response was lost could apply it twice.
+pub struct ClientPool {
+    addr: SocketAddr,
+    min_size: usize,
+    max_size: usize,
+    connect_timeout: Duration,
+    timeout: Option<Duration>,
+    idle_timeout: Duration,
+    retries: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+#[derive(Default)]
+struct PoolState {
+    /// Connections not in use, with when they were last returned
+    idle: Vec<(KvsClient, Instant)>,
+    /// Connections idle or in use
+    open: usize,
+}
+
+impl ClientPool {
+    /// Creates a pool of connections to the server at `addr`, opening
+    /// `min_size` of them right away
+    pub fn new<A: ToSocketAddrs>(addr: A, min_size: usize, max_size: usize) -> Result<Self> {
+        let addr = addr.to_socket_addrs()?.next().ok_or(KvError::MalformedRequest)?;
+        if max_size == 0 || min_size > max_size {
+            return Err(KvError::MalformedRequest);
+        }
+        let pool = ClientPool {
+            addr,
+            min_size,
+            max_size,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            timeout: None,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            state: Mutex::new(PoolState::default()),
+            returned: Condvar::new(),
+        };
+        for _ in 0..min_size {
+            let client = pool.connect()?;
+            let mut state = pool.state.lock().unwrap();
+            state.idle.push((client, Instant::now()));
+            state.open += 1;
+        }
+
+        Ok(pool)
+    }
+
+    /// Sets how long to wait for a new connection to be accepted
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.connect_timeout = timeout;
+    }
+
+    /// Sets how long a request may wait for a free connection, and then for
+    /// the server to answer it, as `KvsClient::set_timeout` does. Applies to
+    /// connections opened from now on.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Sets how long connections beyond the minimum are kept while unused
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Sets how many times `get` and `set` are retried, and the wait before
+    /// the first retry, which doubles for each retry up to `max_backoff`
+    pub fn set_retries(&mut self, retries: u32, backoff: Duration, max_backoff: Duration) {
+        self.retries = retries;
+        self.backoff = backoff;
+        self.max_backoff = max_backoff;
+    }
+
+    /// Number of connections currently open, whether idle or in use
+    pub fn open_connections(&self) -> usize {
+        self.state.lock().unwrap().open
+    }
+
+    /// Gets the value of a key, retrying on failures that a retry may fix
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.retrying(|client| client.get(key.clone()))
+    }
+
+    /// Sets the value of a key, retrying on failures that a retry may fix
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.retrying(|client| client.set(key.clone(), value.clone()))
+    }
+
+    /// Removes a key. It is not retried: if the response is lost, a retry
+    /// would fail with `KvError::KeyNotFound` even though the key was removed.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.with_client(|client| client.remove(key))
+    }
+
+    /// Runs `f` with a connection from the pool, once. The connection goes
+    /// back to the pool afterwards unless `f` left it broken.
+    pub fn with_client<T, F: FnOnce(&mut KvsClient) -> Result<T>>(&self, f: F) -> Result<T> {
+        let mut client = self.checkout()?;
+        let res = f(&mut client);
+        self.checkin(client);
+
+        res
+    }
+
+    fn retrying<T, F: FnMut(&mut KvsClient) -> Result<T>>(&self, mut f: F) -> Result<T> {
+        let mut backoff = self.backoff;
+        let mut retries = 0;
+        loop {
+            match self.with_client(&mut f) {
+                Err(ref err) if err.is_retryable() && retries < self.retries => {
+                    retries += 1;
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.max_backoff);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn connect(&self) -> Result<KvsClient> {
+        let mut client = KvsClient::connect_timeout(self.addr, self.connect_timeout)?;
+        client.set_timeout(self.timeout)?;
+
+        Ok(client)
+    }
+
+    /// Takes a healthy idle connection, opens a new one if there is room, or
+    /// waits for one to be returned
+    fn checkout(&self) -> Result<KvsClient> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            self.close_expired(&mut state);
+            while let Some((client, _)) = state.idle.pop() {
+                if client.is_healthy() {
+                    return Ok(client);
+                }
+                state.open -= 1;
+            }
+            if state.open < self.max_size {
+                state.open += 1;
+                drop(state);
+                return self.connect().inspect_err(|_| {
+                    self.state.lock().unwrap().open -= 1;
+                    self.returned.notify_one();
+                });
+            }
+            state = match self.timeout {
+                Some(timeout) => {
+                    let (state, waited) = self.returned.wait_timeout(state, timeout).unwrap();
+                    if waited.timed_out() && state.idle.is_empty() && state.open >= self.max_size {
+                        return Err(KvError::Timeout);
+                    }
+                    state
+                }
+                None => self.returned.wait(state).unwrap(),
+            };
+        }
+    }
+
+    fn checkin(&self, client: KvsClient) {
+        let mut state = self.state.lock().unwrap();
+        if client.is_broken() {
+            state.open -= 1;
+        } else {
+            state.idle.push((client, Instant::now()));
+        }
+        self.returned.notify_one();
+    }
+
+    /// Closes connections beyond the minimum that have been idle too long
+    fn close_expired(&self, state: &mut PoolState) {
+        let now = Instant::now();
+        // the most recently returned connections are at the end
+        while state.open > self.min_size
+            && state.idle.first().is_some_and(|(_, since)| now - *since > self.idle_timeout)
+        {
+            state.idle.remove(0);
+            state.open -= 1;
+        }
+    }
+}
@@ -0,0 +1,368 @@
+//! Hashes, lists and sets stored as values of a `KvsEngine`
+//!
+//! A composite value is stored with its `ValueType` and holds one JSON-encoded
+//! operation per line. Each change is appended as a single operation, so with
+//! `KvStore` it becomes a small delta record rather than a rewrite of the
+//! whole value. Once most of the operations are obsolete the value is
+//! rewritten with just enough operations to rebuild its current contents.
+//!
+//! Decoding a value replays all of its operations, so an engine may keep the
+//! collections of a store or keyspace used last decoded, see `Materialized`.
+use crate::errors::{KvError, Result};
+use crate::kv_engine::{KvsEngine, Update, ValueType};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+
+/// Operations that can be rewritten away when they outnumber the live
+/// entries by this factor
+const REWRITE_FACTOR: usize = 2;
+
+/// Number of decoded collections kept for a store or keyspace
+const MATERIALIZED_KEYS: usize = 64;
+
+#[derive(Serialize, Deserialize)]
+enum Op {
+    HSet(String, String),
+    LPush(String),
+    RPop,
+    SAdd(String),
+}
+
+/// The contents of a composite value
+#[derive(Clone)]
+enum Collection {
+    Hash(BTreeMap<String, String>),
+    List(VecDeque<String>),
+    Set(BTreeSet<String>),
+}
+
+impl Collection {
+    fn len(&self) -> usize {
+        match self {
+            Collection::Hash(hash) => hash.len(),
+            Collection::List(list) => list.len(),
+            Collection::Set(set) => set.len(),
+        }
+    }
+
+    fn apply(&mut self, op: Op) -> Result<()> {
+        match (self, op) {
+            (Collection::Hash(hash), Op::HSet(field, value)) => {
+                hash.insert(field, value);
+            }
+            (Collection::List(list), Op::LPush(element)) => list.push_front(element),
+            (Collection::List(list), Op::RPop) => {
+                list.pop_back();
+            }
+            (Collection::Set(set), Op::SAdd(member)) => {
+                set.insert(member);
+            }
+            _ => return Err(KvError::InternalError),
+        }
+
+        Ok(())
+    }
+
+    /// Encodes the contents with one operation per entry
+    fn encode(&self) -> Result<String> {
+        let ops: Vec<Op> = match self {
+            Collection::Hash(hash) => hash.iter().map(|(f, v)| Op::HSet(f.clone(), v.clone())).collect(),
+            // pushing from the back rebuilds the list front to back
+            Collection::List(list) => list.iter().rev().map(|e| Op::LPush(e.clone())).collect(),
+            Collection::Set(set) => set.iter().map(|m| Op::SAdd(m.clone())).collect(),
+        };
+        let mut value = String::new();
+        for op in &ops {
+            value.push_str(&encode_op(op)?);
+        }
+
+        Ok(value)
+    }
+}
+
+fn encode_op(op: &Op) -> Result<String> {
+    let mut line = serde_json::to_string(op)?;
+    line.push('\n');
+
+    Ok(line)
+}
+
+/// The collections of one store or keyspace used last, kept decoded along
+/// with the value they were decoded from. An entry is only used while the
+/// stored value still matches it, the least recently used is dropped first.
+#[derive(Default)]
+pub struct Materialized {
+    lru: Mutex<Lru>,
+}
+
+#[derive(Default)]
+struct Lru {
+    /// Entries by key, with the tick they were last used at
+    entries: HashMap<String, (u64, Decoded)>,
+    /// Keys by the tick they were last used at
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl fmt::Debug for Materialized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Materialized")
+            .field("keys", &self.lru.lock().unwrap().entries.len())
+            .finish()
+    }
+}
+
+impl Materialized {
+    /// Takes the collection decoded last for the key out
+    fn take(&self, key: &str) -> Option<Decoded> {
+        let mut lru = self.lru.lock().unwrap();
+        let (tick, decoded) = lru.entries.remove(key)?;
+        lru.order.remove(&tick);
+        Some(decoded)
+    }
+
+    /// Puts the collection back as the one used last, dropping the least
+    /// recently used if there is no room
+    fn keep(&self, key: String, decoded: Decoded) {
+        let mut lru = self.lru.lock().unwrap();
+        let lru = &mut *lru;
+        if let Some((tick, _)) = lru.entries.remove(&key) {
+            lru.order.remove(&tick);
+        }
+        if lru.entries.len() >= MATERIALIZED_KEYS {
+            if let Some((_, oldest)) = lru.order.pop_first() {
+                lru.entries.remove(&oldest);
+            }
+        }
+        lru.tick += 1;
+        lru.order.insert(lru.tick, key.clone());
+        lru.entries.insert(key, (lru.tick, decoded));
+    }
+}
+
+/// A collection decoded from a stored value
+struct Decoded {
+    value_type: ValueType,
+    value: String,
+    collection: Collection,
+    /// Number of operations in the value
+    ops: usize,
+}
+
+impl Decoded {
+    /// Returns the collection if it was decoded from `value`, or decodes it
+    fn new(cached: Option<Decoded>, value: Option<&str>, value_type: ValueType) -> Result<Decoded> {
+        match cached {
+            Some(cached) if cached.value_type == value_type && Some(cached.value.as_str()) == value => Ok(cached),
+            _ => {
+                let (collection, ops) = decode(value, value_type)?;
+                Ok(Decoded {
+                    value_type,
+                    value: value.unwrap_or_default().to_owned(),
+                    collection,
+                    ops,
+                })
+            }
+        }
+    }
+}
+
+/// Rebuilds a collection of the given type from a stored value, returning
+/// it with the number of operations it was built from
+fn decode(value: Option<&str>, value_type: ValueType) -> Result<(Collection, usize)> {
+    let mut collection = match value_type {
+        ValueType::Hash => Collection::Hash(BTreeMap::new()),
+        ValueType::List => Collection::List(VecDeque::new()),
+        ValueType::Set => Collection::Set(BTreeSet::new()),
+        ValueType::String => return Err(KvError::InternalError),
+    };
+
+    let mut ops = 0;
+    for line in value.unwrap_or_default().lines() {
+        collection.apply(serde_json::from_str(line)?)?;
+        ops += 1;
+    }
+
+    Ok((collection, ops))
+}
+
+/// Atomically applies the operation chosen by `f`, if any, to the collection
+/// at `key`. The operation is appended to the stored value unless the value
+/// is due to be rewritten. Returns the length of the updated collection.
+fn modify<E, F>(engine: &E, key: String, value_type: ValueType, mut f: F) -> Result<usize>
+where
+    E: KvsEngine,
+    F: FnMut(&Collection) -> Result<Option<Op>>,
+{
+    let materialized = engine.materialized();
+    let mut cached = materialized.and_then(|m| m.take(&key));
+    let mut result = None;
+    engine.update_typed(key.clone(), value_type, |current| {
+        // the closure may be retried, the cached collection only serves once
+        let mut decoded = Decoded::new(cached.take(), current, value_type)?;
+        let op = match f(&decoded.collection)? {
+            Some(op) => op,
+            None => {
+                result = Some(decoded);
+                return Ok(Update::Unchanged);
+            }
+        };
+        let line = encode_op(&op)?;
+        decoded.collection.apply(op)?;
+        let update = if current.is_none() {
+            decoded.value = line.clone();
+            decoded.ops = 1;
+            Update::Set(line)
+        } else if decoded.ops + 1 > REWRITE_FACTOR * decoded.collection.len().max(1) {
+            decoded.value = decoded.collection.encode()?;
+            decoded.ops = decoded.collection.len();
+            Update::Set(decoded.value.clone())
+        } else {
+            decoded.value.push_str(&line);
+            decoded.ops += 1;
+            Update::Append(line)
+        };
+        result = Some(decoded);
+        Ok(update)
+    })?;
+
+    let decoded = result.ok_or(KvError::InternalError)?;
+    let len = decoded.collection.len();
+    if let Some(materialized) = materialized {
+        materialized.keep(key, decoded);
+    }
+
+    Ok(len)
+}
+
+fn read<E: KvsEngine>(engine: &E, key: String, value_type: ValueType) -> Result<Collection> {
+    let value = engine.get_typed(key.clone(), value_type)?;
+    let materialized = match (engine.materialized(), &value) {
+        (Some(materialized), Some(_)) => materialized,
+        _ => return decode(value.as_deref(), value_type).map(|(collection, _)| collection),
+    };
+    let decoded = Decoded::new(materialized.take(&key), value.as_deref(), value_type)?;
+    let collection = decoded.collection.clone();
+    materialized.keep(key, decoded);
+
+    Ok(collection)
+}
+
+/// Hash, list and set operations on top of any `KvsEngine`. Operations of one
+/// type on a key holding another type, or a plain string, fail with
+/// `KvError::WrongType`. A missing key behaves as an empty collection.
+pub trait Collections: KvsEngine {
+    /// Sets a field of the hash at the key
+    fn hset(&self, key: String, field: String, value: String) -> Result<()> {
+        modify(self, key, ValueType::Hash, |_| Ok(Some(Op::HSet(field.clone(), value.clone())))).map(|_| ())
+    }
+
+    /// Gets a field of the hash at the key
+    fn hget(&self, key: String, field: String) -> Result<Option<String>> {
+        match read(self, key, ValueType::Hash)? {
+            Collection::Hash(mut hash) => Ok(hash.remove(&field)),
+            _ => Err(KvError::InternalError),
+        }
+    }
+
+    /// Gets every field of the hash at the key
+    fn hgetall(&self, key: String) -> Result<BTreeMap<String, String>> {
+        match read(self, key, ValueType::Hash)? {
+            Collection::Hash(hash) => Ok(hash),
+            _ => Err(KvError::InternalError),
+        }
+    }
+
+    /// Pushes an element onto the front of the list at the key, returning the new length
+    fn lpush(&self, key: String, element: String) -> Result<usize> {
+        modify(self, key, ValueType::List, |_| Ok(Some(Op::LPush(element.clone()))))
+    }
+
+    /// Removes and returns the element at the back of the list at the key
+    fn rpop(&self, key: String) -> Result<Option<String>> {
+        let mut popped = None;
+        modify(self, key, ValueType::List, |collection| {
+            popped = match collection {
+                Collection::List(list) => list.back().cloned(),
+                _ => return Err(KvError::InternalError),
+            };
+            Ok(popped.as_ref().map(|_| Op::RPop))
+        })?;
+
+        Ok(popped)
+    }
+
+    /// Returns the elements of the list at the key from `start` to `stop`
+    /// inclusive. Negative indexes count from the end of the list.
+    fn lrange(&self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        let list = match read(self, key, ValueType::List)? {
+            Collection::List(list) => list,
+            _ => return Err(KvError::InternalError),
+        };
+        let len = list.len() as i64;
+        let resolve = |i: i64| if i < 0 { len + i } else { i };
+        let start = resolve(start).max(0);
+        let stop = resolve(stop).min(len - 1);
+        if start > stop {
+            return Ok(Vec::new());
+        }
+
+        Ok(list.into_iter().skip(start as usize).take((stop - start + 1) as usize).collect())
+    }
+
+    /// Adds a member to the set at the key, returning whether it was not already present
+    fn sadd(&self, key: String, member: String) -> Result<bool> {
+        let mut added = false;
+        modify(self, key, ValueType::Set, |collection| {
+            added = match collection {
+                Collection::Set(set) => !set.contains(&member),
+                _ => return Err(KvError::InternalError),
+            };
+            Ok(if added { Some(Op::SAdd(member.clone())) } else { None })
+        })?;
+
+        Ok(added)
+    }
+
+    /// Returns the members of the set at the key
+    fn smembers(&self, key: String) -> Result<BTreeSet<String>> {
+        match read(self, key, ValueType::Set)? {
+            Collection::Set(set) => Ok(set),
+            _ => Err(KvError::InternalError),
+        }
+    }
+}
+
+impl<E: KvsEngine> Collections for E {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoded() -> Decoded {
+        Decoded {
+            value_type: ValueType::Set,
+            value: String::new(),
+            collection: Collection::Set(BTreeSet::new()),
+            ops: 0,
+        }
+    }
+
+    #[test]
+    fn materialized_drops_least_recently_used() {
+        let materialized = Materialized::default();
+        for i in 0..MATERIALIZED_KEYS {
+            materialized.keep(format!("key{:02}", i), decoded());
+        }
+        // using the oldest key makes the next one the least recently used
+        let used = materialized.take("key00").unwrap();
+        materialized.keep("key00".to_owned(), used);
+        materialized.keep("new".to_owned(), decoded());
+
+        assert!(materialized.take("key01").is_none());
+        assert!(materialized.take("key00").is_some());
+        assert!(materialized.take("new").is_some());
+    }
+}
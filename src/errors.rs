@@ -22,6 +22,51 @@ pub enum KvError {
     MalformedRequest,
     /// Error from the sled library
     SledError(sled::Error),
+    /// The store has been closed and no longer accepts writes
+    StoreClosed,
+    /// A record in a log file could not be parsed
+    CorruptLog {
+        /// Generation of the damaged log file
+        gen: u64,
+        /// Byte offset of the damaged record
+        offset: u64,
+    },
+    /// The stored value is not an integer
+    NotAnInteger,
+    /// An increment or decrement would overflow
+    IntegerOverflow,
+    /// The operation does not match the type of the stored value
+    WrongType,
+    /// The secondary index does not exist, already exists, or has an invalid path
+    InvalidIndex(String),
+    /// Changes before this sequence number are no longer retained
+    SequenceUnavailable(u64),
+    /// The byte range does not fall on character boundaries of the value
+    InvalidRange,
+    /// The keyspace name is not valid, or keyspaces cannot be used here
+    InvalidKeyspace(String),
+    /// The engine does not support this operation
+    Unsupported,
+    /// Stored data did not match what was expected
+    VerificationFailed(String),
+    /// The peer does not speak a compatible version of the binary protocol
+    UnsupportedVersion(u16),
+    /// A subscriber fell too far behind and missed messages
+    Lagged,
+    /// The server did not answer within the client's timeout
+    Timeout,
+}
+
+impl KvError {
+    /// Whether the same request may succeed if it is sent again, because the
+    /// error comes from the state of the server or the connection rather than
+    /// from the request
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            KvError::Io(_) | KvError::SledError(_) | KvError::StoreClosed | KvError::Lagged | KvError::Timeout
+        )
+    }
 }
 
 impl From<serde_json::Error> for KvError {
@@ -52,6 +97,26 @@ impl fmt::Display for KvError {
             KvError::InternalError => write!(f, "Internal error"),
             KvError::MissingLogFile => write!(f, "There is a missing log file"),
             KvError::MalformedRequest => write!(f, "The request was malformed"),
+            KvError::StoreClosed => write!(f, "The store has been closed"),
+            KvError::CorruptLog { gen, offset } => write!(
+                f,
+                "Corrupt record in {}.log at offset {}, run `kvs-admin fsck`",
+                gen, offset
+            ),
+            KvError::NotAnInteger => write!(f, "Value is not an integer"),
+            KvError::IntegerOverflow => write!(f, "Increment or decrement would overflow"),
+            KvError::WrongType => write!(f, "Operation against a key holding the wrong kind of value"),
+            KvError::InvalidIndex(ref name) => write!(f, "Invalid index {:?}", name),
+            KvError::SequenceUnavailable(oldest) => {
+                write!(f, "Changes are only retained from sequence {}", oldest)
+            }
+            KvError::InvalidRange => write!(f, "Range does not fall on character boundaries"),
+            KvError::InvalidKeyspace(ref name) => write!(f, "Invalid keyspace {:?}", name),
+            KvError::Unsupported => write!(f, "Operation not supported by this engine"),
+            KvError::VerificationFailed(ref msg) => write!(f, "Verification failed: {}", msg),
+            KvError::UnsupportedVersion(version) => write!(f, "Unsupported protocol version {}", version),
+            KvError::Lagged => write!(f, "Subscriber fell behind and messages were dropped"),
+            KvError::Timeout => write!(f, "Timed out waiting for the server"),
         }
     }
 }
@@ -66,6 +131,20 @@ impl Error for KvError {
             KvError::InternalError => "Internal error",
             KvError::MissingLogFile => "Missing log file",
             KvError::MalformedRequest => "MalformedRequest",
+            KvError::StoreClosed => "Store closed",
+            KvError::CorruptLog { .. } => "Corrupt log file",
+            KvError::NotAnInteger => "Value is not an integer",
+            KvError::IntegerOverflow => "Integer overflow",
+            KvError::WrongType => "Wrong type",
+            KvError::InvalidIndex(_) => "Invalid index",
+            KvError::SequenceUnavailable(_) => "Sequence no longer retained",
+            KvError::InvalidRange => "Invalid range",
+            KvError::InvalidKeyspace(_) => "Invalid keyspace",
+            KvError::Unsupported => "Unsupported operation",
+            KvError::VerificationFailed(_) => "Verification failed",
+            KvError::UnsupportedVersion(_) => "Unsupported protocol version",
+            KvError::Lagged => "Subscriber fell behind",
+            KvError::Timeout => "Timed out",
         }
     }
 }
@@ -0,0 +1,329 @@
+//! Export and import of store contents in portable formats
+use crate::errors::{KvError, Result};
+use crate::kv_engine::{KvsEngine, Update, ValueType};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::ops::Bound;
+use std::str::FromStr;
+
+const BATCH_SIZE: usize = 1000;
+/// Header of binary files whose records carry no type, read as plain strings
+const BINARY_MAGIC: &[u8; 8] = b"KVSDUMP1";
+/// Header of binary files whose records start with the type of their value
+const BINARY_MAGIC_TYPED: &[u8; 8] = b"KVSDUMP2";
+
+/// File formats supported by `export` and `import`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// One `{"key": .., "value": ..}` object per line, with a `"type"` for
+    /// values other than plain strings
+    JsonLines,
+    /// A `key,value,type` header followed by one record per row
+    Csv,
+    /// A magic header followed by a type byte, then length-prefixed key and
+    /// value, per record
+    Binary,
+}
+
+impl FromStr for Format {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Format> {
+        match s {
+            "jsonl" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv),
+            "bin" => Ok(Format::Binary),
+            _ => Err(KvError::MalformedRequest),
+        }
+    }
+}
+
+/// Selects the keys to export. The default selects every key.
+#[derive(Debug, Clone)]
+pub struct KeyRange {
+    /// Lower bound of the keys
+    pub start: Bound<String>,
+    /// Upper bound of the keys
+    pub end: Bound<String>,
+    /// Only keys starting with this prefix
+    pub prefix: Option<String>,
+}
+
+impl Default for KeyRange {
+    fn default() -> Self {
+        KeyRange {
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+            prefix: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: String,
+    value: String,
+    /// Missing from files written before values had types
+    #[serde(rename = "type", default, skip_serializing_if = "ValueType::is_string")]
+    value_type: ValueType,
+}
+
+/// Writes every key in `range` to `writer`, along with the type of its
+/// value, returning the number of records written
+pub fn export<E: KvsEngine, W: Write>(engine: &E, range: &KeyRange, format: Format, writer: W) -> Result<u64> {
+    let mut sink = Sink::new(format, writer)?;
+    let mut count = 0;
+    let mut start = match (&range.start, &range.prefix) {
+        // no key before the prefix can match it
+        (Bound::Unbounded, Some(prefix)) => Bound::Included(prefix.clone()),
+        (start, _) => start.clone(),
+    };
+
+    loop {
+        let batch = engine.scan_typed(start, range.end.clone(), BATCH_SIZE)?;
+        match batch.last() {
+            Some((key, _, _)) => start = Bound::Excluded(key.clone()),
+            None => break,
+        }
+        for (key, value_type, value) in batch {
+            if let Some(ref prefix) = range.prefix {
+                if !key.starts_with(prefix.as_str()) {
+                    if key.as_str() > prefix.as_str() {
+                        return sink.finish().map(|_| count);
+                    }
+                    continue;
+                }
+            }
+            sink.write(Record { key, value, value_type })?;
+            count += 1;
+        }
+    }
+
+    sink.finish().map(|_| count)
+}
+
+/// Loads every record from `reader` into `engine`, plain strings in batches,
+/// returning the number of records imported. A hash, list or set replaces
+/// the value of the same type only, failing with `KvError::WrongType` on a
+/// key holding another.
+pub fn import<E: KvsEngine, R: Read>(engine: &E, format: Format, reader: R) -> Result<u64> {
+    let mut count = 0;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut source = Source::new(format, reader)?;
+    while let Some(record) = source.next()? {
+        if !record.value_type.is_string() {
+            let value = record.value;
+            engine.update_typed(record.key, record.value_type, |_| Ok(Update::Set(value.clone())))?;
+            count += 1;
+            continue;
+        }
+        batch.push((record.key, record.value));
+        if batch.len() == BATCH_SIZE {
+            count += batch.len() as u64;
+            engine.set_batch(std::mem::take(&mut batch))?;
+        }
+    }
+    count += batch.len() as u64;
+    engine.set_batch(batch)?;
+    engine.flush()?;
+
+    Ok(count)
+}
+
+enum Sink<W: Write> {
+    JsonLines(W),
+    Csv(Box<csv::Writer<W>>),
+    Binary(W),
+}
+
+impl<W: Write> Sink<W> {
+    fn new(format: Format, mut writer: W) -> Result<Self> {
+        Ok(match format {
+            Format::JsonLines => Sink::JsonLines(writer),
+            Format::Csv => Sink::Csv(Box::new(csv::Writer::from_writer(writer))),
+            Format::Binary => {
+                writer.write_all(BINARY_MAGIC_TYPED)?;
+                Sink::Binary(writer)
+            }
+        })
+    }
+
+    fn write(&mut self, record: Record) -> Result<()> {
+        match self {
+            Sink::JsonLines(w) => {
+                serde_json::to_writer(&mut *w, &record)?;
+                w.write_all(b"\n")?;
+            }
+            // every row has the same columns, so the type is written for strings too
+            Sink::Csv(w) => w.serialize(CsvRecord::from(record)).map_err(csv_error)?,
+            Sink::Binary(w) => {
+                w.write_all(&[type_tag(record.value_type)])?;
+                for field in &[record.key, record.value] {
+                    w.write_all(&(field.len() as u32).to_le_bytes())?;
+                    w.write_all(field.as_bytes())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Sink::JsonLines(mut w) | Sink::Binary(mut w) => w.flush()?,
+            Sink::Csv(mut w) => w.flush()?,
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct CsvRecord {
+    key: String,
+    value: String,
+    #[serde(rename = "type")]
+    value_type: ValueType,
+}
+
+impl From<Record> for CsvRecord {
+    fn from(record: Record) -> Self {
+        CsvRecord {
+            key: record.key,
+            value: record.value,
+            value_type: record.value_type,
+        }
+    }
+}
+
+fn type_tag(value_type: ValueType) -> u8 {
+    match value_type {
+        ValueType::String => 0,
+        ValueType::Hash => 1,
+        ValueType::List => 2,
+        ValueType::Set => 3,
+    }
+}
+
+fn tag_type(tag: u8) -> Result<ValueType> {
+    match tag {
+        0 => Ok(ValueType::String),
+        1 => Ok(ValueType::Hash),
+        2 => Ok(ValueType::List),
+        3 => Ok(ValueType::Set),
+        _ => Err(KvError::MalformedRequest),
+    }
+}
+
+/// Reads the key-value pairs in `reader`, in file order. A record of a
+/// hash, list or set fails with `KvError::WrongType`, as only `import`
+/// restores those.
+pub fn records<R: Read>(format: Format, reader: R) -> Result<Records<R>> {
+    Ok(Records(Source::new(format, reader)?))
+}
+
+/// Iterator over the records of an exported file, created by `records`
+pub struct Records<R: Read>(Source<R>);
+
+impl<R: Read> Iterator for Records<R> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.0.next().transpose()?;
+        Some(record.and_then(|record| match record.value_type {
+            ValueType::String => Ok((record.key, record.value)),
+            _ => Err(KvError::WrongType),
+        }))
+    }
+}
+
+enum Source<R: Read> {
+    JsonLines(std::io::Lines<BufReader<R>>),
+    Csv(csv::DeserializeRecordsIntoIter<R, Record>),
+    /// Whether each record starts with a type byte
+    Binary(BufReader<R>, bool),
+}
+
+impl<R: Read> Source<R> {
+    fn new(format: Format, reader: R) -> Result<Self> {
+        Ok(match format {
+            Format::JsonLines => Source::JsonLines(BufReader::new(reader).lines()),
+            Format::Csv => Source::Csv(csv::Reader::from_reader(reader).into_deserialize()),
+            Format::Binary => {
+                let mut reader = BufReader::new(reader);
+                let mut magic = [0; 8];
+                reader.read_exact(&mut magic)?;
+                match &magic {
+                    BINARY_MAGIC => Source::Binary(reader, false),
+                    BINARY_MAGIC_TYPED => Source::Binary(reader, true),
+                    _ => return Err(KvError::MalformedRequest),
+                }
+            }
+        })
+    }
+
+    fn next(&mut self) -> Result<Option<Record>> {
+        match self {
+            Source::JsonLines(lines) => loop {
+                match lines.next() {
+                    None => return Ok(None),
+                    Some(line) => {
+                        let line = line?;
+                        if line.trim().is_empty() {
+                            continue;
+                        }
+                        return Ok(Some(serde_json::from_str(&line)?));
+                    }
+                }
+            },
+            Source::Csv(records) => match records.next() {
+                None => Ok(None),
+                Some(record) => Ok(Some(record.map_err(csv_error)?)),
+            },
+            Source::Binary(reader, typed) => {
+                if reader.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+                let value_type = if *typed {
+                    let mut tag = [0; 1];
+                    reader.read_exact(&mut tag).map_err(truncated)?;
+                    tag_type(tag[0])?
+                } else {
+                    ValueType::String
+                };
+                let key = read_field(reader)?;
+                let value = read_field(reader)?;
+                Ok(Some(Record { key, value, value_type }))
+            }
+        }
+    }
+}
+
+/// Reads a length-prefixed field. The buffer grows with the bytes actually
+/// read rather than being sized by the length in the file, which may be
+/// corrupt; a file ending within the field is malformed.
+fn read_field<R: Read>(reader: &mut R) -> Result<String> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len).map_err(truncated)?;
+    let len = u64::from(u32::from_le_bytes(len));
+    let mut buf = Vec::new();
+    if reader.take(len).read_to_end(&mut buf)? as u64 != len {
+        return Err(KvError::MalformedRequest);
+    }
+
+    String::from_utf8(buf).map_err(|_| KvError::MalformedRequest)
+}
+
+fn truncated(err: io::Error) -> KvError {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof => KvError::MalformedRequest,
+        _ => KvError::Io(err),
+    }
+}
+
+fn csv_error(err: csv::Error) -> KvError {
+    match err.into_kind() {
+        csv::ErrorKind::Io(err) => KvError::Io(err),
+        _ => KvError::MalformedRequest,
+    }
+}
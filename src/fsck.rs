@@ -0,0 +1,248 @@
+//! Integrity checking and repair of `KvStore` directories
+use crate::errors::{KvError, Result};
+use crate::kv::{
+    format_log_path, keyspace_names, log_generations, Command, CommandPos, ShutdownMarker, KEYSPACE_DIR,
+    SHUTDOWN_MARKER,
+};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+/// A problem found while checking a store
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// Bytes in a log file that could not be parsed as a record
+    Corrupt {
+        /// Generation of the log file
+        gen: u64,
+        /// Offset of the first unparsable byte
+        offset: u64,
+        /// Number of bytes skipped before the next parsable record
+        len: u64,
+        /// Why parsing failed
+        reason: String,
+    },
+    /// An index entry that does not resolve to a value for its key
+    IndexMismatch {
+        /// The affected key
+        key: String,
+        /// What was wrong with the entry
+        reason: String,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Corrupt { gen, offset, len, reason } => write!(
+                f,
+                "{}.log offset {}: {} unparsable bytes ({})",
+                gen, offset, len, reason
+            ),
+            Problem::IndexMismatch { key, reason } => write!(f, "key {:?}: {}", key, reason),
+        }
+    }
+}
+
+/// Result of checking a store
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// Generations found in the store
+    pub generations: Vec<u64>,
+    /// Number of records that could be parsed
+    pub records: u64,
+    /// Number of live keys in the rebuilt index
+    pub live_keys: u64,
+    /// Problems found, in the order they were encountered
+    pub problems: Vec<Problem>,
+    /// Reports of the store's keyspaces, by name
+    pub keyspaces: Vec<(String, FsckReport)>,
+}
+
+impl FsckReport {
+    /// Whether the store and its keyspaces are free of problems
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty() && self.keyspaces.iter().all(|(_, report)| report.is_clean())
+    }
+}
+
+/// Walks every generation in `dir` and in each of its keyspaces, reporting
+/// unparsable records and checking that the index built from the parsable
+/// ones is consistent.
+pub fn check(dir: impl AsRef<Path>) -> Result<FsckReport> {
+    let dir = dir.as_ref();
+    let (mut report, _, _) = scan_store(dir)?;
+    for name in keyspace_names(dir)? {
+        let keyspace_report = check(dir.join(KEYSPACE_DIR).join(&name))?;
+        report.keyspaces.push((name, keyspace_report));
+    }
+
+    Ok(report)
+}
+
+/// Checks `dir` and writes a compacted store containing only the live,
+/// salvageable records into `out`, which must not contain any log files.
+/// Each keyspace is repaired into the keyspace of the same name in `out`.
+pub fn repair(dir: impl AsRef<Path>, out: impl AsRef<Path>) -> Result<FsckReport> {
+    let dir = dir.as_ref();
+    let out = out.as_ref();
+    let (mut report, index, logs) = scan_store(dir)?;
+
+    std::fs::create_dir_all(out)?;
+    if !log_generations(out)?.is_empty() {
+        return Err(KvError::VerificationFailed(format!(
+            "{} already contains a store",
+            out.display()
+        )));
+    }
+
+    // appended values are merged, their deltas point into the old logs
+    let mut writer = File::create(format_log_path(out, 1))?;
+    for (key, cmd_pos) in &index {
+        if let Ok(value) = resolve(&logs, key, cmd_pos) {
+            serde_json::to_writer(&mut writer, &Command::set(key.clone(), cmd_pos.value_type, value))?;
+        }
+    }
+    writer.sync_all()?;
+
+    for name in keyspace_names(dir)? {
+        let keyspace_report = repair(dir.join(KEYSPACE_DIR).join(&name), out.join(KEYSPACE_DIR).join(&name))?;
+        report.keyspaces.push((name, keyspace_report));
+    }
+
+    Ok(report)
+}
+
+type Logs = BTreeMap<u64, Vec<u8>>;
+
+fn scan_store(dir: &Path) -> Result<(FsckReport, BTreeMap<String, CommandPos>, Logs)> {
+    let mut report = FsckReport::default();
+    let mut index = BTreeMap::new();
+    let mut logs = BTreeMap::new();
+    report.generations = log_generations(dir)?;
+
+    for gen in report.generations.clone() {
+        let data = std::fs::read(format_log_path(dir, gen))?;
+        scan_log(gen, &data, &mut index, &mut report);
+        logs.insert(gen, data);
+    }
+    report.live_keys = index.len() as u64;
+
+    // every entry must lead to a `Set` of its own key
+    for (key, cmd_pos) in &index {
+        if let Err(reason) = resolve(&logs, key, cmd_pos) {
+            report.problems.push(Problem::IndexMismatch { key: key.clone(), reason });
+        }
+    }
+
+    // a clean shutdown marker must agree with the replayed index
+    let marker_path = dir.join(SHUTDOWN_MARKER);
+    if marker_path.exists() {
+        let marker = File::open(&marker_path)
+            .map_err(KvError::from)
+            .and_then(|f| serde_json::from_reader::<_, ShutdownMarker>(f).map_err(KvError::from));
+        match marker {
+            Ok(marker) => {
+                for (key, cmd_pos) in &index {
+                    if marker.index.get(key) != Some(cmd_pos) {
+                        report.problems.push(Problem::IndexMismatch {
+                            key: key.clone(),
+                            reason: "shutdown marker disagrees with the logs".to_owned(),
+                        });
+                    }
+                }
+                for key in marker.index.keys().filter(|k| !index.contains_key(*k)) {
+                    report.problems.push(Problem::IndexMismatch {
+                        key: key.clone(),
+                        reason: "shutdown marker has a key missing from the logs".to_owned(),
+                    });
+                }
+            }
+            Err(e) => report.problems.push(Problem::IndexMismatch {
+                key: String::new(),
+                reason: format!("shutdown marker does not parse: {}", e),
+            }),
+        }
+    }
+
+    Ok((report, index, logs))
+}
+
+/// Follows the chain of appended deltas from an index entry back to the `Set`
+/// of its key, returning the stitched value or why it could not be resolved
+fn resolve(logs: &Logs, key: &str, cmd_pos: &CommandPos) -> std::result::Result<String, String> {
+    let mut deltas: Vec<String> = Vec::new();
+    let mut cmd_pos = cmd_pos.clone();
+    loop {
+        let data = logs
+            .get(&cmd_pos.gen)
+            .ok_or_else(|| format!("entry points at missing generation {}", cmd_pos.gen))?;
+        let end = (cmd_pos.pos + cmd_pos.len) as usize;
+        let record = data
+            .get(cmd_pos.pos as usize..end)
+            .ok_or_else(|| "entry is past the end of the log".to_owned())?;
+        match serde_json::from_slice::<Command>(record) {
+            Ok(Command::Set(ref k, mut value)) | Ok(Command::SetTyped(ref k, _, mut value)) if k == key => {
+                for delta in deltas.iter().rev() {
+                    value.push_str(delta);
+                }
+                return Ok(value);
+            }
+            // a delta can only extend an earlier record, anything else is a loop
+            Ok(Command::Append(ref k, delta, prev)) if k == key && (prev.gen, prev.pos) < (cmd_pos.gen, cmd_pos.pos) => {
+                deltas.push(delta);
+                cmd_pos = prev;
+            }
+            Ok(_) => return Err("entry points at a different record".to_owned()),
+            Err(e) => return Err(format!("entry does not parse: {}", e)),
+        }
+    }
+}
+
+/// Parses every record in a log, skipping over damaged regions
+fn scan_log(gen: u64, data: &[u8], index: &mut BTreeMap<String, CommandPos>, report: &mut FsckReport) {
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut stream = serde_json::Deserializer::from_slice(&data[pos..]).into_iter::<Command>();
+        match stream.next() {
+            None => break,
+            Some(Ok(command)) => {
+                let len = stream.byte_offset() as u64;
+                let cmd_pos = CommandPos { gen, pos: pos as u64, len, value_type: command.value_type() };
+                match command {
+                    Command::Set(key, _) | Command::SetTyped(key, _, _) => {
+                        index.insert(key, cmd_pos);
+                    }
+                    Command::Rm(key) => {
+                        index.remove(&key);
+                    }
+                    Command::Append(key, _, _) => {
+                        index.insert(key, cmd_pos);
+                    }
+                }
+                report.records += 1;
+                pos += len as usize;
+            }
+            Some(Err(e)) => {
+                let next = next_record_start(data, pos + 1);
+                report.problems.push(Problem::Corrupt {
+                    gen,
+                    offset: pos as u64,
+                    len: (next - pos) as u64,
+                    reason: e.to_string(),
+                });
+                pos = next;
+            }
+        }
+    }
+}
+
+/// Finds the next offset that looks like the start of a record. Quotes inside
+/// JSON strings are escaped, so these patterns can only appear at a record boundary.
+fn next_record_start(data: &[u8], from: usize) -> usize {
+    const STARTS: [&[u8]; 4] = [b"{\"Set\":", b"{\"SetTyped\":", b"{\"Rm\":", b"{\"Append\":"];
+    (from..data.len())
+        .find(|&i| STARTS.iter().any(|s| data[i..].starts_with(s)))
+        .unwrap_or(data.len())
+}
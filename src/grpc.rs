@@ -0,0 +1,388 @@
+//! gRPC interface to a `KvsEngine`, defined by `proto/kvs.proto`
+//!
+//! The server side is run by `KvServer` when given an address with
+//! `set_grpc_addr`. The generated types, including a client, are in `proto`;
+//! the client is made from a `tonic::transport::Channel`.
+use crate::changes::{Change, ChangeOp, ChangeStream};
+use crate::errors::{KvError, Result};
+use crate::kv_engine::KvsEngine;
+use crate::thread_pool::ThreadPool;
+use crate::{ErrorCode, ServerError, Watched};
+use proto::kvs_server::{Kvs, KvsServer};
+use proto::{
+    change, operation, operation_result, watch_request, BatchRequest, BatchResponse, GetRequest, GetResponse,
+    KeyValue, OperationResult, RemoveRequest, RemoveResponse, ScanRequest, SetRequest, SetResponse, WatchRequest,
+};
+use std::future::Future;
+use std::net::TcpListener;
+use std::ops::Bound;
+use std::result;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Code, Request, Response, Status};
+
+/// Messages and services generated from `proto/kvs.proto`
+#[allow(missing_docs, clippy::all)]
+pub mod proto {
+    tonic::include_proto!("kvs");
+}
+
+/// Keys read from the engine at a time by `Scan`
+const SCAN_PAGE: usize = 256;
+
+/// Changes buffered for a `Watch` client that is slow to read them
+const WATCH_BUFFER: usize = 1024;
+
+/// How long streaming calls get to finish once a shutdown is requested
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+
+/// How long a `Watch` waits for a change before checking that its client is
+/// still there
+const WATCH_TICK: Duration = Duration::from_secs(1);
+
+/// Serves gRPC on `listener` until `shutdown` completes. Engine calls run on
+/// the pool, while a small runtime of its own drives the connections.
+pub(crate) fn serve<E, P, S>(engine: E, pool: Arc<Mutex<P>>, listener: TcpListener, shutdown: S) -> Result<()>
+where
+    E: KvsEngine,
+    P: ThreadPool + Send + 'static,
+    S: Future<Output = ()>,
+{
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()?;
+    listener.set_nonblocking(true)?;
+    let service = KvsService {
+        engine: Mutex::new(engine),
+        pool,
+    };
+
+    let res = runtime.block_on(async move {
+        let incoming = TcpListenerStream::new(tokio::net::TcpListener::from_std(listener)?);
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(
+            tonic::transport::Server::builder()
+                .tcp_nodelay(true)
+                .add_service(KvsServer::new(service))
+                .serve_with_incoming_shutdown(incoming, async {
+                    let _ = stopped.await;
+                }),
+        );
+        shutdown.await;
+        let _ = stop.send(());
+        // a watch may go on indefinitely, so it is not waited for
+        match tokio::time::timeout(SHUTDOWN_GRACE, server).await {
+            Ok(Ok(Err(e))) => Err(KvError::Io(std::io::Error::other(e))),
+            _ => Ok(()),
+        }
+    });
+    runtime.shutdown_background();
+
+    res
+}
+
+struct KvsService<E, P> {
+    engine: Mutex<E>,
+    pool: Arc<Mutex<P>>,
+}
+
+impl<E: KvsEngine, P: ThreadPool + Send + 'static> KvsService<E, P> {
+    fn engine(&self) -> E {
+        self.engine.lock().unwrap().clone()
+    }
+
+    /// Runs `f` on the pool and waits for its result
+    async fn offload<T, F>(&self, f: F) -> result::Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        offload(&self.pool, f).await
+    }
+}
+
+/// Runs `f` on the pool and waits for its result
+async fn offload<P, T, F>(pool: &Mutex<P>, f: F) -> result::Result<T, Status>
+where
+    P: ThreadPool,
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    pool.lock().unwrap().spawn(move || {
+        let _ = tx.send(f());
+    });
+    match rx.await {
+        Ok(res) => res.map_err(status),
+        Err(_) => Err(Status::internal("The request was dropped")),
+    }
+}
+
+#[tonic::async_trait]
+impl<E: KvsEngine, P: ThreadPool + Send + 'static> Kvs for KvsService<E, P> {
+    async fn get(&self, request: Request<GetRequest>) -> result::Result<Response<GetResponse>, Status> {
+        let GetRequest { key, keyspace } = request.into_inner();
+        let engine = self.engine();
+        let value = self.offload(move || in_keyspace(engine, &keyspace, false)?.get(key)).await?;
+
+        Ok(Response::new(found(value)))
+    }
+
+    async fn set(&self, request: Request<SetRequest>) -> result::Result<Response<SetResponse>, Status> {
+        let SetRequest { key, value, keyspace } = request.into_inner();
+        let engine = self.engine();
+        self.offload(move || in_keyspace(engine, &keyspace, true)?.set(key, value)).await?;
+
+        Ok(Response::new(SetResponse {}))
+    }
+
+    async fn remove(&self, request: Request<RemoveRequest>) -> result::Result<Response<RemoveResponse>, Status> {
+        let RemoveRequest { key, keyspace } = request.into_inner();
+        let engine = self.engine();
+        self.offload(move || in_keyspace(engine, &keyspace, false)?.remove(key)).await?;
+
+        Ok(Response::new(RemoveResponse {}))
+    }
+
+    async fn batch(&self, request: Request<BatchRequest>) -> result::Result<Response<BatchResponse>, Status> {
+        let BatchRequest { operations, keyspace } = request.into_inner();
+        let engine = self.engine();
+        let results = self
+            .offload(move || {
+                let stores = operations.iter().any(|op| matches!(op.op, Some(operation::Op::Set(_))));
+                let engine = in_keyspace(engine, &keyspace, stores)?;
+                Ok(operations.into_iter().map(|op| run_op(&engine, op.op)).collect())
+            })
+            .await?;
+
+        Ok(Response::new(BatchResponse { results }))
+    }
+
+    type ScanStream = ReceiverStream<result::Result<KeyValue, Status>>;
+
+    async fn scan(&self, request: Request<ScanRequest>) -> result::Result<Response<Self::ScanStream>, Status> {
+        let request = request.into_inner();
+        let (engine, pool) = (self.engine(), self.pool.clone());
+        let (tx, rx) = mpsc::channel(SCAN_PAGE);
+        // the keys are sent from a task, which waits for a slow client
+        // without holding a worker
+        tokio::spawn(async move {
+            if let Err(status) = scan(engine, &pool, request, &tx).await {
+                let _ = tx.send(Err(status)).await;
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type WatchStream = ReceiverStream<result::Result<proto::Change, Status>>;
+
+    async fn watch(&self, request: Request<WatchRequest>) -> result::Result<Response<Self::WatchStream>, Status> {
+        let WatchRequest { target, keyspace } = request.into_inner();
+        let watched = target.map(|target| match target {
+            watch_request::Target::Key(key) => Watched::Key(key),
+            watch_request::Target::Prefix(prefix) => Watched::Prefix(prefix),
+        });
+        let engine = self.engine();
+        let stream = self
+            .offload(move || {
+                let engine = in_keyspace(engine, &keyspace, false)?;
+                engine.subscribe(engine.last_sequence()? + 1)
+            })
+            .await?;
+
+        // a watch lasts as long as the client, so it gets a thread rather than a worker
+        let (tx, rx) = mpsc::channel(WATCH_BUFFER);
+        thread::spawn(move || watch(stream, watched, tx));
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// Sends the changes to the watched keys until the client goes away. A
+/// client that fell behind is told so with `DATA_LOSS`.
+fn watch(
+    mut stream: ChangeStream,
+    watched: Option<Watched>,
+    tx: mpsc::Sender<result::Result<proto::Change, Status>>,
+) {
+    loop {
+        match stream.recv_timeout(WATCH_TICK) {
+            Ok(change) if watched.as_ref().is_none_or(|w| w.matches(&change.key)) => {
+                if tx.blocking_send(Ok(change.into())).is_err() {
+                    return;
+                }
+            }
+            // the stream is dropped as soon as the client is gone, even if
+            // no change comes along
+            Ok(_) | Err(RecvTimeoutError::Timeout) if tx.is_closed() => return,
+            Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                if stream.lagged() {
+                    let _ = tx.blocking_send(Err(status(KvError::Lagged)));
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Sends the keys of the requested range until it is exhausted or the client
+/// goes away, reading each page on the pool
+async fn scan<E, P>(
+    engine: E,
+    pool: &Mutex<P>,
+    request: ScanRequest,
+    tx: &mpsc::Sender<result::Result<KeyValue, Status>>,
+) -> result::Result<(), Status>
+where
+    E: KvsEngine,
+    P: ThreadPool,
+{
+    let keyspace = request.keyspace;
+    let engine = offload(pool, move || in_keyspace(engine, &keyspace, false)).await?;
+    let mut start = match request.start {
+        start if start.is_empty() => Bound::Unbounded,
+        start => Bound::Included(start),
+    };
+    let end = match request.end {
+        end if end.is_empty() => Bound::Unbounded,
+        end => Bound::Excluded(end),
+    };
+    let mut remaining = if request.limit == 0 { u64::MAX } else { request.limit };
+
+    while remaining > 0 {
+        let wanted = remaining.min(SCAN_PAGE as u64) as usize;
+        let (engine, from, to) = (engine.clone(), start.clone(), end.clone());
+        let page = offload(pool, move || engine.scan(from, to, wanted)).await?;
+        let len = page.len();
+        let last = page.last().map(|(key, _)| key.clone());
+        for (key, value) in page {
+            if tx.send(Ok(KeyValue { key, value })).await.is_err() {
+                return Ok(());
+            }
+        }
+        remaining -= len as u64;
+        match last {
+            Some(key) if len == wanted => start = Bound::Excluded(key),
+            _ => break,
+        }
+    }
+
+    Ok(())
+}
+
+fn run_op<E: KvsEngine>(engine: &E, op: Option<operation::Op>) -> OperationResult {
+    let res = match op {
+        Some(operation::Op::Get(key)) => engine.get(key).map(|value| operation_result::Result::Get(found(value))),
+        Some(operation::Op::Set(KeyValue { key, value })) => {
+            engine.set(key, value).map(|_| operation_result::Result::Done(true))
+        }
+        Some(operation::Op::Remove(key)) => engine.remove(key).map(|_| operation_result::Result::Done(true)),
+        None => Err(KvError::MalformedRequest),
+    };
+    let result = res.unwrap_or_else(|err| {
+        let status = status(err);
+        operation_result::Result::Error(proto::Error {
+            code: status.code() as i32,
+            message: status.message().to_owned(),
+        })
+    });
+
+    OperationResult { result: Some(result) }
+}
+
+/// Opens the named keyspace, creating it only for a call that stores data
+fn in_keyspace<E: KvsEngine>(engine: E, keyspace: &str, stores: bool) -> Result<E> {
+    if keyspace.is_empty() {
+        Ok(engine)
+    } else if stores {
+        engine.keyspace(keyspace)
+    } else {
+        engine.existing_keyspace(keyspace)
+    }
+}
+
+fn found(value: Option<String>) -> GetResponse {
+    GetResponse {
+        found: value.is_some(),
+        value: value.unwrap_or_default(),
+    }
+}
+
+/// The status a failed call ends with
+fn status(err: KvError) -> Status {
+    let error = ServerError::from(err);
+    let code = match error.code {
+        ErrorCode::KeyNotFound => Code::NotFound,
+        ErrorCode::Serde
+        | ErrorCode::MalformedRequest
+        | ErrorCode::NotAnInteger
+        | ErrorCode::IntegerOverflow
+        | ErrorCode::WrongType
+        | ErrorCode::InvalidIndex(_)
+        | ErrorCode::SequenceUnavailable(_)
+        | ErrorCode::InvalidRange
+        | ErrorCode::InvalidKeyspace(_) => Code::InvalidArgument,
+        ErrorCode::Unsupported => Code::Unimplemented,
+        ErrorCode::Lagged => Code::DataLoss,
+        _ if error.retryable => Code::Unavailable,
+        _ => Code::Internal,
+    };
+
+    Status::new(code, error.message)
+}
+
+impl From<Change> for proto::Change {
+    fn from(change: Change) -> Self {
+        let op = match change.op {
+            ChangeOp::Set(value) => change::Op::Set(value),
+            ChangeOp::Append(value) => change::Op::Append(value),
+            ChangeOp::Remove => change::Op::Remove(true),
+            ChangeOp::Modified(value_type) => change::Op::Modified(value_type.to_string()),
+        };
+
+        proto::Change {
+            seq: change.seq,
+            key: change.key,
+            op: Some(op),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KvStore;
+    use tempfile::TempDir;
+
+    #[test]
+    fn lagged_watch_ends_with_data_loss() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        // nothing reads the stream while the feed overflows its buffer
+        let stream = store.subscribe(1)?;
+        for i in 0..2000 {
+            store.set(format!("key{}", i), "value".to_owned())?;
+        }
+        assert!(stream.lagged());
+
+        let (tx, mut rx) = mpsc::channel(2000);
+        watch(stream, None, tx);
+        let mut last = None;
+        while let Ok(message) = rx.try_recv() {
+            last = Some(message);
+        }
+        match last {
+            Some(Err(status)) => assert_eq!(status.code(), Code::DataLoss),
+            _ => panic!("a lagged watch should end with an error"),
+        }
+
+        Ok(())
+    }
+}
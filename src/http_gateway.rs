@@ -0,0 +1,270 @@
+//! An HTTP/JSON gateway to a `KvsEngine`
+//!
+//! | request                 | effect                                        |
+//! |-------------------------|-----------------------------------------------|
+//! | `GET /keys/{key}`       | `{"key": .., "value": ..}`, or 404            |
+//! | `PUT /keys/{key}`       | sets the key to the `value` of the JSON body  |
+//! | `DELETE /keys/{key}`    | removes the key, or 404                       |
+//! | `GET /keys`             | lists keys in order, see `Listing`            |
+//! | `POST /batch`           | runs a JSON array of `BatchOp`s in order      |
+//!
+//! Keys in paths are percent-encoded. Every endpoint takes a `keyspace`
+//! query parameter. Errors are answered with a status code matching the
+//! `KvError` and a body of `{"error": ServerError}`.
+use crate::errors::{KvError, Result};
+use crate::kv_engine::KvsEngine;
+use crate::kv_protocol::{ErrorCode, ServerError};
+use crate::server::offload;
+use crate::thread_pool::ThreadPool;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Read;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+use tiny_http::{Header, Method, Request, Response};
+
+/// Largest request body accepted
+const MAX_BODY_LEN: u64 = 64 * 1024 * 1024;
+
+/// Keys listed when no `limit` is given
+const DEFAULT_LIMIT: usize = 100;
+
+/// Most keys listed by one request
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Serialize)]
+struct Entry {
+    key: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct PutBody {
+    value: String,
+}
+
+/// A page of `GET /keys`, which takes the query parameters `start`
+/// (inclusive), `after` (exclusive), `end` (exclusive), `prefix` and `limit`.
+/// Passing `next` as `after` fetches the following page.
+#[derive(Serialize)]
+struct Listing {
+    items: Vec<Entry>,
+    /// The last key listed if there may be more, otherwise null
+    next: Option<String>,
+}
+
+/// One operation of `POST /batch`, such as `{"op": "set", "key": "a", "value": "1"}`
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum BatchOp {
+    Get { key: String },
+    Set { key: String, value: String },
+    Delete { key: String },
+}
+
+/// The outcome of one `BatchOp`: `{"value": ..}` for a get, `{}` for
+/// another success, and `{"error": ..}` for a failure
+#[derive(Serialize)]
+#[serde(untagged)]
+enum BatchResult {
+    Value { value: Option<String> },
+    Done {},
+    Error { error: ServerError },
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: ServerError,
+}
+
+/// Answers one request. The body is read and the response written on the
+/// calling thread, only the engine calls are run on the pool.
+pub(crate) fn handle_request<E, P>(engine: E, pool: Arc<Mutex<P>>, mut request: Request)
+where
+    E: KvsEngine,
+    P: ThreadPool,
+{
+    let (url, method) = (request.url().to_owned(), request.method().clone());
+    let routed = read_body(&mut request).and_then(|body| offload(&pool, move || route(&engine, &url, method, &body))?);
+    let response = match routed {
+        Ok(response) => response,
+        Err(err) => {
+            let error = ServerError::from(err);
+            let status = status_code(&error.code);
+            json(status, &ErrorBody { error })
+        }
+    };
+    let _ = request.respond(response);
+}
+
+type HttpResponse = Response<std::io::Cursor<Vec<u8>>>;
+
+fn route<E: KvsEngine>(engine: &E, url: &str, method: Method, body: &[u8]) -> Result<HttpResponse> {
+    let (path, query) = match url.find('?') {
+        Some(i) => (url[..i].to_owned(), parse_query(&url[i + 1..])?),
+        None => (url.to_owned(), HashMap::new()),
+    };
+    // only writes create the keyspace they name
+    let engine = match query.get("keyspace") {
+        Some(name) if matches!(method, Method::Put | Method::Post) => engine.keyspace(name)?,
+        Some(name) => engine.existing_keyspace(name)?,
+        None => engine.clone(),
+    };
+
+    if let Some(key) = path.strip_prefix("/keys/") {
+        let key = percent_decode(key)?;
+        return match method {
+            Method::Get => match engine.get(key.clone())? {
+                Some(value) => Ok(json(200, &Entry { key, value })),
+                None => Err(KvError::KeyNotFound),
+            },
+            Method::Put => {
+                let body: PutBody = serde_json::from_slice(body)?;
+                engine.set(key, body.value)?;
+                Ok(Response::from_data(Vec::new()).with_status_code(204))
+            }
+            Method::Delete => {
+                engine.remove(key)?;
+                Ok(Response::from_data(Vec::new()).with_status_code(204))
+            }
+            _ => Ok(not_allowed()),
+        };
+    }
+
+    match (path.as_str(), method) {
+        ("/keys", Method::Get) => list(&engine, &query),
+        ("/batch", Method::Post) => {
+            let ops: Vec<BatchOp> = serde_json::from_slice(body)?;
+            let results: Vec<BatchResult> = ops.into_iter().map(|op| run_op(&engine, op)).collect();
+            Ok(json(200, &results))
+        }
+        ("/keys", _) | ("/batch", _) => Ok(not_allowed()),
+        _ => Ok(not_found_route()),
+    }
+}
+
+fn list<E: KvsEngine>(engine: &E, query: &HashMap<String, String>) -> Result<HttpResponse> {
+    let limit = match query.get("limit") {
+        Some(limit) => limit.parse::<usize>().map_err(|_| KvError::MalformedRequest)?,
+        None => DEFAULT_LIMIT,
+    }
+    .min(MAX_LIMIT);
+    let prefix = query.get("prefix").cloned().unwrap_or_default();
+    let mut start = match (query.get("after"), query.get("start")) {
+        (Some(after), _) => Bound::Excluded(after.clone()),
+        (None, Some(start)) => Bound::Included(start.clone()),
+        (None, None) => Bound::Unbounded,
+    };
+    // a prefix narrows the start of the range to the first key it could match
+    let below_prefix = match &start {
+        Bound::Unbounded => true,
+        Bound::Included(key) | Bound::Excluded(key) => *key < prefix,
+    };
+    if !prefix.is_empty() && below_prefix {
+        start = Bound::Included(prefix.clone());
+    }
+    let end = match query.get("end") {
+        Some(end) => Bound::Excluded(end.clone()),
+        None => Bound::Unbounded,
+    };
+
+    let pairs = engine.scan(start, end, limit)?;
+    let more = pairs.len() == limit && pairs.last().is_some_and(|(key, _)| key.starts_with(&prefix));
+    let items: Vec<Entry> = pairs
+        .into_iter()
+        .take_while(|(key, _)| key.starts_with(&prefix))
+        .map(|(key, value)| Entry { key, value })
+        .collect();
+    let next = if more { items.last().map(|entry| entry.key.clone()) } else { None };
+
+    Ok(json(200, &Listing { items, next }))
+}
+
+fn run_op<E: KvsEngine>(engine: &E, op: BatchOp) -> BatchResult {
+    let res = match op {
+        BatchOp::Get { key } => engine.get(key).map(|value| BatchResult::Value { value }),
+        BatchOp::Set { key, value } => engine.set(key, value).map(|_| BatchResult::Done {}),
+        BatchOp::Delete { key } => engine.remove(key).map(|_| BatchResult::Done {}),
+    };
+
+    res.unwrap_or_else(|err| BatchResult::Error { error: err.into() })
+}
+
+/// The HTTP status for a failed request
+fn status_code(code: &ErrorCode) -> u16 {
+    match code {
+        ErrorCode::KeyNotFound => 404,
+        ErrorCode::Serde
+        | ErrorCode::MalformedRequest
+        | ErrorCode::NotAnInteger
+        | ErrorCode::IntegerOverflow
+        | ErrorCode::WrongType
+        | ErrorCode::InvalidIndex(_)
+        | ErrorCode::SequenceUnavailable(_)
+        | ErrorCode::InvalidRange
+        | ErrorCode::InvalidKeyspace(_) => 400,
+        ErrorCode::Unsupported => 501,
+        ErrorCode::StoreClosed => 503,
+        _ => 500,
+    }
+}
+
+fn json<T: Serialize>(status: u16, body: &T) -> HttpResponse {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    // serializing these types cannot fail
+    let body = serde_json::to_vec(body).unwrap_or_default();
+
+    Response::from_data(body).with_status_code(status).with_header(content_type)
+}
+
+fn not_allowed() -> HttpResponse {
+    let error = ServerError::new(ErrorCode::MalformedRequest, "Method not allowed");
+    json(405, &ErrorBody { error })
+}
+
+fn not_found_route() -> HttpResponse {
+    let error = ServerError::new(ErrorCode::MalformedRequest, "No such endpoint");
+    json(404, &ErrorBody { error })
+}
+
+fn read_body(request: &mut Request) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    request.as_reader().take(MAX_BODY_LEN + 1).read_to_end(&mut body)?;
+    if body.len() as u64 > MAX_BODY_LEN {
+        return Err(KvError::MalformedRequest);
+    }
+
+    Ok(body)
+}
+
+fn parse_query(query: &str) -> Result<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = match pair.find('=') {
+            Some(i) => (&pair[..i], &pair[i + 1..]),
+            None => (pair, ""),
+        };
+        params.insert(percent_decode(&name.replace('+', " "))?, percent_decode(&value.replace('+', " "))?);
+    }
+
+    Ok(params)
+}
+
+fn percent_decode(s: &str) -> Result<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3).ok_or(KvError::MalformedRequest)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| KvError::MalformedRequest)?;
+            decoded.push(u8::from_str_radix(hex, 16).map_err(|_| KvError::MalformedRequest)?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| KvError::MalformedRequest)
+}
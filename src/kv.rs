@@ -1,28 +1,89 @@
 use std::sync::Arc;
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::ffi::OsStr;
+use fs2::FileExt;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
 
+use crate::changes::{Change, ChangeFeed, ChangeOp, ChangeStream};
+use crate::collections::Materialized;
 use crate::errors::{KvError, Result};
-use crate::kv_engine::KvsEngine;
+use crate::kv_engine::{KvsEngine, Update, ValueType};
+use crate::secondary_index::SecondaryIndexes;
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+pub(crate) const SHUTDOWN_MARKER: &str = "clean_shutdown";
+pub(crate) const KEYSPACE_DIR: &str = "keyspaces";
+const LOCK_FILE: &str = "LOCK";
+const APPEND_PREFIX: &[u8] = b"{\"Append\":";
 
 #[derive(Debug, Serialize, Deserialize)]
-enum Command {
+pub(crate) enum Command {
     Set(String, String),
     Rm(String),
+    /// A value appended to a key, along with the position of the key's previous record
+    Append(String, String, CommandPos),
+    /// A value of another type than a plain string
+    SetTyped(String, ValueType, String),
 }
 
+impl Command {
+    /// A record setting the key to a value of the given type
+    pub(crate) fn set(key: String, value_type: ValueType, value: String) -> Command {
+        match value_type {
+            ValueType::String => Command::Set(key, value),
+            _ => Command::SetTyped(key, value_type, value),
+        }
+    }
+
+    /// The type of the key's value after this record
+    pub(crate) fn value_type(&self) -> ValueType {
+        match self {
+            Command::SetTyped(_, value_type, _) => *value_type,
+            Command::Append(_, _, prev) => prev.value_type,
+            Command::Set(..) | Command::Rm(_) => ValueType::String,
+        }
+    }
+
+    /// The value of a record holding a whole value
+    fn into_value(self) -> Result<String> {
+        match self {
+            Command::Set(_, value) | Command::SetTyped(_, _, value) => Ok(value),
+            _ => Err(KvError::InternalError),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CommandPos {
+    pub(crate) gen: u64,
+    pub(crate) pos: u64,
+    pub(crate) len: u64,
+    /// Type of the key's value, kept here so it is known without reading the log
+    #[serde(default, skip_serializing_if = "ValueType::is_string")]
+    pub(crate) value_type: ValueType,
+}
+
+/// Written by `close` so that the next `open` can skip replaying the logs
 #[derive(Debug, Serialize, Deserialize)]
-struct CommandPos {
-    gen: u64,
-    pos: u64,
-    len: u64,
+pub(crate) struct ShutdownMarker {
+    pub(crate) current_gen: u64,
+    pub(crate) log_len: u64,
+    pub(crate) compact_space: u64,
+    pub(crate) index: BTreeMap<String, CommandPos>,
+}
+
+/// Index of a generation that only contains plain `Set`s, so it can be loaded
+/// without parsing the log
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Hint {
+    pub(crate) log_len: u64,
+    /// Key, position and length of every record in the log
+    pub(crate) entries: Vec<(String, u64, u64)>,
 }
 
 /// A key-value store
@@ -32,6 +93,15 @@ pub struct KvStore {
     index: Arc<RwLock<BTreeMap<String, CommandPos>>>,
     writer: Arc<RwLock<KvStoreWriter>>,
     readers: KvStoreReader,
+    /// Secondary indexes on JSON values, always locked after `index` and `writer`,
+    /// which are always locked in that order
+    secondary: Arc<RwLock<SecondaryIndexes>>,
+    /// Sequenced record of every mutation, written in log order under the writer lock
+    changes: Arc<ChangeFeed>,
+    /// Open keyspaces of a top-level store, `None` for a keyspace itself
+    keyspaces: Option<Arc<Mutex<HashMap<String, KvStore>>>>,
+    /// Collections of this store or keyspace kept decoded
+    materialized: Arc<Materialized>,
 }
 
 #[derive(Debug)]
@@ -40,6 +110,29 @@ struct KvStoreWriter {
     current_gen: u64,
     compact_space: u64,
     safe_gen: u64,
+    closed: bool,
+    pins: usize,
+    stale_gens: Vec<u64>,
+    /// Keeps the directory from being opened or loaded into until the store is closed
+    lock: Option<File>,
+}
+
+impl KvStoreWriter {
+    /// Fails if the store has already been closed
+    fn check_open(&self) -> Result<()> {
+        if self.closed {
+            Err(KvError::StoreClosed)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for KvStoreWriter {
+    fn drop(&mut self) {
+        // without `close` no marker is written, the next open replays the logs
+        let _ = self.writer.sync_data();
+    }
 }
 
 #[derive(Debug)]
@@ -66,35 +159,40 @@ impl Clone for KvStoreReader {
 impl KvStore {
     /// Open a KvStore
     pub fn open(store_path: impl Into<PathBuf>) -> Result<Self> {
-        let store_path = store_path.into();
+        let mut store = KvStore::open_logs(store_path.into())?;
+        store.keyspaces = Some(Arc::new(Mutex::new(HashMap::new())));
+
+        Ok(store)
+    }
+
+    /// Open the logs in a directory, without support for keyspaces
+    fn open_logs(store_path: PathBuf) -> Result<Self> {
         std::fs::create_dir_all(&store_path)?;
+        let lock = lock_dir(&store_path)?;
 
         let mut compact_space = 0;
         let mut index = BTreeMap::new();
         let mut readers = HashMap::new();
-        // find files that end with .log in the log folder
-        let mut log_files: Vec<u64> = store_path
-            .read_dir()?
-            .flat_map(|f| -> Result<_> { Ok(f?.path()) })
-            .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-            .flat_map(|path| {
-                path.file_name()
-                    .and_then(OsStr::to_str)
-                    .map(|name| name.trim_end_matches(".log"))
-                    .map(str::parse::<u64>)
-            })
-            .flatten()
-            .collect();
-        // sort the log files
-        log_files.sort_unstable();
+        let log_files = log_generations(&store_path)?;
 
-        // for each generation, load log into index
+        // take the fast path if the store was closed cleanly, otherwise
+        // load each generation's log into the index
         // create and store a reader for each log file
+        let marker = KvStore::take_shutdown_marker(&store_path, &log_files)?;
         for &gen in &log_files {
             let mut reader = File::open(format_log_path(&store_path, gen))?;
-            let free = KvStore::load(gen, &mut reader, &mut index)?;
+            if marker.is_none() {
+                let free = match KvStore::load_hint(&store_path, gen, &mut index)? {
+                    Some(free) => free,
+                    None => KvStore::load(gen, &mut reader, &mut index)?,
+                };
+                compact_space += free;
+            }
             readers.insert(gen, reader);
-            compact_space += free;
+        }
+        if let Some(marker) = marker {
+            index = marker.index;
+            compact_space = marker.compact_space;
         }
 
         // find the latest generation, start at 1 if none
@@ -103,7 +201,7 @@ impl KvStore {
         readers.insert(current_gen, reader);
 
         let readers = KvStoreReader {
-            store_path,
+            store_path: store_path.clone(),
             readers: Arc::new(RwLock::new(readers)),
         };
 
@@ -112,18 +210,83 @@ impl KvStore {
             current_gen,
             compact_space,
             safe_gen: *log_files.first().unwrap_or(&0),
+            closed: false,
+            pins: 0,
+            stale_gens: Vec::new(),
+            lock: Some(lock),
         };
 
+        let secondary = SecondaryIndexes::load(&store_path)?;
         let store = KvStore {
             store_path: readers.store_path.clone(),
             index: Arc::new(RwLock::new(index)),
             writer: Arc::new(RwLock::new(writer)),
-            readers
+            readers,
+            secondary: Arc::new(RwLock::new(secondary)),
+            changes: Arc::new(ChangeFeed::open(&store_path)?),
+            keyspaces: None,
+            materialized: Arc::default(),
         };
+        store.rebuild_secondary(&store.index.read().unwrap())?;
 
         Ok(store)
     }
 
+    /// Reads and removes the clean shutdown marker, returning it only if it
+    /// still describes the log files on disk
+    fn take_shutdown_marker(store_path: &Path, log_files: &[u64]) -> Result<Option<ShutdownMarker>> {
+        let marker_path = store_path.join(SHUTDOWN_MARKER);
+        if !marker_path.exists() {
+            return Ok(None);
+        }
+        let marker = File::open(&marker_path)
+            .map_err(KvError::from)
+            .and_then(|f| serde_json::from_reader::<_, ShutdownMarker>(f).map_err(KvError::from));
+        // the marker is only valid for a single open, a crash from here on
+        // must go through a full replay
+        std::fs::remove_file(&marker_path)?;
+
+        let marker = match marker {
+            Ok(marker) => marker,
+            Err(_) => return Ok(None),
+        };
+        if log_files.last() != Some(&marker.current_gen) {
+            return Ok(None);
+        }
+        let log_len = std::fs::metadata(format_log_path(store_path, marker.current_gen))?.len();
+        if log_len != marker.log_len {
+            return Ok(None);
+        }
+
+        Ok(Some(marker))
+    }
+
+    /// Loads a generation's index entries from its hint file instead of
+    /// parsing the log. Returns `None` if there is no usable hint.
+    fn load_hint(store_path: &Path, gen: u64, index: &mut BTreeMap<String, CommandPos>) -> Result<Option<u64>> {
+        let hint_path = format_hint_path(store_path, gen);
+        if !hint_path.exists() {
+            return Ok(None);
+        }
+        let hint: Hint = match serde_json::from_reader(BufReader::new(File::open(&hint_path)?)) {
+            Ok(hint) => hint,
+            Err(_) => return Ok(None),
+        };
+        if std::fs::metadata(format_log_path(store_path, gen))?.len() != hint.log_len {
+            return Ok(None);
+        }
+
+        let mut free_space = 0;
+        for (key, pos, len) in hint.entries {
+            let cmd_pos = CommandPos { gen, pos, len, value_type: ValueType::String };
+            if let Some(old_cmd) = index.insert(key, cmd_pos) {
+                free_space += old_cmd.len;
+            }
+        }
+
+        Ok(Some(free_space))
+    }
+
     /// Load the KvStore
     fn load(
         gen: u64,
@@ -138,12 +301,21 @@ impl KvStore {
         // the store only holds the key name and the location to find the value
         while let Some(command) = stream.next() {
             let new_pos = stream.byte_offset() as u64;
-            match command? {
-                Command::Set(key, _) => {
+            let command = command.map_err(|e| {
+                if e.is_io() {
+                    KvError::Serde(e)
+                } else {
+                    KvError::CorruptLog { gen, offset: pos }
+                }
+            })?;
+            let value_type = command.value_type();
+            match command {
+                Command::Set(key, _) | Command::SetTyped(key, _, _) => {
                     let command_pos = CommandPos {
                         gen,
                         pos,
                         len: new_pos - pos,
+                        value_type,
                     };
                     if let Some(old_cmd) = index.insert(key, command_pos) {
                         free_space += old_cmd.len
@@ -154,6 +326,13 @@ impl KvStore {
                         free_space += old_cmd.len
                     }
                 }
+                Command::Append(key, _, _) => {
+                    // the previous record stays part of the value, but the delta
+                    // is reclaimed once compaction merges it
+                    let len = new_pos - pos;
+                    index.insert(key, CommandPos { gen, pos, len, value_type });
+                    free_space += len;
+                }
             }
             pos = new_pos;
         }
@@ -174,8 +353,32 @@ impl KvStore {
         Ok((reader, writer))
     }
 
-    /// Read the value from the log files given a `CommandPos`
+    /// Read the command at a `CommandPos`, stitching any appended deltas onto
+    /// the `Set` they extend
     fn read_log(&self, cmd_pos: &CommandPos) -> Result<Command> {
+        let mut deltas = Vec::new();
+        let mut cmd_pos = cmd_pos.clone();
+        loop {
+            let (key, value_type, mut value) = match self.read_record(&cmd_pos)? {
+                Command::Append(_, delta, prev) => {
+                    deltas.push(delta);
+                    cmd_pos = prev;
+                    continue;
+                }
+                Command::Set(key, value) => (key, ValueType::String, value),
+                Command::SetTyped(key, value_type, value) => (key, value_type, value),
+                Command::Rm(_) => return Err(KvError::InternalError),
+            };
+            // deltas were collected newest first
+            for delta in deltas.iter().rev() {
+                value.push_str(delta);
+            }
+            return Ok(Command::set(key, value_type, value));
+        }
+    }
+
+    /// Read a single record from the log files given a `CommandPos`
+    fn read_record(&self, cmd_pos: &CommandPos) -> Result<Command> {
         let gen = cmd_pos.gen;
         let len = cmd_pos.len;
         // obtain the correct reader for the generation
@@ -200,7 +403,7 @@ impl KvStore {
         // obtain the last position in the log file
         let pos = writer.seek(SeekFrom::End(0))?;
         let len = writer.write(serialized.as_bytes())? as u64;
-        Ok(CommandPos { gen, pos, len })
+        Ok(CommandPos { gen, pos, len, value_type: cmd.value_type() })
     }
 
     fn compact(&self) -> Result<()> {
@@ -216,27 +419,38 @@ impl KvStore {
         // iterate through the entries inside the index
         for (_, cmd_pos) in index.iter_mut() {
             // read the value of the key from the correct reader
-            let reader = self.readers.readers.write().unwrap();
-            let mut old_reader = reader
-                .get(&cmd_pos.gen)
-                .ok_or(KvError::InternalError)?;
-            old_reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let mut entry_reader = old_reader.take(cmd_pos.len);
+            let mut entry = Vec::with_capacity(cmd_pos.len as usize);
+            {
+                let reader = self.readers.readers.write().unwrap();
+                let mut old_reader = reader
+                    .get(&cmd_pos.gen)
+                    .ok_or(KvError::InternalError)?;
+                old_reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+                old_reader.take(cmd_pos.len).read_to_end(&mut entry)?;
+            }
+            // appended deltas point into the old generations, merge them into one `Set`
+            if entry.starts_with(APPEND_PREFIX) {
+                entry = serde_json::to_vec(&self.read_log(cmd_pos)?)?;
+            }
 
             // copy the value to the latest generation
-            let len = std::io::copy(&mut entry_reader, &mut writer)?;
+            writer.write_all(&entry)?;
+            let len = entry.len() as u64;
             *cmd_pos = CommandPos {
                 gen: current_gen,
                 pos: new_pos,
                 len,
+                value_type: cmd_pos.value_type,
             };
             new_pos += len;
         }
 
         // delete old generations TODO: optimise this portion
-        for gen in 1..current_gen {
-            let log_path = format_log_path(&self.store_path, gen);
-            let _ = std::fs::remove_file(log_path);
+        // generations pinned by a snapshot are deleted once it is released
+        if kv_writer.pins > 0 {
+            kv_writer.stale_gens.extend(1..current_gen);
+        } else {
+            remove_logs(&self.store_path, 1..current_gen);
         }
 
         // recreate readers hashmap
@@ -245,47 +459,361 @@ impl KvStore {
         let mut new_readers = HashMap::new();
         new_readers.insert(current_gen, reader);
 
-        let mut readers = self.readers.readers.write().unwrap();
-        *readers = new_readers;
+        *self.readers.readers.write().unwrap() = new_readers;
         kv_writer.current_gen = current_gen;
         kv_writer.compact_space = 0;
         kv_writer.safe_gen = current_gen;
         kv_writer.writer = writer;
 
+        self.rebuild_secondary(&index)
+    }
+
+    /// Re-indexes every live value into the secondary indexes
+    fn rebuild_secondary(&self, index: &BTreeMap<String, CommandPos>) -> Result<()> {
+        let mut secondary = self.secondary.write().unwrap();
+        if secondary.is_empty() {
+            return Ok(());
+        }
+        secondary.clear();
+        for (key, cmd_pos) in index {
+            if let Command::Set(_, value) = self.read_log(cmd_pos)? {
+                secondary.insert(key, &value);
+            }
+        }
+
         Ok(())
     }
 }
 
-fn format_log_path(path: &Path, gen: u64) -> PathBuf {
+/// A consistent view of the log files at a point in time. Compaction will not
+/// delete any of these generations until the snapshot is dropped.
+pub(crate) struct LogSnapshot {
+    pub(crate) store_path: PathBuf,
+    /// Generation and length of every log file, the last one being the active log
+    pub(crate) logs: Vec<(u64, u64)>,
+    /// The retained changes, the last one being that of the last logged record
+    pub(crate) changes: VecDeque<Change>,
+    writer: Arc<RwLock<KvStoreWriter>>,
+}
+
+impl KvStore {
+    /// Pins the current generations of every store at once and records the
+    /// length of each active log, so that the snapshots are all of the same
+    /// point in time
+    pub(crate) fn pin_snapshots(stores: &[&KvStore]) -> Result<Vec<LogSnapshot>> {
+        let mut writers: Vec<_> = stores.iter().map(|store| store.writer.write().unwrap()).collect();
+        let snapshots: Vec<_> = stores
+            .iter()
+            .zip(writers.iter_mut())
+            .map(|(store, writer)| store.pin_locked(writer))
+            .collect();
+        // a snapshot takes the writer lock when it is dropped, as the ones
+        // taken before a failure are
+        drop(writers);
+
+        snapshots.into_iter().collect()
+    }
+
+    fn pin_locked(&self, writer: &mut KvStoreWriter) -> Result<LogSnapshot> {
+        let mut logs = Vec::new();
+        for gen in log_generations(&self.store_path)? {
+            if gen < writer.current_gen && !writer.stale_gens.contains(&gen) {
+                logs.push((gen, std::fs::metadata(format_log_path(&self.store_path, gen))?.len()));
+            }
+        }
+        logs.push((writer.current_gen, writer.writer.metadata()?.len()));
+        writer.pins += 1;
+
+        Ok(LogSnapshot {
+            store_path: self.store_path.clone(),
+            logs,
+            changes: self.changes.retained(),
+            writer: self.writer.clone(),
+        })
+    }
+}
+
+impl Drop for LogSnapshot {
+    fn drop(&mut self) {
+        let mut writer = self.writer.write().unwrap();
+        writer.pins -= 1;
+        if writer.pins == 0 {
+            let stale = std::mem::take(&mut writer.stale_gens);
+            remove_logs(&self.store_path, stale);
+        }
+    }
+}
+
+fn remove_logs(store_path: &Path, gens: impl IntoIterator<Item = u64>) {
+    for gen in gens {
+        let log_path = format_log_path(store_path, gen);
+        let _ = std::fs::remove_file(log_path);
+        let _ = std::fs::remove_file(format_hint_path(store_path, gen));
+    }
+}
+
+/// Locks the store directory, failing if it is already locked, for as long as
+/// the returned file is kept. Taken by an open store and by a bulk load.
+pub(crate) fn lock_dir(store_path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(store_path.join(LOCK_FILE))?;
+    match file.try_lock_exclusive() {
+        Ok(()) => Ok(file),
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => Err(KvError::Io(io::Error::other(format!(
+            "The store at {} is already in use",
+            store_path.display()
+        )))),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the generations of the log files in the store, in ascending order
+pub(crate) fn log_generations(store_path: &Path) -> Result<Vec<u64>> {
+    // find files that end with .log in the log folder
+    let mut log_files: Vec<u64> = store_path
+        .read_dir()?
+        .flat_map(|f| -> Result<_> { Ok(f?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .map(|name| name.trim_end_matches(".log"))
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    // sort the log files
+    log_files.sort_unstable();
+
+    Ok(log_files)
+}
+
+pub(crate) fn format_hint_path(path: &Path, gen: u64) -> PathBuf {
+    path.join(format!("{}.hint", gen))
+}
+
+pub(crate) fn format_log_path(path: &Path, gen: u64) -> PathBuf {
     let fname = format!("{}.log", gen);
     path.join(fname)
 }
 
+impl KvStore {
+    fn open_keyspaces(&self) -> Vec<KvStore> {
+        self.keyspaces
+            .as_ref()
+            .map(|k| k.lock().unwrap().values().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Lists the keyspace directories of the store at `store_path`
+pub(crate) fn keyspace_names(store_path: &Path) -> Result<Vec<String>> {
+    let keyspace_dir = store_path.join(KEYSPACE_DIR);
+    if !keyspace_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names: Vec<String> = keyspace_dir
+        .read_dir()?
+        .flat_map(|f| -> Result<_> { Ok(f?.path()) })
+        .filter(|path| path.is_dir())
+        .flat_map(|path| path.file_name().and_then(OsStr::to_str).map(str::to_owned))
+        .filter(|name| check_keyspace_name(name).is_ok())
+        .collect();
+    names.sort_unstable();
+
+    Ok(names)
+}
+
+/// Keyspace names become directory names, so only a safe set of characters is allowed
+pub(crate) fn check_keyspace_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if valid {
+        Ok(())
+    } else {
+        Err(KvError::InvalidKeyspace(name.to_owned()))
+    }
+}
+
+/// `BTreeMap::range` panics on inverted bounds, so those are checked up front
+fn is_empty_range(start: &Bound<String>, end: &Bound<String>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s), Bound::Excluded(e))
+        | (Bound::Excluded(s), Bound::Included(e))
+        | (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+        _ => false,
+    }
+}
+
 impl KvsEngine for KvStore {
     /// Retrieves the value associated with the key.
-    fn get(&self, key: String) -> Result<Option<String>> {
+    fn get_typed(&self, key: String, value_type: ValueType) -> Result<Option<String>> {
         let index = self.index.read().unwrap();
-        let command_pos = index.get(&key);
-        if let Some(command) = command_pos {
-            let cmd = self.read_log(&command)?;
-            match cmd {
-                Command::Set(_, value) => Ok(Some(value)),
-                Command::Rm(_) => Err(KvError::InternalError),
-            }
-        } else {
-            Ok(None)
+        match index.get(&key) {
+            Some(cmd_pos) if cmd_pos.value_type != value_type => Err(KvError::WrongType),
+            Some(cmd_pos) => self.read_log(cmd_pos)?.into_value().map(Some),
+            None => Ok(None),
         }
     }
 
     /// Sets the value of a key. If the key already exists, it will overwrite the current value.
     fn set(&self, key: String, value: String) -> Result<()> {
         {
+            let mut index = self.index.write().unwrap();
             let mut writer = self.writer.write().unwrap();
+            writer.check_open()?;
             let cmd = Command::Set(key.to_string(), value);
             let cmd_pos = KvStore::write_log(&writer.writer, &cmd, writer.current_gen)?;
-            if let Some(old_cmd) = self.index.write().unwrap().insert(key, cmd_pos) {
+            if let Some(old_cmd) = index.insert(key.clone(), cmd_pos) {
                 writer.compact_space += old_cmd.len;
             }
+            // changes are recorded once the index agrees with the log
+            if let Command::Set(_, value) = cmd {
+                self.secondary.write().unwrap().insert(&key, &value);
+                self.changes.record(&key, ChangeOp::Set(value));
+            }
+        }
+
+        if self.writer.read().unwrap().compact_space > COMPACTION_THRESHOLD {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets several keys with a single write to the log.
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        {
+            let mut index = self.index.write().unwrap();
+            let mut writer = self.writer.write().unwrap();
+            writer.check_open()?;
+            let mut buf = Vec::new();
+            let mut entries = Vec::with_capacity(pairs.len());
+            for (key, value) in pairs {
+                let start = buf.len() as u64;
+                let cmd = Command::Set(key.clone(), value);
+                serde_json::to_writer(&mut buf, &cmd)?;
+                entries.push((cmd, start, buf.len() as u64 - start));
+            }
+            let pos = writer.writer.seek(SeekFrom::End(0))?;
+            writer.writer.write_all(&buf)?;
+
+            let mut secondary = self.secondary.write().unwrap();
+            let mut changes = Vec::with_capacity(entries.len());
+            for (cmd, start, len) in entries {
+                let (key, value) = match cmd {
+                    Command::Set(key, value) => (key, value),
+                    _ => return Err(KvError::InternalError),
+                };
+                let cmd_pos = CommandPos {
+                    gen: writer.current_gen,
+                    pos: pos + start,
+                    len,
+                    value_type: ValueType::String,
+                };
+                if let Some(old_cmd) = index.insert(key.clone(), cmd_pos) {
+                    writer.compact_space += old_cmd.len;
+                }
+                secondary.insert(&key, &value);
+                changes.push((key, value));
+            }
+            // the whole batch is indexed before any of it is recorded
+            for (key, value) in changes {
+                self.changes.record(&key, ChangeOp::Set(value));
+            }
+        }
+
+        if self.writer.read().unwrap().compact_space > COMPACTION_THRESHOLD {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads and rewrites the value while holding the write lock. Appends are
+    /// written as delta records.
+    fn update_typed<F>(&self, key: String, value_type: ValueType, mut f: F) -> Result<()>
+    where
+        F: FnMut(Option<&str>) -> Result<Update>,
+    {
+        {
+            let mut index = self.index.write().unwrap();
+            let mut writer = self.writer.write().unwrap();
+            writer.check_open()?;
+            let current = match index.get(&key) {
+                Some(cmd_pos) if cmd_pos.value_type != value_type => return Err(KvError::WrongType),
+                Some(cmd_pos) => Some(self.read_log(cmd_pos)?.into_value()?),
+                None => None,
+            };
+            let cmd = match (f(current.as_deref())?, index.get(&key)) {
+                (Update::Unchanged, _) => return Ok(()),
+                (Update::Append(value), Some(prev)) => Command::Append(key.clone(), value, prev.clone()),
+                (Update::Set(value), _) | (Update::Append(value), None) => Command::set(key.clone(), value_type, value),
+            };
+
+            let cmd_pos = KvStore::write_log(&writer.writer, &cmd, writer.current_gen)?;
+            let len = cmd_pos.len;
+            match (&cmd, index.insert(key.clone(), cmd_pos)) {
+                (Command::Append(..), _) => writer.compact_space += len,
+                (_, Some(old_cmd)) => writer.compact_space += old_cmd.len,
+                _ => {}
+            }
+            let mut secondary = self.secondary.write().unwrap();
+            match cmd {
+                // only plain strings are indexed or have their contents recorded
+                _ if !value_type.is_string() => self.changes.record(&key, ChangeOp::Modified(value_type)),
+                Command::Append(_, delta, _) => {
+                    secondary.insert(&key, &format!("{}{}", current.unwrap_or_default(), delta));
+                    self.changes.record(&key, ChangeOp::Append(delta));
+                }
+                Command::Set(_, value) => {
+                    secondary.insert(&key, &value);
+                    self.changes.record(&key, ChangeOp::Set(value));
+                }
+                Command::Rm(_) | Command::SetTyped(..) => {}
+            }
+        }
+
+        if self.writer.read().unwrap().compact_space > COMPACTION_THRESHOLD {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Appends to the value by writing a delta record that points at the key's
+    /// previous record, so the existing value is not rewritten.
+    fn append(&self, key: String, value: String) -> Result<()> {
+        {
+            let mut index = self.index.write().unwrap();
+            let mut writer = self.writer.write().unwrap();
+            writer.check_open()?;
+            let cmd = match index.get(&key) {
+                Some(prev) if !prev.value_type.is_string() => return Err(KvError::WrongType),
+                Some(prev) => Command::Append(key.clone(), value, prev.clone()),
+                None => Command::Set(key.clone(), value),
+            };
+            let cmd_pos = KvStore::write_log(&writer.writer, &cmd, writer.current_gen)?;
+            if let Command::Append(..) = cmd {
+                writer.compact_space += cmd_pos.len;
+            }
+            let mut secondary = self.secondary.write().unwrap();
+            if !secondary.is_empty() {
+                if let Command::Set(_, value) = self.read_log(&cmd_pos)? {
+                    secondary.insert(&key, &value);
+                }
+            }
+            index.insert(key.clone(), cmd_pos);
+            match cmd {
+                Command::Append(_, delta, _) => self.changes.record(&key, ChangeOp::Append(delta)),
+                Command::Set(_, value) => self.changes.record(&key, ChangeOp::Set(value)),
+                Command::Rm(_) | Command::SetTyped(..) => {}
+            }
         }
 
         if self.writer.read().unwrap().compact_space > COMPACTION_THRESHOLD {
@@ -300,10 +828,13 @@ impl KvsEngine for KvStore {
         {
             let mut index = self.index.write().unwrap();
             let mut writer = self.writer.write().unwrap();
+            writer.check_open()?;
             if let Some(old_cmd) = index.remove(&key) {
                 let cmd = Command::Rm(key.to_string());
                 KvStore::write_log(&writer.writer, &cmd, writer.current_gen)?;
                 writer.compact_space += old_cmd.len;
+                self.secondary.write().unwrap().remove(&key);
+                self.changes.record(&key, ChangeOp::Remove);
             } else {
                 return Err(KvError::KeyNotFound);
             }
@@ -315,4 +846,175 @@ impl KvsEngine for KvStore {
 
         Ok(())
     }
+
+    /// Returns the live key-value pairs within the bounds that hold plain
+    /// strings, in key order.
+    fn scan(&self, start: Bound<String>, end: Bound<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        if is_empty_range(&start, &end) {
+            return Ok(pairs);
+        }
+        let index = self.index.read().unwrap();
+        let strings = index.range((start, end)).filter(|(_, cmd_pos)| cmd_pos.value_type.is_string());
+        for (key, cmd_pos) in strings.take(limit) {
+            pairs.push((key.clone(), self.read_log(cmd_pos)?.into_value()?));
+        }
+
+        Ok(pairs)
+    }
+
+    /// Returns the live keys within the bounds with their types and values, in key order.
+    fn scan_typed(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, ValueType, String)>> {
+        let mut entries = Vec::new();
+        if is_empty_range(&start, &end) {
+            return Ok(entries);
+        }
+        let index = self.index.read().unwrap();
+        for (key, cmd_pos) in index.range((start, end)).take(limit) {
+            entries.push((key.clone(), cmd_pos.value_type, self.read_log(cmd_pos)?.into_value()?));
+        }
+
+        Ok(entries)
+    }
+
+    /// Declares the index and builds it from the current values. The
+    /// definition is saved so the index is rebuilt when the store is opened.
+    fn create_index(&self, name: &str, path: &str) -> Result<()> {
+        let index = self.index.read().unwrap();
+        self.secondary.write().unwrap().create(name, path)?;
+        let res = self
+            .rebuild_secondary(&index)
+            .and_then(|_| self.secondary.read().unwrap().save(&self.store_path));
+        if res.is_err() {
+            let _ = self.secondary.write().unwrap().drop_index(name);
+        }
+
+        res
+    }
+
+    /// Removes the index and its saved definition.
+    fn drop_index(&self, name: &str) -> Result<()> {
+        let mut secondary = self.secondary.write().unwrap();
+        secondary.drop_index(name)?;
+        secondary.save(&self.store_path)
+    }
+
+    /// Looks up the keys whose value has `value` at the index's path.
+    fn query_index(&self, name: &str, value: &str) -> Result<Vec<String>> {
+        self.secondary.read().unwrap().query(name, value)
+    }
+
+    /// Streams the changes recorded in the change log from `from` on.
+    fn subscribe(&self, from: u64) -> Result<ChangeStream> {
+        self.changes.subscribe(from)
+    }
+
+    fn last_sequence(&self) -> Result<u64> {
+        Ok(self.changes.last_sequence())
+    }
+
+    /// Writes a consistent copy of the store into `dir`, see `KvStore::backup_to`.
+    fn backup(&self, dir: &Path) -> Result<()> {
+        self.backup_to(dir).map(|_| ())
+    }
+
+    /// Writes the records added since the backup in `previous`, see `KvStore::backup_incremental`.
+    fn backup_incremental(&self, previous: &Path, dir: &Path) -> Result<()> {
+        KvStore::backup_incremental(self, previous, dir).map(|_| ())
+    }
+
+    /// Syncs the active log file to disk.
+    fn flush(&self) -> Result<()> {
+        for keyspace in self.open_keyspaces() {
+            keyspace.flush()?;
+        }
+        let writer = self.writer.read().unwrap();
+        writer.writer.sync_data()?;
+
+        Ok(())
+    }
+
+    /// Syncs the log and writes a clean shutdown marker, for the store and
+    /// every open keyspace. Any further writes through this store or its
+    /// clones will fail with `StoreClosed`.
+    fn close(&self) -> Result<()> {
+        for keyspace in self.open_keyspaces() {
+            keyspace.close()?;
+        }
+        let index = self.index.read().unwrap();
+        let mut writer = self.writer.write().unwrap();
+        if writer.closed {
+            return Ok(());
+        }
+        writer.writer.sync_data()?;
+
+        let marker = ShutdownMarker {
+            current_gen: writer.current_gen,
+            log_len: writer.writer.metadata()?.len(),
+            compact_space: writer.compact_space,
+            index: index.clone(),
+        };
+        // write to a temporary file first so a torn marker is never picked up
+        let tmp_path = self.store_path.join(format!("{}.tmp", SHUTDOWN_MARKER));
+        let tmp = File::create(&tmp_path)?;
+        serde_json::to_writer(&tmp, &marker)?;
+        tmp.sync_all()?;
+        std::fs::rename(&tmp_path, self.store_path.join(SHUTDOWN_MARKER))?;
+        writer.closed = true;
+        let res = self.changes.close();
+        writer.lock = None;
+
+        res
+    }
+
+    fn materialized(&self) -> Option<&Materialized> {
+        Some(&self.materialized)
+    }
+
+    /// Opens the keyspace stored in `keyspaces/<name>`, creating it if needed.
+    /// Every keyspace has its own logs, index and compaction.
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        let keyspaces = self
+            .keyspaces
+            .as_ref()
+            .ok_or_else(|| KvError::InvalidKeyspace(name.to_owned()))?;
+        check_keyspace_name(name)?;
+        let mut keyspaces = keyspaces.lock().unwrap();
+        if let Some(keyspace) = keyspaces.get(name) {
+            return Ok(keyspace.clone());
+        }
+
+        let keyspace = KvStore::open_logs(self.store_path.join(KEYSPACE_DIR).join(name))?;
+        keyspaces.insert(name.to_owned(), keyspace.clone());
+
+        Ok(keyspace)
+    }
+
+    /// Lists the keyspaces stored on disk.
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        if self.keyspaces.is_none() {
+            return Ok(Vec::new());
+        }
+
+        keyspace_names(&self.store_path)
+    }
+
+    /// Closes the keyspace and deletes its directory.
+    fn drop_keyspace(&self, name: &str) -> Result<()> {
+        if !self.keyspaces()?.iter().any(|k| k == name) {
+            return Err(KvError::InvalidKeyspace(name.to_owned()));
+        }
+        let keyspace = self.keyspace(name)?;
+        keyspace.writer.write().unwrap().closed = true;
+        keyspace.changes.close()?;
+        self.keyspaces.as_ref().unwrap().lock().unwrap().remove(name);
+        std::fs::remove_dir_all(&keyspace.store_path)?;
+
+        Ok(())
+    }
 }
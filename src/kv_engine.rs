@@ -1,13 +1,226 @@
-use crate::errors::Result;
+use crate::changes::ChangeStream;
+use crate::collections::Materialized;
+use crate::errors::{KvError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Bound;
+use std::path::Path;
+
+/// The change to make to a value, returned by the closure passed to `KvsEngine::update`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Update {
+    /// Leave the value as it is
+    Unchanged,
+    /// Replace the value
+    Set(String),
+    /// Append to the value, creating it if it does not exist
+    Append(String),
+}
+
+/// The type of the value stored at a key. Engines keep it alongside the value,
+/// so that no plain string can be mistaken for a value of another type.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ValueType {
+    /// A plain string, as written by `set`
+    #[default]
+    String,
+    /// A hash, see `Collections`
+    Hash,
+    /// A list, see `Collections`
+    List,
+    /// A set, see `Collections`
+    Set,
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ValueType::String => "string",
+            ValueType::Hash => "hash",
+            ValueType::List => "list",
+            ValueType::Set => "set",
+        };
+        f.write_str(name)
+    }
+}
+
+impl ValueType {
+    pub(crate) fn is_string(&self) -> bool {
+        *self == ValueType::String
+    }
+}
 
 /// Trait for engines that are compatible with the KV Store
 pub trait KvsEngine: Clone + Send + 'static {
-    /// Get a particular key from the store
-    fn get(&self, key: String) -> Result<Option<String>>;
+    /// Get a particular key from the store. Fails with `WrongType` if the key
+    /// does not hold a plain string.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_typed(key, ValueType::String)
+    }
+
+    /// Gets the value of a key holding a value of the given type, failing with
+    /// `WrongType` if it holds a value of another type.
+    fn get_typed(&self, key: String, value_type: ValueType) -> Result<Option<String>>;
 
-    /// Set the value of a key. If the key already exists, it will overwrite the value.
+    /// Set the value of a key. If the key already exists, it will overwrite the value,
+    /// whatever its type.
     fn set(&self, key: String, value: String) -> Result<()>;
 
+    /// Sets the values of several keys at once.
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()>;
+
+    /// Atomically reads the value of the key, passing `None` if it does not exist,
+    /// and applies the `Update` returned by `f`. `f` may be called more than once,
+    /// and an error returned by it leaves the value unchanged. Fails with
+    /// `WrongType` if the key does not hold a plain string.
+    fn update<F>(&self, key: String, f: F) -> Result<()>
+    where
+        F: FnMut(Option<&str>) -> Result<Update>,
+    {
+        self.update_typed(key, ValueType::String, f)
+    }
+
+    /// Like `update`, for a key holding a value of the given type. Fails with
+    /// `WrongType`, before calling `f`, if the key holds a value of another
+    /// type. The values written by `f` are of the given type.
+    fn update_typed<F>(&self, key: String, value_type: ValueType, f: F) -> Result<()>
+    where
+        F: FnMut(Option<&str>) -> Result<Update>;
+
+    /// Adds `delta` to the integer stored at the key and returns the new value.
+    /// A missing key counts as zero.
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        let mut value = 0;
+        self.update(key, |current| {
+            let current = match current {
+                Some(current) => current.parse::<i64>().map_err(|_| KvError::NotAnInteger)?,
+                None => 0,
+            };
+            value = current.checked_add(delta).ok_or(KvError::IntegerOverflow)?;
+            Ok(Update::Set(value.to_string()))
+        })?;
+
+        Ok(value)
+    }
+
+    /// Subtracts `delta` from the integer stored at the key and returns the new value.
+    fn decr_by(&self, key: String, delta: i64) -> Result<i64> {
+        let delta = delta.checked_neg().ok_or(KvError::IntegerOverflow)?;
+        self.incr_by(key, delta)
+    }
+
+    /// Appends `value` to the value of the key, creating the key if it does not exist.
+    /// Fails with `WrongType` if the key does not hold a plain string.
+    fn append(&self, key: String, value: String) -> Result<()>;
+
+    /// Returns up to `len` bytes of the value of the key starting at byte `offset`.
+    /// The range is clamped to the end of the value.
+    fn get_range(&self, key: String, offset: usize, len: usize) -> Result<Option<String>> {
+        match self.get(key)? {
+            Some(value) => {
+                let start = offset.min(value.len());
+                let end = start.saturating_add(len).min(value.len());
+                value.get(start..end).map(|v| Some(v.to_owned())).ok_or(KvError::InvalidRange)
+            }
+            None => Ok(None),
+        }
+    }
+
     /// Removes the key from the store. If the key does not exist, a KeyNotFound error will be returned.
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Returns up to `limit` key-value pairs within the given bounds, in key order.
+    /// Keys holding values of another type than plain strings are skipped.
+    fn scan(&self, start: Bound<String>, end: Bound<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        let mut start = start;
+        while pairs.len() < limit {
+            let wanted = limit - pairs.len();
+            let page = self.scan_typed(start, end.clone(), wanted)?;
+            match page.last() {
+                Some((key, _, _)) => start = Bound::Excluded(key.clone()),
+                None => break,
+            }
+            let done = page.len() < wanted;
+            pairs.extend(
+                page.into_iter()
+                    .filter(|(_, value_type, _)| value_type.is_string())
+                    .map(|(key, _, value)| (key, value)),
+            );
+            if done {
+                break;
+            }
+        }
+
+        Ok(pairs)
+    }
+
+    /// Returns up to `limit` keys within the given bounds, in key order, with
+    /// the type and value of each.
+    fn scan_typed(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, ValueType, String)>>;
+
+    /// Declares a secondary index named `name` on the field at `path`, such as
+    /// `$.email`, of JSON values. Values that are not JSON objects with that
+    /// field are not indexed.
+    fn create_index(&self, name: &str, path: &str) -> Result<()>;
+
+    /// Removes a secondary index.
+    fn drop_index(&self, name: &str) -> Result<()>;
+
+    /// Returns the keys, in key order, whose value has `value` at the path of the index.
+    fn query_index(&self, name: &str, value: &str) -> Result<Vec<String>>;
+
+    /// Streams every mutation with a sequence number of at least `from`, starting
+    /// with those that are still retained. Fails if changes from `from` on are no
+    /// longer retained.
+    fn subscribe(&self, from: u64) -> Result<ChangeStream>;
+
+    /// Returns the sequence number of the most recent change, so that
+    /// subscribing from the next one streams only changes made from now on.
+    fn last_sequence(&self) -> Result<u64>;
+
+    /// Writes a consistent copy of the engine's data into `dir` while it keeps running.
+    fn backup(&self, dir: &Path) -> Result<()>;
+
+    /// Writes only the changes made since the backup in `previous` into `dir`.
+    fn backup_incremental(&self, previous: &Path, dir: &Path) -> Result<()>;
+
+    /// Opens a named keyspace, creating it if it does not exist. Keys in a keyspace
+    /// are independent of the keys in the engine and in other keyspaces.
+    fn keyspace(&self, name: &str) -> Result<Self>;
+
+    /// Opens a keyspace only if it exists, failing with `InvalidKeyspace`
+    /// otherwise, so that a mistyped name does not create one.
+    fn existing_keyspace(&self, name: &str) -> Result<Self> {
+        if self.keyspaces()?.iter().any(|k| k == name) {
+            self.keyspace(name)
+        } else {
+            Err(KvError::InvalidKeyspace(name.to_owned()))
+        }
+    }
+
+    /// Lists the names of the keyspaces in the engine.
+    fn keyspaces(&self) -> Result<Vec<String>>;
+
+    /// Deletes a keyspace and all of its data.
+    fn drop_keyspace(&self, name: &str) -> Result<()>;
+
+    /// Flushes all pending writes to durable storage.
+    fn flush(&self) -> Result<()>;
+
+    /// Flushes the engine and marks it as cleanly shut down. Writes after
+    /// `close` fail with `KvError::StoreClosed`.
+    fn close(&self) -> Result<()>;
+
+    /// Where `Collections` keeps the collections of this store or keyspace
+    /// decoded, if the engine has room for them
+    #[doc(hidden)]
+    fn materialized(&self) -> Option<&Materialized> {
+        None
+    }
 }
@@ -1,8 +1,17 @@
+use crate::changes::Change;
+use crate::errors::KvError;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::ops::Bound;
 
 pub type Key = String;
 pub type Value = String;
 
+/// The newest version of the JSON protocol this library speaks. Version 1
+/// sends errors as a plain message; version 2 sends them as a `ServerError`.
+pub const JSON_PROTOCOL_VERSION: u16 = 2;
+
 /// Commands that can be sent from the client to the server
 #[derive(Debug, Serialize, Deserialize)]
 pub enum KvRequest {
@@ -12,6 +21,153 @@ pub enum KvRequest {
     Set(Key, Value),
     /// Remove the value of key
     Rm(Key),
+    /// Atomically add to the integer value of key, responding with the new value
+    Incr(Key, i64),
+    /// Atomically subtract from the integer value of key, responding with the new value
+    Decr(Key, i64),
+    /// Append to the value of key
+    Append(Key, Value),
+    /// Get `len` bytes of the value of key starting at byte `offset`
+    GetRange(Key, usize, usize),
+    /// Set a field of the hash at key
+    HSet(Key, String, Value),
+    /// Get a field of the hash at key
+    HGet(Key, String),
+    /// Get every field of the hash at key, responding with a JSON object
+    HGetAll(Key),
+    /// Push an element onto the front of the list at key, responding with the new length
+    LPush(Key, Value),
+    /// Remove and return the element at the back of the list at key
+    RPop(Key),
+    /// Get the elements of the list at key between two inclusive indexes, responding with a JSON array
+    LRange(Key, i64, i64),
+    /// Add a member to the set at key, responding with whether it was added
+    SAdd(Key, Value),
+    /// Get the members of the set at key, responding with a JSON array
+    SMembers(Key),
+    /// Declare a secondary index with the given name on a JSON path such as `$.email`
+    CreateIndex(String, String),
+    /// Remove a secondary index
+    DropIndex(String),
+    /// Find the keys whose indexed field equals the value, responding with a JSON array
+    QueryIndex(String, String),
+    /// Stream every change from the given sequence number on, keeping the
+    /// connection open until the client closes its side of it
+    Changes(u64),
+    /// Acknowledge with a `Success`, then stream every later change to the
+    /// watched keys, keeping the connection open until the client closes its
+    /// side of it
+    Watch(Watched),
+    /// Send a message to every subscriber of the channel, responding with the
+    /// number of subscribers that received it
+    Publish(String, String),
+    /// Acknowledge with a `Success`, then stream the messages published to
+    /// every channel matching one of the patterns, keeping the connection open
+    /// until the client closes its side of it.
+    /// In a pattern `*` matches any run of characters and `?` any one character.
+    Subscribe(Vec<String>),
+    /// Write a consistent backup of the whole store into the named directory
+    /// within the server's backup directory
+    Backup(String),
+    /// Write the changes since the first named backup into the second, both
+    /// within the server's backup directory
+    IncrementalBackup(String, String),
+    /// Run the inner request against the named keyspace
+    Keyspace(String, Box<KvRequest>),
+    /// Delete a keyspace and all of its data
+    DropKeyspace(String),
+    /// Get up to the given number of key-value pairs within the bounds, in key
+    /// order, responding with a JSON array of `[key, value]` arrays
+    Scan(Bound<Key>, Bound<Key>, usize),
+    /// Run each request in order, responding with a `Batch` of their responses.
+    /// Requests that are not `batchable` fail within it.
+    Batch(Vec<KvRequest>),
+    /// Ask to speak the given version of the JSON protocol for the rest of the
+    /// connection, responding with the version the server chose. A connection
+    /// that does not send it speaks version 1.
+    Hello(u16),
+}
+
+impl KvRequest {
+    /// Whether the request may store data, so that a keyspace it runs in is
+    /// created if it does not exist yet. Other requests fail on a keyspace
+    /// that does not exist.
+    pub fn stores_data(&self) -> bool {
+        match self {
+            KvRequest::Set(..)
+            | KvRequest::Incr(..)
+            | KvRequest::Decr(..)
+            | KvRequest::Append(..)
+            | KvRequest::HSet(..)
+            | KvRequest::LPush(..)
+            | KvRequest::SAdd(..)
+            | KvRequest::CreateIndex(..) => true,
+            KvRequest::Batch(requests) => requests.iter().any(KvRequest::stores_data),
+            _ => false,
+        }
+    }
+
+    /// Whether the request can run within a `Batch`. Streams, channels,
+    /// backups and `Hello` belong to a connection or the whole store instead.
+    pub fn batchable(&self) -> bool {
+        match self {
+            KvRequest::Changes(_)
+            | KvRequest::Watch(_)
+            | KvRequest::Publish(..)
+            | KvRequest::Subscribe(_)
+            | KvRequest::Backup(_)
+            | KvRequest::IncrementalBackup(..)
+            | KvRequest::Hello(_) => false,
+            KvRequest::Keyspace(_, req) => req.batchable(),
+            KvRequest::Batch(requests) => requests.iter().all(KvRequest::batchable),
+            _ => true,
+        }
+    }
+}
+
+/// A request carrying a client-chosen ID, which the server echoes in a
+/// `TaggedResponse`. Tagged requests can be pipelined: a client may send any
+/// number of them before reading the responses.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TaggedRequest {
+    /// Identifies the response to this request
+    pub id: u64,
+    /// The request itself
+    pub request: KvRequest,
+    /// Allow the server to respond to this request before the requests sent
+    /// ahead of it, running it concurrently with them. Requests that stream
+    /// their responses are always handled in order.
+    #[serde(default)]
+    pub unordered: bool,
+}
+
+/// A response to a `TaggedRequest`. Every response streamed for a request
+/// carries the request's ID.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TaggedResponse {
+    /// The ID of the request
+    pub id: u64,
+    /// The response itself
+    pub response: KvResponse,
+}
+
+/// The keys selected by `KvRequest::Watch`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Watched {
+    /// A single key
+    Key(Key),
+    /// Every key starting with the prefix
+    Prefix(String),
+}
+
+impl Watched {
+    /// Whether the key is selected
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            Watched::Key(watched) => key == watched,
+            Watched::Prefix(prefix) => key.starts_with(prefix.as_str()),
+        }
+    }
 }
 
 /// Response from the kv server
@@ -20,5 +176,228 @@ pub enum KvResponse {
     /// A successful operation
     Success(Option<String>),
     /// An error on the server side
-    Error(String),
+    Error(ServerError),
+    /// A change streamed in response to `KvRequest::Changes` or `KvRequest::Watch`
+    Change(Change),
+    /// A message streamed in response to `KvRequest::Subscribe`, with its channel
+    Message(String, String),
+    /// The response to each request of a `KvRequest::Batch`, in order
+    Batch(Vec<KvResponse>),
+}
+
+/// An error reported by the server. Servers speaking version 1 of a protocol
+/// report errors as a plain message, which is read as an error of unknown
+/// kind unless it is the message of a missing key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "WireError")]
+pub struct ServerError {
+    /// What went wrong
+    pub code: ErrorCode,
+    /// Description of the error for people
+    pub message: String,
+    /// Whether the same request may succeed if it is sent again
+    pub retryable: bool,
+}
+
+/// The kind of a `ServerError`, mirroring the variants of `KvError`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// `KvError::Serde`
+    Serde,
+    /// `KvError::Io`
+    Io,
+    /// `KvError::KeyNotFound`
+    KeyNotFound,
+    /// `KvError::InternalError`
+    InternalError,
+    /// `KvError::MissingLogFile`
+    MissingLogFile,
+    /// `KvError::MalformedRequest`
+    MalformedRequest,
+    /// `KvError::SledError`
+    SledError,
+    /// `KvError::StoreClosed`
+    StoreClosed,
+    /// `KvError::CorruptLog`
+    CorruptLog {
+        /// Generation of the damaged log file
+        gen: u64,
+        /// Byte offset of the damaged record
+        offset: u64,
+    },
+    /// `KvError::NotAnInteger`
+    NotAnInteger,
+    /// `KvError::IntegerOverflow`
+    IntegerOverflow,
+    /// `KvError::WrongType`
+    WrongType,
+    /// `KvError::InvalidIndex`
+    InvalidIndex(String),
+    /// `KvError::SequenceUnavailable`
+    SequenceUnavailable(u64),
+    /// `KvError::InvalidRange`
+    InvalidRange,
+    /// `KvError::InvalidKeyspace`
+    InvalidKeyspace(String),
+    /// `KvError::Unsupported`
+    Unsupported,
+    /// `KvError::VerificationFailed`
+    VerificationFailed(String),
+    /// `KvError::UnsupportedVersion`
+    UnsupportedVersion(u16),
+    /// `KvError::Lagged`
+    Lagged,
+    /// `KvError::Timeout`
+    Timeout,
+}
+
+/// The forms a `ServerError` takes on the wire
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WireError {
+    Typed {
+        code: ErrorCode,
+        message: String,
+        retryable: bool,
+    },
+    Plain(String),
+}
+
+impl From<WireError> for ServerError {
+    fn from(err: WireError) -> ServerError {
+        match err {
+            WireError::Typed { code, message, retryable } => ServerError { code, message, retryable },
+            WireError::Plain(message) if message == KvError::KeyNotFound.to_string() => {
+                ServerError::new(ErrorCode::KeyNotFound, &message)
+            }
+            WireError::Plain(message) => ServerError::new(ErrorCode::InternalError, &message),
+        }
+    }
+}
+
+/// A response as version 1 of the protocols sends it, with errors as their message
+#[derive(Serialize)]
+pub(crate) enum LegacyResponse<'a> {
+    Success(&'a Option<String>),
+    Error(&'a str),
+    Change(&'a Change),
+    Message(&'a str, &'a str),
+    Batch(Vec<LegacyResponse<'a>>),
+}
+
+impl<'a> From<&'a KvResponse> for LegacyResponse<'a> {
+    fn from(response: &'a KvResponse) -> Self {
+        match response {
+            KvResponse::Success(value) => LegacyResponse::Success(value),
+            KvResponse::Error(err) => LegacyResponse::Error(&err.message),
+            KvResponse::Change(change) => LegacyResponse::Change(change),
+            KvResponse::Message(channel, message) => LegacyResponse::Message(channel, message),
+            KvResponse::Batch(responses) => LegacyResponse::Batch(responses.iter().map(LegacyResponse::from).collect()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct LegacyTaggedResponse<'a> {
+    id: u64,
+    response: LegacyResponse<'a>,
+}
+
+/// Encodes a response as JSON for a client speaking the given version of the
+/// protocol, tagged with the ID of its request if it has one
+pub(crate) fn encode_json(id: Option<u64>, response: KvResponse, version: u16) -> serde_json::Result<Vec<u8>> {
+    match id {
+        Some(id) if version >= 2 => serde_json::to_vec(&TaggedResponse { id, response }),
+        None if version >= 2 => serde_json::to_vec(&response),
+        Some(id) => serde_json::to_vec(&LegacyTaggedResponse { id, response: (&response).into() }),
+        None => serde_json::to_vec(&LegacyResponse::from(&response)),
+    }
+}
+
+impl ServerError {
+    /// An error of the given kind with a message other than the usual one
+    pub fn new(code: ErrorCode, message: &str) -> Self {
+        let retryable = code.is_retryable();
+        ServerError {
+            code,
+            message: message.to_owned(),
+            retryable,
+        }
+    }
+}
+
+impl ErrorCode {
+    /// Errors that come from the state of the server rather than the request
+    fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ErrorCode::Io | ErrorCode::SledError | ErrorCode::StoreClosed | ErrorCode::Lagged | ErrorCode::Timeout
+        )
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<KvError> for ServerError {
+    fn from(err: KvError) -> ServerError {
+        let code = match err {
+            KvError::Serde(_) => ErrorCode::Serde,
+            KvError::Io(_) => ErrorCode::Io,
+            KvError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvError::InternalError => ErrorCode::InternalError,
+            KvError::MissingLogFile => ErrorCode::MissingLogFile,
+            KvError::MalformedRequest => ErrorCode::MalformedRequest,
+            KvError::SledError(_) => ErrorCode::SledError,
+            KvError::StoreClosed => ErrorCode::StoreClosed,
+            KvError::CorruptLog { gen, offset } => ErrorCode::CorruptLog { gen, offset },
+            KvError::NotAnInteger => ErrorCode::NotAnInteger,
+            KvError::IntegerOverflow => ErrorCode::IntegerOverflow,
+            KvError::WrongType => ErrorCode::WrongType,
+            KvError::InvalidIndex(ref name) => ErrorCode::InvalidIndex(name.clone()),
+            KvError::SequenceUnavailable(oldest) => ErrorCode::SequenceUnavailable(oldest),
+            KvError::InvalidRange => ErrorCode::InvalidRange,
+            KvError::InvalidKeyspace(ref name) => ErrorCode::InvalidKeyspace(name.clone()),
+            KvError::Unsupported => ErrorCode::Unsupported,
+            KvError::VerificationFailed(ref msg) => ErrorCode::VerificationFailed(msg.clone()),
+            KvError::UnsupportedVersion(version) => ErrorCode::UnsupportedVersion(version),
+            KvError::Lagged => ErrorCode::Lagged,
+            KvError::Timeout => ErrorCode::Timeout,
+        };
+
+        ServerError::new(code, &err.to_string())
+    }
+}
+
+/// Rebuilds the error the server ran into. Errors from the libraries the
+/// server uses cannot be rebuilt, so they arrive as I/O or serde errors
+/// carrying the server's message.
+impl From<ServerError> for KvError {
+    fn from(err: ServerError) -> KvError {
+        match err.code {
+            ErrorCode::Serde => KvError::Serde(serde::de::Error::custom(err.message)),
+            ErrorCode::Io | ErrorCode::SledError => KvError::Io(io::Error::other(err.message)),
+            ErrorCode::KeyNotFound => KvError::KeyNotFound,
+            ErrorCode::InternalError => KvError::InternalError,
+            ErrorCode::MissingLogFile => KvError::MissingLogFile,
+            ErrorCode::MalformedRequest => KvError::MalformedRequest,
+            ErrorCode::StoreClosed => KvError::StoreClosed,
+            ErrorCode::CorruptLog { gen, offset } => KvError::CorruptLog { gen, offset },
+            ErrorCode::NotAnInteger => KvError::NotAnInteger,
+            ErrorCode::IntegerOverflow => KvError::IntegerOverflow,
+            ErrorCode::WrongType => KvError::WrongType,
+            ErrorCode::InvalidIndex(name) => KvError::InvalidIndex(name),
+            ErrorCode::SequenceUnavailable(oldest) => KvError::SequenceUnavailable(oldest),
+            ErrorCode::InvalidRange => KvError::InvalidRange,
+            ErrorCode::InvalidKeyspace(name) => KvError::InvalidKeyspace(name),
+            ErrorCode::Unsupported => KvError::Unsupported,
+            ErrorCode::VerificationFailed(msg) => KvError::VerificationFailed(msg),
+            ErrorCode::UnsupportedVersion(version) => KvError::UnsupportedVersion(version),
+            ErrorCode::Lagged => KvError::Lagged,
+            ErrorCode::Timeout => KvError::Timeout,
+        }
+    }
 }
@@ -8,19 +8,42 @@
 #[macro_use]
 extern crate slog;
 
+mod async_client;
+pub mod async_server;
+pub mod backup;
+pub mod binary_protocol;
+pub mod bulk_load;
+pub mod changes;
+mod client;
+mod client_pool;
+mod collections;
 mod errors;
+pub mod grpc;
+mod http_gateway;
+pub mod export;
+pub mod fsck;
 mod kv;
 mod kv_engine;
 mod sled_engine;
 mod kv_protocol;
+mod pubsub;
+mod resp;
+mod secondary_index;
+pub mod migrate;
 pub mod server;
 pub mod thread_pool;
 
+pub use crate::async_client::{AsyncKvsClient, AsyncResponses};
+pub use crate::client::{KvsClient, Responses};
+pub use crate::client_pool::ClientPool;
 pub use crate::errors::{KvError, Result};
 pub use crate::kv::KvStore;
 pub use crate::sled_engine::SledEngine;
-pub use crate::kv_engine::KvsEngine;
-pub use crate::kv_protocol::{KvRequest, KvResponse};
+pub use crate::collections::Collections;
+pub use crate::kv_engine::{KvsEngine, Update, ValueType};
+pub use crate::kv_protocol::{
+    ErrorCode, KvRequest, KvResponse, ServerError, TaggedRequest, TaggedResponse, Watched, JSON_PROTOCOL_VERSION,
+};
 pub use crate::thread_pool::{NaiveThreadPool, ThreadPool, SharedQueueThreadPool};
 
 #[cfg(test)]
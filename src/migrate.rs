@@ -0,0 +1,166 @@
+//! Offline migration of data between engines
+use crate::errors::{KvError, Result};
+use crate::kv_engine::{KvsEngine, Update, ValueType};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+const BATCH_SIZE: usize = 1000;
+
+/// Progress of a migration, persisted after every batch so that an
+/// interrupted migration can be resumed
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MigrationReport {
+    /// Last key copied to the destination
+    pub last_key: Option<String>,
+    /// Number of keys copied so far
+    pub count: u64,
+    /// Running CRC32 of every key, type and value copied, in key order
+    pub checksum: u32,
+    /// Progress of each keyspace copied along with the store, by name
+    #[serde(default)]
+    pub keyspaces: BTreeMap<String, MigrationReport>,
+}
+
+/// Copies every live key from `src` into `dst`, in key order and keeping the
+/// type of its value, followed by
+/// every keyspace of `src` into the keyspace of the same name in `dst`.
+///
+/// Progress is written to `checkpoint` after each batch has been flushed to
+/// `dst`, and for each keyspace to a checkpoint of its own next to it. If a
+/// checkpoint exists the migration resumes after the last copied key. Once
+/// everything is copied the destination is scanned again and its count and
+/// checksum compared against the source; the destination is expected to hold
+/// no other keys. The checkpoints are removed on success.
+pub fn migrate<S: KvsEngine, D: KvsEngine>(
+    src: &S,
+    dst: &D,
+    checkpoint: impl AsRef<Path>,
+) -> Result<MigrationReport> {
+    let checkpoint = checkpoint.as_ref();
+    let mut report = copy(src, dst, checkpoint)?;
+    let mut checkpoints = vec![checkpoint.to_owned()];
+    for name in src.keyspaces()? {
+        let keyspace_checkpoint = keyspace_checkpoint(checkpoint, &name);
+        let keyspace_report = copy(&src.existing_keyspace(&name)?, &dst.keyspace(&name)?, &keyspace_checkpoint)?;
+        report.keyspaces.insert(name, keyspace_report);
+        checkpoints.push(keyspace_checkpoint);
+    }
+
+    dst.close()?;
+    for checkpoint in checkpoints {
+        if checkpoint.exists() {
+            std::fs::remove_file(checkpoint)?;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Copies the keys of one store or keyspace and verifies the copy
+fn copy<S: KvsEngine, D: KvsEngine>(src: &S, dst: &D, checkpoint: &Path) -> Result<MigrationReport> {
+    let mut report = if checkpoint.exists() {
+        serde_json::from_reader(File::open(checkpoint)?)?
+    } else {
+        MigrationReport::default()
+    };
+
+    loop {
+        let start = report
+            .last_key
+            .clone()
+            .map_or(Bound::Unbounded, Bound::Excluded);
+        let batch = src.scan_typed(start, Bound::Unbounded, BATCH_SIZE)?;
+        if batch.is_empty() {
+            break;
+        }
+
+        let mut hasher = crc32fast::Hasher::new_with_initial(report.checksum);
+        for (key, value_type, value) in batch {
+            hash_entry(&mut hasher, &key, value_type, &value);
+            if value_type.is_string() {
+                dst.set(key.clone(), value)?;
+            } else {
+                dst.update_typed(key.clone(), value_type, |_| Ok(Update::Set(value.clone())))?;
+            }
+            report.last_key = Some(key);
+            report.count += 1;
+        }
+        report.checksum = hasher.finalize();
+
+        // the checkpoint must never claim keys that are not durable yet
+        dst.flush()?;
+        write_checkpoint(checkpoint, &report)?;
+    }
+    verify(dst, &report)?;
+
+    Ok(report)
+}
+
+fn keyspace_checkpoint(checkpoint: &Path, name: &str) -> PathBuf {
+    let mut file_name = checkpoint.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{}", name));
+    checkpoint.with_file_name(file_name)
+}
+
+/// Scans `engine` and checks that it holds exactly the keys described by
+/// `report`, and that each keyspace in the report holds those described by its own
+pub fn verify<E: KvsEngine>(engine: &E, report: &MigrationReport) -> Result<()> {
+    let mut count = 0;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut start = Bound::Unbounded;
+    loop {
+        let batch = engine.scan_typed(start, Bound::Unbounded, BATCH_SIZE)?;
+        match batch.last() {
+            Some((key, _, _)) => start = Bound::Excluded(key.clone()),
+            None => break,
+        }
+        for (key, value_type, value) in &batch {
+            hash_entry(&mut hasher, key, *value_type, value);
+            count += 1;
+        }
+    }
+
+    if count != report.count {
+        return Err(KvError::VerificationFailed(format!(
+            "expected {} keys, found {}",
+            report.count, count
+        )));
+    }
+    let checksum = hasher.finalize();
+    if checksum != report.checksum {
+        return Err(KvError::VerificationFailed(format!(
+            "expected checksum {:08x}, found {:08x}",
+            report.checksum, checksum
+        )));
+    }
+    for (name, keyspace_report) in &report.keyspaces {
+        verify(&engine.existing_keyspace(name)?, keyspace_report)?;
+    }
+
+    Ok(())
+}
+
+fn hash_entry(hasher: &mut crc32fast::Hasher, key: &str, value_type: ValueType, value: &str) {
+    // length prefixes keep ("ab", "c") and ("a", "bc") apart
+    hasher.update(&(key.len() as u64).to_le_bytes());
+    hasher.update(key.as_bytes());
+    // other types are named so that they never match a string of the same contents
+    if !value_type.is_string() {
+        hasher.update(value_type.to_string().as_bytes());
+    }
+    hasher.update(&(value.len() as u64).to_le_bytes());
+    hasher.update(value.as_bytes());
+}
+
+fn write_checkpoint(path: &Path, report: &MigrationReport) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let tmp = File::create(&tmp_path)?;
+    serde_json::to_writer(&tmp, report)?;
+    tmp.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
@@ -0,0 +1,154 @@
+//! Publish/subscribe messaging between the clients of a `KvServer`
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Number of messages a subscriber may fall behind by before it is disconnected
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// A message delivered to a subscriber, with the channel it was published to
+pub(crate) type Message = (String, String);
+
+/// Routes published messages to every subscriber whose patterns match the channel
+#[derive(Clone, Default)]
+pub(crate) struct Broker {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+#[derive(Clone)]
+struct Subscriber {
+    patterns: Arc<Vec<String>>,
+    sender: SyncSender<Message>,
+    /// Also tells the subscriber apart from the others
+    lagged: Arc<AtomicBool>,
+}
+
+/// Messages for one subscriber. They end when the broker is closed or when
+/// the subscriber fell too far behind, which `lagged` tells apart. Dropping
+/// the subscription unsubscribes.
+pub(crate) struct Subscription {
+    receiver: Receiver<Message>,
+    lagged: Arc<AtomicBool>,
+    broker: Broker,
+}
+
+impl Subscription {
+    /// Whether messages were dropped because the subscriber could not keep up
+    pub(crate) fn lagged(&self) -> bool {
+        self.lagged.load(Ordering::SeqCst)
+    }
+
+    /// Waits at most `timeout` for the next message, so that the subscriber
+    /// can be checked on while its channels are quiet. Fails with
+    /// `Disconnected` once the messages have ended.
+    pub(crate) fn recv_timeout(&self, timeout: Duration) -> Result<Message, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let lagged = &self.lagged;
+        self.broker
+            .subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| !Arc::ptr_eq(lagged, &subscriber.lagged));
+    }
+}
+
+impl Broker {
+    /// Subscribes to every channel matching one of the patterns, in which `*`
+    /// matches any run of characters and `?` any single character. A pattern
+    /// without wildcards is just a channel name.
+    pub(crate) fn subscribe(&self, patterns: Vec<String>) -> Subscription {
+        let (sender, receiver) = sync_channel(SUBSCRIBER_BUFFER);
+        let lagged = Arc::new(AtomicBool::new(false));
+        self.subscribers.lock().unwrap().push(Subscriber {
+            patterns: Arc::new(patterns),
+            sender,
+            lagged: lagged.clone(),
+        });
+
+        Subscription {
+            receiver,
+            lagged,
+            broker: self.clone(),
+        }
+    }
+
+    /// Delivers the message to every matching subscriber, returning how many
+    /// received it. Publishing never blocks: a subscriber whose buffer is
+    /// full is disconnected instead.
+    pub(crate) fn publish(&self, channel: &str, message: &str) -> usize {
+        // patterns are matched against a copy, so that the lock is not held
+        // while a long pattern is matched
+        let subscribers = self.subscribers.lock().unwrap().clone();
+        let mut receivers = 0;
+        let mut dropped = Vec::new();
+        for subscriber in subscribers {
+            if !subscriber.patterns.iter().any(|p| glob_match(p.as_bytes(), channel.as_bytes())) {
+                continue;
+            }
+            // a concurrent publish may have dropped it already
+            if subscriber.lagged.load(Ordering::SeqCst) {
+                continue;
+            }
+            match subscriber.sender.try_send((channel.to_owned(), message.to_owned())) {
+                Ok(()) => receivers += 1,
+                Err(TrySendError::Full(_)) => {
+                    subscriber.lagged.store(true, Ordering::SeqCst);
+                    dropped.push(subscriber.lagged);
+                }
+                Err(TrySendError::Disconnected(_)) => dropped.push(subscriber.lagged),
+            }
+        }
+        if !dropped.is_empty() {
+            self.subscribers
+                .lock()
+                .unwrap()
+                .retain(|subscriber| !dropped.iter().any(|d| Arc::ptr_eq(d, &subscriber.lagged)));
+        }
+
+        receivers
+    }
+
+    /// Ends every subscription
+    pub(crate) fn close(&self) {
+        self.subscribers.lock().unwrap().clear();
+    }
+}
+
+/// Whether the text matches the pattern, in which `*` matches any run of
+/// characters and `?` any single character. Takes time proportional to the
+/// product of their lengths at worst.
+pub(crate) fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // the last `*` seen and the text position it is tried from; an earlier
+    // `*` never needs to be retried, the last one can absorb what it would
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // let the `*` absorb one more character and try again
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
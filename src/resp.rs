@@ -0,0 +1,717 @@
+//! A front end speaking the Redis serialization protocol, RESP2 and RESP3
+//!
+//! Commands arrive as arrays of bulk strings, or as inline commands typed
+//! into a terminal, and are mapped onto a `KvsEngine`. Only the commands of
+//! simple string workloads are supported.
+//!
+//! The engines have no notion of expiry, so the deadlines set by `EXPIRE`
+//! are kept in memory by the front end. A key past its deadline is removed
+//! when a RESP command next touches it. Writing a key through another
+//! protocol clears its deadline, as writing it with `SET` does. Deadlines are
+//! lost on restart and do not hide keys from the other protocols.
+use crate::errors::{KvError, Result};
+use crate::changes::ChangeStream;
+use crate::kv_engine::{KvsEngine, Update, ValueType};
+use crate::pubsub::glob_match;
+use crate::server::offload;
+use crate::thread_pool::ThreadPool;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Longest bulk string accepted
+const MAX_BULK_LEN: usize = 16 * 1024 * 1024;
+
+/// Longest line accepted, which bounds inline commands
+const MAX_LINE_LEN: u64 = 64 * 1024;
+
+/// Most arguments accepted in one command
+const MAX_ARGS: usize = 4096;
+
+/// Scans that may be left unfinished before the oldest is forgotten
+const MAX_CURSORS: usize = 64 * 1024;
+
+/// Keys returned by one `SCAN` when no `COUNT` is given
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Expiry deadlines of keys, shared by every RESP connection
+#[derive(Clone, Default)]
+pub(crate) struct Expiries {
+    deadlines: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl Expiries {
+    /// Removes the key from the engine if its deadline has passed, returning
+    /// whether it did. The engine is called without holding the deadlines, so
+    /// that other keys are not held up by the removal.
+    fn expire<E: KvsEngine>(&self, engine: &E, key: &str) -> Result<bool> {
+        let deadline = match self.deadlines.lock().unwrap().get(key) {
+            Some(&deadline) if deadline <= Instant::now() => deadline,
+            _ => return Ok(false),
+        };
+        match engine.remove(key.to_owned()) {
+            Ok(()) | Err(KvError::KeyNotFound) => {}
+            Err(e) => return Err(e),
+        }
+        // a deadline set in the meantime belongs to a new value
+        let mut deadlines = self.deadlines.lock().unwrap();
+        if deadlines.get(key) == Some(&deadline) {
+            deadlines.remove(key);
+        }
+
+        Ok(true)
+    }
+
+    fn set(&self, key: &str, deadline: Instant) {
+        self.deadlines.lock().unwrap().insert(key.to_owned(), deadline);
+    }
+
+    fn clear(&self, key: &str) -> bool {
+        self.deadlines.lock().unwrap().remove(key).is_some()
+    }
+
+    fn remaining(&self, key: &str) -> Option<Duration> {
+        let deadlines = self.deadlines.lock().unwrap();
+        deadlines.get(key).map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+/// Where each unfinished `SCAN` left off, by cursor. Shared by every RESP
+/// connection, as a client may carry on a scan over another connection.
+#[derive(Clone, Default)]
+pub(crate) struct Cursors {
+    state: Arc<Mutex<CursorState>>,
+}
+
+#[derive(Default)]
+struct CursorState {
+    last_keys: BTreeMap<u64, String>,
+    next_cursor: u64,
+}
+
+impl Cursors {
+    /// Returns the last key the scan returned, unless the cursor is unknown.
+    /// A cursor may be used again until it is the oldest and forgotten.
+    fn last_key(&self, cursor: u64) -> Option<String> {
+        self.state.lock().unwrap().last_keys.get(&cursor).cloned()
+    }
+
+    /// Remembers where a scan left off, returning its new cursor, which is never 0
+    fn save(&self, last_key: String) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.next_cursor += 1;
+        let cursor = state.next_cursor;
+        state.last_keys.insert(cursor, last_key);
+        if state.last_keys.len() > MAX_CURSORS {
+            let oldest = *state.last_keys.keys().next().unwrap();
+            state.last_keys.remove(&oldest);
+        }
+
+        cursor
+    }
+}
+
+/// The engine as the other front ends use it: every key written through it
+/// loses its deadline, so that it does not expire on a deadline set for its
+/// old value. Keyspaces cannot be reached over RESP and have no deadlines.
+#[derive(Clone)]
+pub(crate) struct Expiring<E: KvsEngine> {
+    engine: E,
+    expiries: Option<Expiries>,
+}
+
+impl<E: KvsEngine> Expiring<E> {
+    /// Wraps the engine, clearing the given deadlines if there are any
+    pub(crate) fn new(engine: E, expiries: Option<Expiries>) -> Self {
+        Expiring { engine, expiries }
+    }
+
+    /// Clears the deadline of a key that has been written
+    fn forget(&self, key: &str) {
+        if let Some(expiries) = &self.expiries {
+            expiries.clear(key);
+        }
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Expiring<E> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine.get(key)
+    }
+
+    fn get_typed(&self, key: String, value_type: ValueType) -> Result<Option<String>> {
+        self.engine.get_typed(key, value_type)
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.engine.set(key.clone(), value)?;
+        self.forget(&key);
+        Ok(())
+    }
+
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let keys: Vec<String> = pairs.iter().map(|(key, _)| key.clone()).collect();
+        self.engine.set_batch(pairs)?;
+        keys.iter().for_each(|key| self.forget(key));
+        Ok(())
+    }
+
+    fn update_typed<F>(&self, key: String, value_type: ValueType, f: F) -> Result<()>
+    where
+        F: FnMut(Option<&str>) -> Result<Update>,
+    {
+        self.engine.update_typed(key.clone(), value_type, f)?;
+        self.forget(&key);
+        Ok(())
+    }
+
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        let value = self.engine.incr_by(key.clone(), delta)?;
+        self.forget(&key);
+        Ok(value)
+    }
+
+    fn append(&self, key: String, value: String) -> Result<()> {
+        self.engine.append(key.clone(), value)?;
+        self.forget(&key);
+        Ok(())
+    }
+
+    fn get_range(&self, key: String, offset: usize, len: usize) -> Result<Option<String>> {
+        self.engine.get_range(key, offset, len)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.engine.remove(key.clone())?;
+        self.forget(&key);
+        Ok(())
+    }
+
+    fn scan(&self, start: Bound<String>, end: Bound<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.engine.scan(start, end, limit)
+    }
+
+    fn scan_typed(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, ValueType, String)>> {
+        self.engine.scan_typed(start, end, limit)
+    }
+
+    fn create_index(&self, name: &str, path: &str) -> Result<()> {
+        self.engine.create_index(name, path)
+    }
+
+    fn drop_index(&self, name: &str) -> Result<()> {
+        self.engine.drop_index(name)
+    }
+
+    fn query_index(&self, name: &str, value: &str) -> Result<Vec<String>> {
+        self.engine.query_index(name, value)
+    }
+
+    fn subscribe(&self, from: u64) -> Result<ChangeStream> {
+        self.engine.subscribe(from)
+    }
+
+    fn last_sequence(&self) -> Result<u64> {
+        self.engine.last_sequence()
+    }
+
+    fn backup(&self, dir: &Path) -> Result<()> {
+        self.engine.backup(dir)
+    }
+
+    fn backup_incremental(&self, previous: &Path, dir: &Path) -> Result<()> {
+        self.engine.backup_incremental(previous, dir)
+    }
+
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        let engine = self.engine.keyspace(name)?;
+        Ok(Expiring { engine, expiries: None })
+    }
+
+    fn existing_keyspace(&self, name: &str) -> Result<Self> {
+        let engine = self.engine.existing_keyspace(name)?;
+        Ok(Expiring { engine, expiries: None })
+    }
+
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        self.engine.keyspaces()
+    }
+
+    fn drop_keyspace(&self, name: &str) -> Result<()> {
+        self.engine.drop_keyspace(name)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn close(&self) -> Result<()> {
+        self.engine.close()
+    }
+}
+
+/// A reply, encoded according to the protocol version of the connection
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Null,
+    Array(Vec<Reply>),
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Reply {
+    fn encode(&self, resp3: bool, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Reply::Error(e) => out.extend_from_slice(format!("-{}\r\n", e).as_bytes()),
+            Reply::Integer(i) => out.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+            Reply::Bulk(s) => {
+                out.extend_from_slice(format!("${}\r\n", s.len()).as_bytes());
+                out.extend_from_slice(s.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Null if resp3 => out.extend_from_slice(b"_\r\n"),
+            Reply::Null => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(resp3, out);
+                }
+            }
+            Reply::Map(entries) => {
+                // RESP2 has no maps, so they are sent as flat arrays
+                let header = if resp3 {
+                    format!("%{}\r\n", entries.len())
+                } else {
+                    format!("*{}\r\n", entries.len() * 2)
+                };
+                out.extend_from_slice(header.as_bytes());
+                for (key, value) in entries {
+                    key.encode(resp3, out);
+                    value.encode(resp3, out);
+                }
+            }
+        }
+    }
+}
+
+impl From<KvError> for Reply {
+    fn from(err: KvError) -> Reply {
+        match err {
+            KvError::NotAnInteger | KvError::IntegerOverflow => {
+                Reply::Error("ERR value is not an integer or out of range".to_owned())
+            }
+            KvError::WrongType => {
+                Reply::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_owned())
+            }
+            err => Reply::Error(format!("ERR {}", err)),
+        }
+    }
+}
+
+/// Reads the next command, or `None` once the client has gone away
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<String>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if !line.starts_with('*') {
+        // an inline command, as typed into telnet
+        return Ok(Some(line.split_whitespace().map(str::to_owned).collect()));
+    }
+
+    // the lengths come from the client, so buffers grow as the bytes arrive
+    // rather than being allocated up front
+    let count = parse_len(&line[1..], MAX_ARGS)?;
+    let mut args = Vec::new();
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or(KvError::MalformedRequest)?;
+        if !header.starts_with('$') {
+            return Err(KvError::MalformedRequest);
+        }
+        let len = parse_len(&header[1..], MAX_BULK_LEN)?;
+        let mut arg = Vec::new();
+        reader.by_ref().take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 || !arg.ends_with(b"\r\n") {
+            return Err(KvError::MalformedRequest);
+        }
+        arg.truncate(len);
+        args.push(String::from_utf8(arg).map_err(|_| KvError::MalformedRequest)?);
+    }
+
+    Ok(Some(args))
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = String::new();
+    if reader.by_ref().take(MAX_LINE_LEN).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(KvError::MalformedRequest);
+    }
+    let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+    line.truncate(len);
+
+    Ok(Some(line))
+}
+
+fn parse_len(s: &str, max: usize) -> Result<usize> {
+    match s.parse::<usize>() {
+        Ok(len) if len <= max => Ok(len),
+        _ => Err(KvError::MalformedRequest),
+    }
+}
+
+/// The state of one RESP connection
+struct Session<E: KvsEngine> {
+    engine: E,
+    expiries: Expiries,
+    resp3: bool,
+    cursors: Cursors,
+}
+
+/// Serves the commands of one connection until the client quits, goes away
+/// or sends something that is not RESP. Each command is run on the pool.
+pub(crate) fn handle_client<E, P>(
+    engine: E,
+    expiries: Expiries,
+    cursors: Cursors,
+    pool: Arc<Mutex<P>>,
+    stream: TcpStream,
+) -> Result<()>
+where
+    E: KvsEngine,
+    P: ThreadPool,
+{
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    let mut session = Session {
+        engine,
+        expiries,
+        resp3: false,
+        cursors,
+    };
+
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) | Err(KvError::Io(_)) => return Ok(()),
+            Err(_) => {
+                writer.write_all(b"-ERR Protocol error\r\n")?;
+                writer.flush()?;
+                return Ok(());
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+
+        let quit = args[0].eq_ignore_ascii_case("quit");
+        let reply = if quit {
+            Reply::Simple("OK")
+        } else {
+            // the session goes along with the command and comes back with its reply
+            let (returned, reply) = offload(&pool, move || {
+                let reply = session.execute(args).unwrap_or_else(Reply::from);
+                (session, reply)
+            })?;
+            session = returned;
+            reply
+        };
+        let mut out = Vec::new();
+        reply.encode(session.resp3, &mut out);
+        writer.write_all(&out)?;
+        writer.flush()?;
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+impl<E: KvsEngine> Session<E> {
+    fn execute(&mut self, args: Vec<String>) -> Result<Reply> {
+        let name = args[0].to_ascii_uppercase();
+        let mut args = args.into_iter().skip(1);
+        let arity_error = || Reply::Error(format!("ERR wrong number of arguments for '{}' command", name.to_lowercase()));
+        let reply = match (name.as_str(), args.len()) {
+            ("PING", 0) => Reply::Simple("PONG"),
+            ("PING", 1) | ("ECHO", 1) => Reply::Bulk(args.next().unwrap()),
+            ("HELLO", _) => self.hello(args.next())?,
+            // clients ask for command documentation on connecting
+            ("COMMAND", _) => Reply::Array(Vec::new()),
+            ("GET", 1) => {
+                let key = args.next().unwrap();
+                self.live(&key)?;
+                self.engine.get(key)?.map_or(Reply::Null, Reply::Bulk)
+            }
+            ("SET", n) if n >= 2 => {
+                let key = args.next().unwrap();
+                let value = args.next().unwrap();
+                self.set(key, value, args.collect())?
+            }
+            ("DEL", n) if n >= 1 => {
+                let mut removed = 0;
+                for key in args {
+                    self.live(&key)?;
+                    self.expiries.clear(&key);
+                    match self.engine.remove(key) {
+                        Ok(()) => removed += 1,
+                        Err(KvError::KeyNotFound) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Reply::Integer(removed)
+            }
+            ("EXISTS", n) if n >= 1 => {
+                let mut found = 0;
+                for key in args {
+                    found += self.exists(key)? as i64;
+                }
+                Reply::Integer(found)
+            }
+            ("SCAN", n) if n >= 1 => self.scan(args.collect())?,
+            ("INCR", 1) => Reply::Integer(self.incr_by(args.next().unwrap(), 1)?),
+            ("DECR", 1) => Reply::Integer(self.incr_by(args.next().unwrap(), -1)?),
+            ("INCRBY", 2) | ("DECRBY", 2) => {
+                let key = args.next().unwrap();
+                let delta = parse_integer(&args.next().unwrap())?;
+                let delta = if name == "DECRBY" {
+                    delta.checked_neg().ok_or(KvError::IntegerOverflow)?
+                } else {
+                    delta
+                };
+                Reply::Integer(self.incr_by(key, delta)?)
+            }
+            ("APPEND", 2) => {
+                let key = args.next().unwrap();
+                let value = args.next().unwrap();
+                self.live(&key)?;
+                self.engine.append(key.clone(), value)?;
+                let len = self.engine.get(key)?.map_or(0, |v| v.len());
+                Reply::Integer(len as i64)
+            }
+            ("EXPIRE", 2) | ("PEXPIRE", 2) => {
+                let key = args.next().unwrap();
+                let amount = parse_integer(&args.next().unwrap())?;
+                if !self.exists(key.clone())? {
+                    return Ok(Reply::Integer(0));
+                }
+                if amount <= 0 {
+                    self.expiries.clear(&key);
+                    self.engine.remove(key)?;
+                    return Ok(Reply::Integer(1));
+                }
+                let ttl = if name == "EXPIRE" {
+                    Duration::from_secs(amount as u64)
+                } else {
+                    Duration::from_millis(amount as u64)
+                };
+                match Instant::now().checked_add(ttl) {
+                    Some(deadline) => self.expiries.set(&key, deadline),
+                    None => {
+                        let command = name.to_lowercase();
+                        return Ok(Reply::Error(format!("ERR invalid expire time in '{}' command", command)));
+                    }
+                }
+                Reply::Integer(1)
+            }
+            ("TTL", 1) | ("PTTL", 1) => {
+                let key = args.next().unwrap();
+                if !self.exists(key.clone())? {
+                    return Ok(Reply::Integer(-2));
+                }
+                match self.expiries.remaining(&key) {
+                    Some(left) if name == "TTL" => Reply::Integer(left.as_secs() as i64),
+                    Some(left) => Reply::Integer(left.as_millis() as i64),
+                    None => Reply::Integer(-1),
+                }
+            }
+            ("PERSIST", 1) => {
+                let key = args.next().unwrap();
+                self.live(&key)?;
+                Reply::Integer(self.expiries.clear(&key) as i64)
+            }
+            ("PING", _) | ("ECHO", _) | ("GET", _) | ("SET", _) | ("DEL", _) | ("EXISTS", _) | ("SCAN", _)
+            | ("INCR", _) | ("DECR", _) | ("INCRBY", _) | ("DECRBY", _) | ("APPEND", _) | ("EXPIRE", _)
+            | ("PEXPIRE", _) | ("TTL", _) | ("PTTL", _) | ("PERSIST", _) => arity_error(),
+            _ => Reply::Error(format!("ERR unknown command '{}'", name.to_lowercase())),
+        };
+
+        Ok(reply)
+    }
+
+    /// Expires the key if it is due, returning whether it may still exist
+    fn live(&self, key: &str) -> Result<bool> {
+        self.expiries.expire(&self.engine, key).map(|expired| !expired)
+    }
+
+    /// Returns whether the key exists once expired if due, whatever the type of its value
+    fn exists(&self, key: String) -> Result<bool> {
+        if !self.live(&key)? {
+            return Ok(false);
+        }
+        match self.engine.get(key) {
+            Ok(value) => Ok(value.is_some()),
+            // the key holds a hash, list or set
+            Err(KvError::WrongType) => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn hello(&mut self, version: Option<String>) -> Result<Reply> {
+        match version.as_deref() {
+            None => {}
+            Some("2") => self.resp3 = false,
+            Some("3") => self.resp3 = true,
+            Some(_) => return Ok(Reply::Error("NOPROTO unsupported protocol version".to_owned())),
+        }
+        let field = |name: &'static str| Reply::Bulk(name.to_owned());
+
+        Ok(Reply::Map(vec![
+            (field("server"), field("kvs")),
+            (field("version"), field(env!("CARGO_PKG_VERSION"))),
+            (field("proto"), Reply::Integer(if self.resp3 { 3 } else { 2 })),
+            (field("mode"), field("standalone")),
+            (field("role"), field("master")),
+            (field("modules"), Reply::Array(Vec::new())),
+        ]))
+    }
+
+    /// `SET key value [EX seconds | PX milliseconds] [NX | XX]`
+    fn set(&mut self, key: String, value: String, options: Vec<String>) -> Result<Reply> {
+        let mut deadline = None;
+        let mut only_if = None;
+        let mut options = options.into_iter();
+        while let Some(option) = options.next() {
+            match option.to_ascii_uppercase().as_str() {
+                "NX" | "XX" if only_if.is_none() => only_if = Some(option.eq_ignore_ascii_case("XX")),
+                unit @ "EX" | unit @ "PX" if deadline.is_none() => {
+                    let amount = match options.next().map(|a| parse_integer(&a)) {
+                        Some(Ok(amount)) if amount > 0 => amount as u64,
+                        _ => return Ok(Reply::Error("ERR invalid expire time in 'set' command".to_owned())),
+                    };
+                    let ttl = if unit == "EX" {
+                        Duration::from_secs(amount)
+                    } else {
+                        Duration::from_millis(amount)
+                    };
+                    deadline = match Instant::now().checked_add(ttl) {
+                        Some(deadline) => Some(deadline),
+                        None => return Ok(Reply::Error("ERR invalid expire time in 'set' command".to_owned())),
+                    };
+                }
+                _ => return Ok(Reply::Error("ERR syntax error".to_owned())),
+            }
+        }
+
+        self.live(&key)?;
+        let written = match only_if {
+            None => {
+                self.expiries.clear(&key);
+                self.engine.set(key.clone(), value)?;
+                true
+            }
+            Some(must_exist) => {
+                let mut written = false;
+                let updated = self.engine.update(key.clone(), |current| {
+                    written = current.is_some() == must_exist;
+                    Ok(if written { Update::Set(value.clone()) } else { Update::Unchanged })
+                });
+                match updated {
+                    Ok(()) => {}
+                    // the key holds a hash, list or set, which SET replaces as it would a string
+                    Err(KvError::WrongType) => {
+                        written = must_exist;
+                        if written {
+                            self.engine.set(key.clone(), value)?;
+                        }
+                    }
+                    Err(e) => return Err(e),
+                }
+                if written {
+                    self.expiries.clear(&key);
+                }
+                written
+            }
+        };
+        if !written {
+            return Ok(Reply::Null);
+        }
+        if let Some(deadline) = deadline {
+            self.expiries.set(&key, deadline);
+        }
+
+        Ok(Reply::Simple("OK"))
+    }
+
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        self.live(&key)?;
+        self.engine.incr_by(key, delta)
+    }
+
+    /// `SCAN cursor [MATCH pattern] [COUNT count]`. A cursor stands for the
+    /// last key a scan returned, so keys present for the whole scan are
+    /// returned exactly once.
+    fn scan(&mut self, args: Vec<String>) -> Result<Reply> {
+        let mut args = args.into_iter();
+        let cursor = match args.next().map(|c| c.parse::<u64>()) {
+            Some(Ok(cursor)) => cursor,
+            _ => return Ok(Reply::Error("ERR invalid cursor".to_owned())),
+        };
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        while let Some(option) = args.next() {
+            match (option.to_ascii_uppercase().as_str(), args.next()) {
+                ("MATCH", Some(p)) => pattern = Some(p),
+                ("COUNT", Some(n)) => match n.parse::<usize>() {
+                    Ok(n) if n > 0 => count = n,
+                    _ => return Ok(Reply::Error("ERR value is not an integer or out of range".to_owned())),
+                },
+                _ => return Ok(Reply::Error("ERR syntax error".to_owned())),
+            }
+        }
+
+        let start = match cursor {
+            0 => Bound::Unbounded,
+            cursor => match self.cursors.last_key(cursor) {
+                Some(last) => Bound::Excluded(last),
+                None => return Ok(Reply::Error("ERR invalid cursor".to_owned())),
+            },
+        };
+        // every key is listed, whatever the type of its value
+        let entries = self.engine.scan_typed(start, Bound::Unbounded, count)?;
+        let next = match entries.last() {
+            Some((last, _, _)) if entries.len() == count => self.cursors.save(last.clone()),
+            _ => 0,
+        };
+
+        let mut keys = Vec::new();
+        for (key, _, _) in entries {
+            let matched = match &pattern {
+                Some(pattern) => glob_match(pattern.as_bytes(), key.as_bytes()),
+                None => true,
+            };
+            if matched && self.live(&key)? {
+                keys.push(Reply::Bulk(key));
+            }
+        }
+
+        Ok(Reply::Array(vec![Reply::Bulk(next.to_string()), Reply::Array(keys)]))
+    }
+}
+
+fn parse_integer(s: &str) -> Result<i64> {
+    s.parse::<i64>().map_err(|_| KvError::NotAnInteger)
+}
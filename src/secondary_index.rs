@@ -0,0 +1,187 @@
+//! Secondary indexes on fields of JSON values
+use crate::errors::{KvError, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::path::Path;
+
+/// File in a store directory holding the index definitions
+pub(crate) const INDEX_FILE: &str = "indexes";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Definition {
+    name: String,
+    path: String,
+}
+
+/// The secondary indexes of a store, keyed by name
+#[derive(Debug, Default)]
+pub(crate) struct SecondaryIndexes {
+    indexes: BTreeMap<String, SecondaryIndex>,
+}
+
+#[derive(Debug)]
+struct SecondaryIndex {
+    /// The path as declared, such as `$.address.city`
+    path: String,
+    /// The path split into object fields
+    fields: Vec<String>,
+    /// Keys by the value found at the path
+    by_value: BTreeMap<String, BTreeSet<String>>,
+    /// The value found at the path by key, to unindex a key without reading its old value
+    by_key: HashMap<String, String>,
+}
+
+impl SecondaryIndex {
+    fn new(path: &str) -> Result<Self> {
+        Ok(SecondaryIndex {
+            path: path.to_owned(),
+            fields: parse_path(path)?,
+            by_value: BTreeMap::new(),
+            by_key: HashMap::new(),
+        })
+    }
+
+    fn insert(&mut self, key: &str, json: Option<&Value>) {
+        self.remove(key);
+        if let Some(indexed) = json.and_then(|json| extract(json, &self.fields)) {
+            self.by_value.entry(indexed.clone()).or_default().insert(key.to_owned());
+            self.by_key.insert(key.to_owned(), indexed);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(indexed) = self.by_key.remove(key) {
+            if let Some(keys) = self.by_value.get_mut(&indexed) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.by_value.remove(&indexed);
+                }
+            }
+        }
+    }
+}
+
+impl SecondaryIndexes {
+    /// Reads the index definitions of the store at `dir`. The indexes are
+    /// empty until values are inserted.
+    pub(crate) fn load(dir: &Path) -> Result<Self> {
+        let mut secondary = SecondaryIndexes::default();
+        let path = dir.join(INDEX_FILE);
+        if !path.exists() {
+            return Ok(secondary);
+        }
+        let definitions: Vec<Definition> = serde_json::from_reader(File::open(path)?)?;
+        for definition in definitions {
+            let index = SecondaryIndex::new(&definition.path)?;
+            secondary.indexes.insert(definition.name, index);
+        }
+
+        Ok(secondary)
+    }
+
+    /// Writes the index definitions into the store at `dir`
+    pub(crate) fn save(&self, dir: &Path) -> Result<()> {
+        let definitions: Vec<Definition> = self
+            .indexes
+            .iter()
+            .map(|(name, index)| Definition { name: name.clone(), path: index.path.clone() })
+            .collect();
+        let tmp_path = dir.join(format!("{}.tmp", INDEX_FILE));
+        let tmp = File::create(&tmp_path)?;
+        serde_json::to_writer(&tmp, &definitions)?;
+        tmp.sync_all()?;
+        std::fs::rename(&tmp_path, dir.join(INDEX_FILE))?;
+
+        Ok(())
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
+    }
+
+    /// Declares a new, empty index
+    pub(crate) fn create(&mut self, name: &str, path: &str) -> Result<()> {
+        if name.is_empty() || self.indexes.contains_key(name) {
+            return Err(KvError::InvalidIndex(name.to_owned()));
+        }
+        self.indexes.insert(name.to_owned(), SecondaryIndex::new(path)?);
+
+        Ok(())
+    }
+
+    pub(crate) fn drop_index(&mut self, name: &str) -> Result<()> {
+        self.indexes
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| KvError::InvalidIndex(name.to_owned()))
+    }
+
+    /// Empties every index, keeping the definitions
+    pub(crate) fn clear(&mut self) {
+        for index in self.indexes.values_mut() {
+            index.by_value.clear();
+            index.by_key.clear();
+        }
+    }
+
+    /// Indexes the new value of a key. Values that are not JSON, or lack the
+    /// indexed field, are left out of the index.
+    pub(crate) fn insert(&mut self, key: &str, value: &str) {
+        if self.indexes.is_empty() {
+            return;
+        }
+        let json = serde_json::from_str::<Value>(value).ok();
+        for index in self.indexes.values_mut() {
+            index.insert(key, json.as_ref());
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) {
+        for index in self.indexes.values_mut() {
+            index.remove(key);
+        }
+    }
+
+    /// Returns the keys whose indexed field equals `value`, in key order
+    pub(crate) fn query(&self, name: &str, value: &str) -> Result<Vec<String>> {
+        let index = self
+            .indexes
+            .get(name)
+            .ok_or_else(|| KvError::InvalidIndex(name.to_owned()))?;
+
+        Ok(index
+            .by_value
+            .get(value)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+}
+
+/// Parses a path of the form `$.field.subfield`
+fn parse_path(path: &str) -> Result<Vec<String>> {
+    let fields: Vec<String> = match path.strip_prefix("$.") {
+        Some(rest) => rest.split('.').map(str::to_owned).collect(),
+        None => return Err(KvError::InvalidIndex(path.to_owned())),
+    };
+    if fields.iter().any(String::is_empty) {
+        return Err(KvError::InvalidIndex(path.to_owned()));
+    }
+
+    Ok(fields)
+}
+
+/// Finds the field at the path. Strings are indexed as they are, other
+/// scalars by their JSON text, and objects and arrays not at all.
+fn extract(json: &Value, fields: &[String]) -> Option<String> {
+    let mut json = json;
+    for field in fields {
+        json = json.get(field)?;
+    }
+    match json {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(_) | Value::Bool(_) => Some(json.to_string()),
+        _ => None,
+    }
+}
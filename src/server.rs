@@ -1,80 +1,859 @@
 //! Kv Server
-use crate::errors::{Result};
+use crate::binary_protocol::{decode_request, encode_response, read_frame, server_handshake, write_frame, MAGIC};
+use crate::changes::ChangeStream;
+use crate::collections::Collections;
+use crate::errors::{KvError, Result};
+use crate::grpc;
 use crate::kv_engine::KvsEngine;
+use crate::http_gateway;
+use crate::pubsub::{Broker, Subscription};
+use crate::resp::{self, Cursors, Expiries};
 use crate::thread_pool::ThreadPool;
-use crate::{KvRequest, KvResponse};
+use crate::kv_protocol::encode_json;
+use crate::{ErrorCode, KvRequest, KvResponse, ServerError, TaggedRequest, Watched, JSON_PROTOCOL_VERSION};
+use serde::{Deserialize, Serialize};
 use slog::Logger;
-use std::error::Error;
-use std::io::{BufReader, BufWriter};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
 use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a connection may go without sending a request before it is closed
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Connections served at once over all front ends, unless set otherwise
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+/// Unordered requests of one connection that may be in flight at once
+const MAX_IN_FLIGHT: usize = 64;
+
+/// How long a streaming request waits for something to write before checking
+/// that its client is still there
+pub(crate) const STREAM_TICK: Duration = Duration::from_secs(1);
+
+/// How long checking on a client waits for it to have sent something
+const PEEK_TIMEOUT: Duration = Duration::from_millis(1);
 
 /// The Kv Server
 pub struct KvServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
-    pool: P,
+    pool: Arc<Mutex<P>>,
     logger: Logger,
+    shutdown: ShutdownHandle,
+    broker: Broker,
+    idle_timeout: Duration,
+    connections: Connections,
+    resp_addr: Option<SocketAddr>,
+    http_addr: Option<SocketAddr>,
+    grpc_addr: Option<SocketAddr>,
+    backup_dir: Option<PathBuf>,
+    expiries: Expiries,
+}
+
+type Waker = Box<dyn Fn() + Send>;
+
+/// Handle used to stop a running `KvServer` from another thread
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    wakers: Arc<Mutex<Vec<Waker>>>,
+}
+
+impl ShutdownHandle {
+    /// Ask the server to stop accepting connections and close its engine
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        // wake up the listeners so that they notice the request
+        for wake in self.wakers.lock().unwrap().iter() {
+            wake();
+        }
+    }
+
+    pub(crate) fn on_shutdown<F: Fn() + Send + 'static>(&self, wake: F) {
+        self.wakers.lock().unwrap().push(Box::new(wake));
+    }
+
+    fn listening_on(&self, addr: SocketAddr) {
+        self.on_shutdown(move || {
+            let _ = TcpStream::connect(addr);
+        });
+    }
+
+    pub(crate) fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
 }
 
-impl<E: KvsEngine, P: ThreadPool> KvServer<E, P> {
+impl<E: KvsEngine, P: ThreadPool + Send + 'static> KvServer<E, P> {
     /// Create new server
     pub fn new(engine: E, pool: P, logger: Logger) -> Self {
         KvServer {
             engine,
-            pool,
+            pool: Arc::new(Mutex::new(pool)),
             logger,
+            shutdown: ShutdownHandle::default(),
+            broker: Broker::default(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            connections: Connections::new(DEFAULT_MAX_CONNECTIONS),
+            resp_addr: None,
+            http_addr: None,
+            grpc_addr: None,
+            backup_dir: None,
+            expiries: Expiries::default(),
         }
     }
 
-    /// Start the server and listen on given address
+    /// Also serves Redis clients, speaking RESP on the given address
+    pub fn set_resp_addr<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
+        let addr = addr.to_socket_addrs()?.next().ok_or(KvError::MalformedRequest)?;
+        self.resp_addr = Some(addr);
+
+        Ok(())
+    }
+
+    /// Also serves the HTTP/JSON gateway on the given address
+    pub fn set_http_addr<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
+        let addr = addr.to_socket_addrs()?.next().ok_or(KvError::MalformedRequest)?;
+        self.http_addr = Some(addr);
+
+        Ok(())
+    }
+
+    /// Also serves the gRPC service of `proto/kvs.proto` on the given address
+    pub fn set_grpc_addr<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
+        let addr = addr.to_socket_addrs()?.next().ok_or(KvError::MalformedRequest)?;
+        self.grpc_addr = Some(addr);
+
+        Ok(())
+    }
+
+    /// Sets how long a connection may stay idle between requests before the
+    /// server closes it. Streaming connections are not subject to it.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Sets how many connections the JSON, binary and RESP front ends, and
+    /// requests the HTTP gateway, may serve at once in total. Each takes a
+    /// thread, so further connections are closed straight away and further
+    /// HTTP requests answered with 503 until one ends.
+    pub fn set_max_connections(&mut self, max: usize) {
+        self.connections = Connections::new(max);
+    }
+
+    /// Lets clients request backups, each written into the directory under
+    /// `dir` that the request names. Without it backup requests are refused.
+    pub fn set_backup_dir<D: Into<PathBuf>>(&mut self, dir: D) {
+        self.backup_dir = Some(dir.into());
+    }
+
+    /// Returns a handle that can be used to stop the server once it is running
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Start the server and listen on given address. Returns after a shutdown
+    /// has been requested, once the engine has been flushed and closed.
     pub fn run<A: Display +  ToSocketAddrs>(self, addr: A) -> Result<()> {
         debug!(&self.logger, "Listening on {}", &addr);
         let listener = TcpListener::bind(addr)?;
-        for client in listener.incoming() {
-            match client {
-                Ok(stream) => {
-                    let engine = self.engine.clone();
-                    self.pool.spawn(move || {
-                        let _ = handle_client(engine, stream.try_clone().unwrap());
-                    });
-                }
-                Err(_) => {
-                    println!("Failed to process stream");
+        self.shutdown.listening_on(listener.local_addr()?);
+
+        let resp = match self.resp_addr {
+            Some(resp_addr) => {
+                debug!(&self.logger, "Listening for RESP on {}", resp_addr);
+                let listener = TcpListener::bind(resp_addr)?;
+                self.shutdown.listening_on(listener.local_addr()?);
+                let (engine, expiries, pool) = (self.engine.clone(), self.expiries.clone(), self.pool.clone());
+                let (shutdown, logger, idle_timeout) = (self.shutdown.clone(), self.logger.clone(), self.idle_timeout);
+                let connections = self.connections.clone();
+                let cursors = Cursors::default();
+                Some(thread::spawn(move || {
+                    accept(listener, &shutdown, &logger, idle_timeout, &connections, |stream, slot| {
+                        let (engine, expiries, pool) = (engine.clone(), expiries.clone(), pool.clone());
+                        let cursors = cursors.clone();
+                        thread::spawn(move || {
+                            let _slot = slot;
+                            resp::handle_client(engine, expiries, cursors, pool, stream)
+                        });
+                    })
+                }))
+            }
+            None => None,
+        };
+
+        // keys written through the other front ends lose their RESP deadlines
+        let expiries = self.resp_addr.map(|_| self.expiries.clone());
+        let engine = resp::Expiring::new(self.engine.clone(), expiries);
+
+        let http = match self.http_addr {
+            Some(http_addr) => {
+                debug!(&self.logger, "Listening for HTTP on {}", http_addr);
+                let server = tiny_http::Server::http(http_addr).map_err(|e| KvError::Io(io::Error::other(e)))?;
+                let server = Arc::new(server);
+                let unblock = server.clone();
+                self.shutdown.on_shutdown(move || unblock.unblock());
+                let (engine, pool, connections) = (engine.clone(), self.pool.clone(), self.connections.clone());
+                let logger = self.logger.clone();
+                Some(thread::spawn(move || {
+                    for request in server.incoming_requests() {
+                        let slot = match connections.admit() {
+                            Some(slot) => slot,
+                            None => {
+                                warn!(logger, "Too many connections, refusing HTTP request");
+                                let _ = request.respond(tiny_http::Response::empty(503));
+                                continue;
+                            }
+                        };
+                        let (engine, pool) = (engine.clone(), pool.clone());
+                        thread::spawn(move || {
+                            let _slot = slot;
+                            http_gateway::handle_request(engine, pool, request)
+                        });
+                    }
+                }))
+            }
+            None => None,
+        };
+
+        let grpc = match self.grpc_addr {
+            Some(grpc_addr) => {
+                debug!(&self.logger, "Listening for gRPC on {}", grpc_addr);
+                let listener = TcpListener::bind(grpc_addr)?;
+                let stopped = Arc::new(tokio::sync::Notify::new());
+                let stop = stopped.clone();
+                self.shutdown.on_shutdown(move || stop.notify_one());
+                let (engine, pool, logger) = (engine.clone(), self.pool.clone(), self.logger.clone());
+                Some(thread::spawn(move || {
+                    if let Err(e) = grpc::serve(engine, pool, listener, async move { stopped.notified().await }) {
+                        error!(logger, "gRPC server failed: {}", e);
+                    }
+                }))
+            }
+            None => None,
+        };
+
+        // a connection spends most of its time waiting for the client, so it
+        // gets a thread of its own and only its engine calls go to the pool
+        accept(listener, &self.shutdown, &self.logger, self.idle_timeout, &self.connections, |stream, slot| {
+            let (engine, broker, pool) = (engine.clone(), self.broker.clone(), self.pool.clone());
+            let backup_dir = self.backup_dir.clone();
+            thread::spawn(move || {
+                let _slot = slot;
+                handle_client(engine, broker, backup_dir, pool, stream)
+            });
+        });
+        for listener in resp.into_iter().chain(http).chain(grpc) {
+            let _ = listener.join();
+        }
+
+        info!(&self.logger, "Shutting down, closing engine");
+        self.broker.close();
+        self.engine.close()
+    }
+}
+
+/// Runs `f` on the pool and waits for its result
+pub(crate) fn offload<P, T, F>(pool: &Mutex<P>, f: F) -> Result<T>
+where
+    P: ThreadPool,
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    pool.lock().unwrap().spawn(move || {
+        let _ = sender.send(f());
+    });
+    // the sender is dropped without sending if `f` panics
+    receiver.recv().map_err(|_| KvError::InternalError)
+}
+
+/// Counts the connections being served, shared by the front ends
+#[derive(Clone)]
+struct Connections {
+    open: Arc<AtomicUsize>,
+    max: usize,
+}
+
+/// A connection being served, counted until it is dropped
+struct Slot(Arc<AtomicUsize>);
+
+impl Connections {
+    fn new(max: usize) -> Self {
+        Connections {
+            open: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Counts one more connection, unless the maximum are already served
+    fn admit(&self) -> Option<Slot> {
+        self.open
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| if open < self.max { Some(open + 1) } else { None })
+            .ok()
+            .map(|_| Slot(self.open.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Hands each connection to `serve`, along with the slot it holds while it
+/// is served, until a shutdown is requested. Connections beyond the maximum
+/// are closed at once.
+fn accept<F: FnMut(TcpStream, Slot)>(
+    listener: TcpListener,
+    shutdown: &ShutdownHandle,
+    logger: &Logger,
+    idle_timeout: Duration,
+    connections: &Connections,
+    mut serve: F,
+) {
+    for client in listener.incoming() {
+        if shutdown.is_requested() {
+            break;
+        }
+        match client {
+            Ok(stream) => {
+                let slot = match connections.admit() {
+                    Some(slot) => slot,
+                    None => {
+                        warn!(logger, "Too many connections, closing new one");
+                        continue;
+                    }
+                };
+                // responses are small and written as soon as they are ready
+                let configured = stream
+                    .set_read_timeout(Some(idle_timeout))
+                    .and_then(|_| stream.set_nodelay(true));
+                if let Err(e) = configured {
+                    error!(logger, "Unable to configure connection: {}", e);
+                    continue;
                 }
+                serve(stream, slot);
+            }
+            Err(_) => {
+                println!("Failed to process stream");
             }
         }
+    }
+}
+
+/// A request as it arrives, with an ID when the client pipelines requests
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Incoming {
+    Tagged(TaggedRequest),
+    Plain(KvRequest),
+}
+
+/// Writes responses to the connection, one whole response at a time, in the
+/// version of the protocol the client speaks
+#[derive(Clone)]
+struct Responder {
+    writer: Arc<Mutex<BufWriter<TcpStream>>>,
+    binary: bool,
+    version: Arc<AtomicU16>,
+}
+
+impl Responder {
+    fn send(&self, id: Option<u64>, response: KvResponse) -> Result<()> {
+        let version = self.version.load(Ordering::SeqCst);
+        let mut writer = self.writer.lock().unwrap();
+        if self.binary {
+            write_frame(&mut *writer, &encode_response(id.unwrap_or(0), &response, version)?)?;
+        } else {
+            writer.write_all(&encode_json(id, response, version)?)?;
+        }
+        writer.flush()?;
 
         Ok(())
     }
 
+    /// Answers `KvRequest::Hello`, switching a JSON connection to the newest
+    /// version both sides speak. A binary connection chose its version in the
+    /// handshake and keeps it.
+    fn hello(&self, id: Option<u64>, version: u16) -> Result<()> {
+        if !self.binary {
+            self.version.store(version.clamp(1, JSON_PROTOCOL_VERSION), Ordering::SeqCst);
+        }
+        let version = self.version.load(Ordering::SeqCst);
+
+        self.send(id, KvResponse::Success(Some(version.to_string())))
+    }
+
+    /// Whether the client has closed the connection. A streaming request
+    /// reads nothing more from the client, so it has to look.
+    fn peer_closed(&self) -> bool {
+        let writer = self.writer.lock().unwrap();
+        let stream = writer.get_ref();
+        if stream.set_read_timeout(Some(PEEK_TIMEOUT)).is_err() {
+            return true;
+        }
+        match stream.peek(&mut [0; 1]) {
+            Ok(read) => read == 0,
+            Err(e) => !matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut),
+        }
+    }
 }
 
-fn handle_client<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
-    let reader = BufReader::new(&stream);
-    let writer = BufWriter::new(&stream);
-    let mut request_stream =
-        serde_json::Deserializer::from_reader(reader).into_iter::<KvRequest>();
-    let req = request_stream.next();
+/// Answers the unordered requests of a connection as the pool completes them.
+/// Responses are written by a thread of its own, so that no worker waits for
+/// a client that is slow to read them.
+struct Completions {
+    sender: mpsc::Sender<(Option<u64>, KvResponse)>,
+    /// Takes a slot for each request in flight, which is freed once its
+    /// response is written
+    slots: mpsc::SyncSender<()>,
+}
 
-    let res = req.map(|body| match body {
-        Ok(r) => match r {
-            KvRequest::Get(k) => engine
-                .get(k)
-                .map(KvResponse::Success)
-                .unwrap_or_else(|e| KvResponse::Error(e.description().to_string())),
-            KvRequest::Set(k, v) => engine
-                .set(k, v)
-                .map(|_| KvResponse::Success(None))
-                .unwrap_or_else(|e| KvResponse::Error(e.description().to_string())),
-            KvRequest::Rm(k) => engine
-                .remove(k)
+impl Completions {
+    fn start(responder: Responder) -> Completions {
+        let (sender, receiver) = mpsc::channel::<(Option<u64>, KvResponse)>();
+        let (slots, freed) = mpsc::sync_channel(MAX_IN_FLIGHT);
+        thread::spawn(move || {
+            for (id, response) in receiver {
+                let sent = responder.send(id, response);
+                let _ = freed.recv();
+                if sent.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Completions { sender, slots }
+    }
+
+    /// Runs the job on the pool and sends its response, first waiting while
+    /// too many requests are in flight
+    fn spawn<P, F>(&self, pool: &Mutex<P>, id: Option<u64>, job: F) -> Result<()>
+    where
+        P: ThreadPool,
+        F: FnOnce() -> KvResponse + Send + 'static,
+    {
+        // fails once the client has gone away
+        self.slots.send(()).map_err(|_| KvError::InternalError)?;
+        let sender = self.sender.clone();
+        pool.lock().unwrap().spawn(move || {
+            let _ = sender.send((id, job()));
+        });
+
+        Ok(())
+    }
+}
+
+/// A request with its ID, if any, and whether it may be answered out of order.
+/// An error means the rest of the input cannot be trusted.
+type Received = Result<(Option<u64>, KvRequest, bool)>;
+
+/// Serves a connection in whichever protocol the client speaks, which it can
+/// tell by the first byte.
+fn handle_client<E, P>(
+    engine: E,
+    broker: Broker,
+    backup_dir: Option<PathBuf>,
+    pool: Arc<Mutex<P>>,
+    stream: TcpStream,
+) -> Result<()>
+where
+    E: KvsEngine,
+    P: ThreadPool,
+{
+    let mut first = [0; 1];
+    if stream.peek(&mut first)? == 0 {
+        return Ok(());
+    }
+    let binary = first[0] == MAGIC[0];
+    let version = if binary { server_handshake(&mut &stream)? } else { 1 };
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let responder = Responder {
+        writer: Arc::new(Mutex::new(BufWriter::new(stream))),
+        binary,
+        version: Arc::new(AtomicU16::new(version)),
+    };
+
+    if binary {
+        let requests = std::iter::from_fn(move || match read_frame(&mut reader) {
+            Ok(Some(frame)) => Some(
+                decode_request(frame).map(|(id, req, unordered)| (Some(id), req, unordered)),
+            ),
+            // the client went away or timed out
+            Ok(None) | Err(KvError::Io(_)) => None,
+            Err(e) => Some(Err(e)),
+        });
+        serve(engine, broker, backup_dir, pool, requests, responder)
+    } else {
+        let requests = serde_json::Deserializer::from_reader(reader)
+            .into_iter::<Incoming>()
+            .map_while(|incoming| match incoming {
+                Ok(Incoming::Tagged(tagged)) => Some(Ok((Some(tagged.id), tagged.request, tagged.unordered))),
+                Ok(Incoming::Plain(req)) => Some(Ok((None, req, false))),
+                // the client went away or timed out
+                Err(e) if e.is_io() => None,
+                Err(e) => Some(Err(e.into())),
+            });
+        serve(engine, broker, backup_dir, pool, requests, responder)
+    }
+}
+
+/// Serves the requests of one connection until the client closes it or stays
+/// idle for too long. Requests are answered in order, except for unordered
+/// ones, which are answered as soon as they are done. A streaming request
+/// takes over the connection.
+fn serve<E, P, I>(
+    engine: E,
+    broker: Broker,
+    backup_dir: Option<PathBuf>,
+    pool: Arc<Mutex<P>>,
+    requests: I,
+    responder: Responder,
+) -> Result<()>
+where
+    E: KvsEngine,
+    P: ThreadPool,
+    I: Iterator<Item = Received>,
+{
+    let mut completions = None;
+    for received in requests {
+        let (id, req, unordered) = match received {
+            Ok(received) => received,
+            // the rest of the stream cannot be trusted after malformed input
+            Err(e) => return responder.send(None, KvResponse::Error(e.into())),
+        };
+
+        let req = match req {
+            KvRequest::Subscribe(patterns) => return stream_messages(broker.subscribe(patterns), id, &responder),
+            KvRequest::Hello(version) => {
+                responder.hello(id, version)?;
+                continue;
+            }
+            req => req,
+        };
+        let (engine, broker, backup_dir) = (engine.clone(), broker.clone(), backup_dir.clone());
+        if unordered && !is_stream(&req) {
+            let completions = completions.get_or_insert_with(|| Completions::start(responder.clone()));
+            completions.spawn(&pool, id, move || respond(&engine, &broker, backup_dir.as_deref(), req))?;
+            continue;
+        }
+        // streams are read on this thread, which is the connection's own
+        match offload(&pool, move || outcome(&engine, &broker, backup_dir.as_deref(), req))? {
+            Outcome::Response(response) => responder.send(id, response)?,
+            Outcome::Stream(ack, changes) => return stream_changes(ack, changes, id, &responder),
+        }
+    }
+
+    Ok(())
+}
+
+/// What became of a request once it reached the engine
+pub(crate) enum Outcome {
+    Response(KvResponse),
+    Stream(bool, Changes),
+}
+
+/// Answers a request, or subscribes to the changes it asks to stream
+pub(crate) fn outcome<E: KvsEngine>(
+    engine: &E,
+    broker: &Broker,
+    backup_dir: Option<&Path>,
+    req: KvRequest,
+) -> Outcome {
+    match open_stream(engine, &req) {
+        Some(Ok((ack, changes))) => Outcome::Stream(ack, changes),
+        Some(Err(e)) => Outcome::Response(KvResponse::Error(e.into())),
+        None => Outcome::Response(respond(engine, broker, backup_dir, req)),
+    }
+}
+
+/// Whether the request is answered with a stream, which `open_stream`
+/// subscribes to, or with the messages of its channels
+fn is_stream(req: &KvRequest) -> bool {
+    match req {
+        KvRequest::Subscribe(_) | KvRequest::Changes(_) | KvRequest::Watch(_) => true,
+        KvRequest::Keyspace(_, inner) => matches!(**inner, KvRequest::Changes(_) | KvRequest::Watch(_)),
+        _ => false,
+    }
+}
+
+/// Answers a request that is not streamed. Backups are only written within
+/// `backup_dir`, and only when the server has one.
+pub(crate) fn respond<E: KvsEngine>(
+    engine: &E,
+    broker: &Broker,
+    backup_dir: Option<&Path>,
+    req: KvRequest,
+) -> KvResponse {
+    match req {
+        KvRequest::Publish(channel, message) => {
+            KvResponse::Success(Some(broker.publish(&channel, &message).to_string()))
+        }
+        KvRequest::Backup(name) => match backup_path(backup_dir, &name) {
+            Ok(dir) => engine
+                .backup(&dir)
                 .map(|_| KvResponse::Success(None))
-                .unwrap_or_else(|e| KvResponse::Error(e.description().to_string())),
+                .unwrap_or_else(|e| KvResponse::Error(e.into())),
+            Err(e) => KvResponse::Error(e),
         },
-        Err(e) => KvResponse::Error(e.description().to_string()),
-    }).unwrap_or_else(|| KvResponse::Error("Unable to parse request".to_string()));
+        KvRequest::IncrementalBackup(previous, name) => {
+            match (backup_path(backup_dir, &previous), backup_path(backup_dir, &name)) {
+                (Ok(previous), Ok(dir)) => engine
+                    .backup_incremental(&previous, &dir)
+                    .map(|_| KvResponse::Success(None))
+                    .unwrap_or_else(|e| KvResponse::Error(e.into())),
+                (Err(e), _) | (_, Err(e)) => KvResponse::Error(e),
+            }
+        }
+        req => handle_request(engine, req),
+    }
+}
 
-    serde_json::to_writer(writer, &res)?;
+/// The directory within `backup_dir` where the backup a client named goes
+fn backup_path(backup_dir: Option<&Path>, name: &str) -> std::result::Result<PathBuf, ServerError> {
+    let backup_dir = backup_dir
+        .ok_or_else(|| ServerError::new(ErrorCode::Unsupported, "The server does not accept backup requests"))?;
+    // a name is a single directory, so a client cannot write outside of `backup_dir`
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(ServerError::new(ErrorCode::MalformedRequest, "Invalid backup name"));
+    }
+
+    Ok(backup_dir.join(name))
+}
+
+/// Acknowledges the subscription and writes each message as it is published,
+/// until the client goes away. A subscriber that fell behind is told so
+/// before the connection is closed.
+fn stream_messages(subscription: Subscription, id: Option<u64>, responder: &Responder) -> Result<()> {
+    responder.send(id, KvResponse::Success(None))?;
+    loop {
+        match subscription.recv_timeout(STREAM_TICK) {
+            Ok((channel, message)) => responder.send(id, KvResponse::Message(channel, message))?,
+            Err(RecvTimeoutError::Timeout) if responder.peer_closed() => return Ok(()),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    if subscription.lagged() {
+        responder.send(id, KvResponse::Error(KvError::Lagged.into()))?;
+    }
 
     Ok(())
 }
+
+/// Streams the changes to the watched keys, or every change, as responses.
+/// A subscriber that fell behind is told so before the stream ends.
+pub(crate) struct Changes {
+    stream: ChangeStream,
+    watched: Option<Watched>,
+    ended: bool,
+}
+
+impl Changes {
+    fn new(stream: ChangeStream, watched: Option<Watched>) -> Self {
+        Changes {
+            stream,
+            watched,
+            ended: false,
+        }
+    }
+
+    /// Waits at most `timeout` for the next response, so that the client can
+    /// be checked on while the watched keys are quiet. Fails with
+    /// `Disconnected` once the stream has ended.
+    pub(crate) fn recv_timeout(&mut self, timeout: Duration) -> std::result::Result<KvResponse, RecvTimeoutError> {
+        if self.ended {
+            return Err(RecvTimeoutError::Disconnected);
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.stream.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(change) if self.watched.as_ref().is_none_or(|w| w.matches(&change.key)) => {
+                    return Ok(KvResponse::Change(change))
+                }
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => return Err(RecvTimeoutError::Timeout),
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.ended = true;
+                    if self.stream.lagged() {
+                        return Ok(KvResponse::Error(KvError::Lagged.into()));
+                    }
+                    return Err(RecvTimeoutError::Disconnected);
+                }
+            }
+        }
+    }
+}
+
+/// Subscribes to the engine's changes if the request asks for a stream,
+/// returning whether to acknowledge the request before streaming
+pub(crate) fn open_stream<E: KvsEngine>(engine: &E, req: &KvRequest) -> Option<Result<(bool, Changes)>> {
+    match req {
+        KvRequest::Changes(from) => Some(engine.subscribe(*from).map(|s| (false, Changes::new(s, None)))),
+        KvRequest::Watch(watched) => Some(watch(engine, watched.clone())),
+        KvRequest::Keyspace(name, inner) => match **inner {
+            KvRequest::Changes(_) | KvRequest::Watch(_) => {
+                Some(engine.existing_keyspace(name).and_then(|k| open_stream(&k, inner).unwrap()))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Subscribes to the changes made to the watched keys from now on
+fn watch<E: KvsEngine>(engine: &E, watched: Watched) -> Result<(bool, Changes)> {
+    let from = engine.last_sequence()? + 1;
+    let stream = engine.subscribe(from)?;
+
+    Ok((true, Changes::new(stream, Some(watched))))
+}
+
+/// Writes each change as it happens until the stream ends or the client goes
+/// away, which is checked whenever there has been nothing to write for a while
+fn stream_changes(ack: bool, mut changes: Changes, id: Option<u64>, responder: &Responder) -> Result<()> {
+    if ack {
+        responder.send(id, KvResponse::Success(None))?;
+    }
+    loop {
+        match changes.recv_timeout(STREAM_TICK) {
+            Ok(response) => responder.send(id, response)?,
+            Err(RecvTimeoutError::Timeout) if responder.peer_closed() => return Ok(()),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+fn handle_request<E: KvsEngine>(engine: &E, req: KvRequest) -> KvResponse {
+    match req {
+        KvRequest::Get(k) => engine
+            .get(k)
+            .map(KvResponse::Success)
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::Set(k, v) => engine
+            .set(k, v)
+            .map(|_| KvResponse::Success(None))
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::Rm(k) => engine
+            .remove(k)
+            .map(|_| KvResponse::Success(None))
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::Incr(k, delta) => engine
+            .incr_by(k, delta)
+            .map(|v| KvResponse::Success(Some(v.to_string())))
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::Decr(k, delta) => engine
+            .decr_by(k, delta)
+            .map(|v| KvResponse::Success(Some(v.to_string())))
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::Append(k, v) => engine
+            .append(k, v)
+            .map(|_| KvResponse::Success(None))
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::GetRange(k, offset, len) => engine
+            .get_range(k, offset, len)
+            .map(KvResponse::Success)
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::HSet(k, field, v) => engine
+            .hset(k, field, v)
+            .map(|_| KvResponse::Success(None))
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::HGet(k, field) => engine
+            .hget(k, field)
+            .map(KvResponse::Success)
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::HGetAll(k) => engine
+            .hgetall(k)
+            .and_then(|hash| to_json(&hash))
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::LPush(k, v) => engine
+            .lpush(k, v)
+            .map(|len| KvResponse::Success(Some(len.to_string())))
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::RPop(k) => engine
+            .rpop(k)
+            .map(KvResponse::Success)
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::LRange(k, start, stop) => engine
+            .lrange(k, start, stop)
+            .and_then(|list| to_json(&list))
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::SAdd(k, v) => engine
+            .sadd(k, v)
+            .map(|added| KvResponse::Success(Some(added.to_string())))
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::SMembers(k) => engine
+            .smembers(k)
+            .and_then(|set| to_json(&set))
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::CreateIndex(name, path) => engine
+            .create_index(&name, &path)
+            .map(|_| KvResponse::Success(None))
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::DropIndex(name) => engine
+            .drop_index(&name)
+            .map(|_| KvResponse::Success(None))
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::QueryIndex(name, value) => engine
+            .query_index(&name, &value)
+            .and_then(|keys| to_json(&keys))
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::Changes(_) | KvRequest::Watch(_) => {
+            KvResponse::Error(ServerError::new(ErrorCode::MalformedRequest, "Changes can only be streamed"))
+        }
+        KvRequest::Publish(..) | KvRequest::Subscribe(_) => {
+            KvResponse::Error(ServerError::new(ErrorCode::MalformedRequest, "Channels do not belong to a keyspace"))
+        }
+        KvRequest::Backup(_) | KvRequest::IncrementalBackup(..) => {
+            KvResponse::Error(ServerError::new(ErrorCode::MalformedRequest, "Backups are of the whole store"))
+        }
+        KvRequest::Keyspace(name, req) => in_keyspace(engine, &name, &req)
+            .map(|keyspace| handle_request(&keyspace, *req))
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::DropKeyspace(name) => engine
+            .drop_keyspace(&name)
+            .map(|_| KvResponse::Success(None))
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::Scan(start, end, limit) => engine
+            .scan(start, end, limit)
+            .and_then(|pairs| to_json(&pairs))
+            .unwrap_or_else(|e| KvResponse::Error(e.into())),
+        KvRequest::Batch(requests) => KvResponse::Batch(
+            requests
+                .into_iter()
+                .map(|req| match req {
+                    req if req.batchable() => handle_request(engine, req),
+                    _ => KvResponse::Error(ServerError::new(ErrorCode::MalformedRequest, "Not allowed in a batch")),
+                })
+                .collect(),
+        ),
+        KvRequest::Hello(_) => {
+            KvResponse::Error(ServerError::new(ErrorCode::MalformedRequest, "Hello applies to the whole connection"))
+        }
+    }
+}
+
+/// Opens the keyspace a request runs in, creating it only for a request that stores data
+fn in_keyspace<E: KvsEngine>(engine: &E, name: &str, req: &KvRequest) -> Result<E> {
+    if req.stores_data() {
+        engine.keyspace(name)
+    } else {
+        engine.existing_keyspace(name)
+    }
+}
+
+/// Responds with a composite value encoded as JSON
+fn to_json<T: Serialize>(value: &T) -> Result<KvResponse> {
+    Ok(KvResponse::Success(Some(serde_json::to_string(value)?)))
+}
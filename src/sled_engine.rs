@@ -1,46 +1,328 @@
+use crate::changes::{ChangeFeed, ChangeOp, ChangeStream};
+use crate::collections::Materialized;
 use crate::errors::KvError;
 use crate::errors::Result;
-use crate::KvsEngine;
-use sled::{Db, IVec};
+use crate::kv::check_keyspace_name;
+use crate::kv_engine::{KvsEngine, Update, ValueType};
+use sled::{Batch, Db, Event, IVec, Tree};
+use std::collections::HashMap;
+use std::ops::Bound;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const KEYSPACE_PREFIX: &str = "keyspace/";
+
+/// First byte of values of another type than plain strings, followed by a
+/// byte naming the type. It never occurs in UTF-8, so no plain string can be
+/// read back as a value of another type.
+const TYPE_MARKER: u8 = 0xff;
+
+fn encode(value_type: ValueType, value: &str) -> Vec<u8> {
+    let tag = match value_type {
+        ValueType::String => return value.as_bytes().to_vec(),
+        ValueType::Hash => b'h',
+        ValueType::List => b'l',
+        ValueType::Set => b's',
+    };
+    let mut bytes = vec![TYPE_MARKER, tag];
+    bytes.extend_from_slice(value.as_bytes());
+
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Result<(ValueType, &str)> {
+    let (value_type, value) = match bytes {
+        [TYPE_MARKER, b'h', value @ ..] => (ValueType::Hash, value),
+        [TYPE_MARKER, b'l', value @ ..] => (ValueType::List, value),
+        [TYPE_MARKER, b's', value @ ..] => (ValueType::Set, value),
+        value => (ValueType::String, value),
+    };
+    let value = std::str::from_utf8(value).map_err(|_| KvError::InternalError)?;
+
+    Ok((value_type, value))
+}
+
+/// Records the changes sled reports for a tree, from when the feed is
+/// started on. Writers wait for the events to be taken, so a thread takes
+/// them for as long as the tree exists.
+fn start_feed(tree: &Tree) -> Arc<ChangeFeed> {
+    let feed = Arc::new(ChangeFeed::in_memory());
+    let events = tree.watch_prefix(vec![]);
+    let recorder = feed.clone();
+    thread::spawn(move || {
+        for event in events {
+            let (key, op) = match event {
+                Event::Insert(key, value) => match decode(&value) {
+                    Ok((ValueType::String, value)) => (key, ChangeOp::Set(value.to_owned())),
+                    Ok((value_type, _)) => (key, ChangeOp::Modified(value_type)),
+                    Err(_) => continue,
+                },
+                Event::Remove(key) => (key, ChangeOp::Remove),
+            };
+            let key = String::from_utf8_lossy(&key);
+            recorder.record(&key, op);
+        }
+    });
+
+    feed
+}
 
 /// A key-value store using the Sled engine
+///
+/// Sled keeps no history of changes, so sequence numbers start over when the
+/// store is opened and only count the changes made since the first call to
+/// `subscribe` or `last_sequence` for the store or keyspace. Appends are
+/// reported as the value they result in.
 #[derive(Clone)]
 pub struct SledEngine {
-    store: Db,
+    db: Db,
+    /// The default tree, or the tree of a keyspace
+    store: Tree,
+    is_keyspace: bool,
+    /// The change feeds started so far, by tree name
+    feeds: Arc<Mutex<HashMap<Vec<u8>, Arc<ChangeFeed>>>>,
+    /// Set by `close`, for the store and all of its keyspaces
+    closed: Arc<AtomicBool>,
+    /// Collections of the tree kept decoded
+    materialized: Arc<Materialized>,
+    /// The decoded collections of every tree used so far, by tree name
+    trees: Arc<Mutex<HashMap<Vec<u8>, Arc<Materialized>>>>,
 }
 
 impl SledEngine {
     /// Open a new store
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
-        let store = Db::open(path)?;
+        let db = Db::open(path)?;
+        let store = (*db).clone();
+        let materialized = Arc::new(Materialized::default());
+        let mut trees = HashMap::new();
+        trees.insert(store.name(), materialized.clone());
+
+        Ok(SledEngine {
+            db,
+            store,
+            is_keyspace: false,
+            feeds: Arc::default(),
+            closed: Arc::default(),
+            materialized,
+            trees: Arc::new(Mutex::new(trees)),
+        })
+    }
+
+    /// Fails if the store has already been closed
+    fn check_open(&self) -> Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            Err(KvError::StoreClosed)
+        } else {
+            Ok(())
+        }
+    }
 
-        Ok(SledEngine { store })
+    /// The change feed of the tree, started the first time it is needed
+    fn feed(&self) -> Arc<ChangeFeed> {
+        let mut feeds = self.feeds.lock().unwrap();
+        feeds
+            .entry(self.store.name())
+            .or_insert_with(|| start_feed(&self.store))
+            .clone()
     }
 }
 
 impl KvsEngine for SledEngine {
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let val = self.store.get(key)?;
-        let conv = val
-            .map(|x| x.to_vec())
-            .and_then(|x| String::from_utf8(x).ok());
-
-        Ok(conv)
+    fn get_typed(&self, key: String, value_type: ValueType) -> Result<Option<String>> {
+        match self.store.get(key)? {
+            Some(bytes) => match decode(&bytes)? {
+                (found, value) if found == value_type => Ok(Some(value.to_owned())),
+                _ => Err(KvError::WrongType),
+            },
+            None => Ok(None),
+        }
     }
 
     fn set(&self, key: String, value: String) -> Result<()> {
+        self.check_open()?;
         self.store
             .insert(IVec::from(key.as_bytes()), IVec::from(value.as_bytes()))?;
-        let _ = self.store.flush();
+        self.store.flush()?;
 
         Ok(())
     }
 
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.check_open()?;
+        let mut batch = Batch::default();
+        for (key, value) in pairs {
+            batch.insert(key.as_bytes(), value.as_bytes());
+        }
+        self.store.apply_batch(batch)?;
+        self.store.flush()?;
+
+        Ok(())
+    }
+
+    fn update_typed<F>(&self, key: String, value_type: ValueType, mut f: F) -> Result<()>
+    where
+        F: FnMut(Option<&str>) -> Result<Update>,
+    {
+        self.check_open()?;
+        let mut error = None;
+        self.store.update_and_fetch(key.as_bytes(), |old| {
+            // the closure may be retried, only the last attempt counts
+            error = None;
+            let update = old.map(decode).transpose().and_then(|current| match current {
+                Some((found, _)) if found != value_type => Err(KvError::WrongType),
+                current => f(current.map(|(_, value)| value)),
+            });
+            match update {
+                Ok(Update::Unchanged) => old.map(|v| v.to_vec()),
+                Ok(Update::Set(value)) => Some(encode(value_type, &value)),
+                Ok(Update::Append(value)) => {
+                    let mut new = old.map_or_else(|| encode(value_type, ""), |v| v.to_vec());
+                    new.extend_from_slice(value.as_bytes());
+                    Some(new)
+                }
+                Err(e) => {
+                    error = Some(e);
+                    old.map(|v| v.to_vec())
+                }
+            }
+        })?;
+        if let Some(e) = error {
+            return Err(e);
+        }
+        self.store.flush()?;
+
+        Ok(())
+    }
+
+    fn append(&self, key: String, value: String) -> Result<()> {
+        self.update(key, |_| Ok(Update::Append(value.clone())))
+    }
+
     fn remove(&self, key: String) -> Result<()> {
+        self.check_open()?;
         let val = self.store.remove(IVec::from(key.as_bytes()))?;
-        let _ = self.store.flush();
+        self.store.flush()?;
 
         val.map(|_| ()).ok_or(KvError::KeyNotFound)
     }
+
+    fn scan_typed(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, ValueType, String)>> {
+        let mut entries = Vec::new();
+        for entry in self.store.range((start, end)).take(limit) {
+            let (key, value) = entry?;
+            let key = String::from_utf8(key.to_vec()).map_err(|_| KvError::InternalError)?;
+            let (value_type, value) = decode(&value)?;
+            entries.push((key, value_type, value.to_owned()));
+        }
+
+        Ok(entries)
+    }
+
+    fn create_index(&self, _name: &str, _path: &str) -> Result<()> {
+        Err(KvError::Unsupported)
+    }
+
+    fn drop_index(&self, _name: &str) -> Result<()> {
+        Err(KvError::Unsupported)
+    }
+
+    fn query_index(&self, _name: &str, _value: &str) -> Result<Vec<String>> {
+        Err(KvError::Unsupported)
+    }
+
+    fn subscribe(&self, from: u64) -> Result<ChangeStream> {
+        self.feed().subscribe(from)
+    }
+
+    fn last_sequence(&self) -> Result<u64> {
+        Ok(self.feed().last_sequence())
+    }
+
+    fn backup(&self, _dir: &Path) -> Result<()> {
+        Err(KvError::Unsupported)
+    }
+
+    fn backup_incremental(&self, _previous: &Path, _dir: &Path) -> Result<()> {
+        Err(KvError::Unsupported)
+    }
+
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        if self.is_keyspace {
+            return Err(KvError::InvalidKeyspace(name.to_owned()));
+        }
+        check_keyspace_name(name)?;
+        let store = self.db.open_tree(format!("{}{}", KEYSPACE_PREFIX, name))?;
+        let materialized = self.trees.lock().unwrap().entry(store.name()).or_default().clone();
+
+        Ok(SledEngine {
+            db: self.db.clone(),
+            store,
+            is_keyspace: true,
+            feeds: self.feeds.clone(),
+            closed: self.closed.clone(),
+            materialized,
+            trees: self.trees.clone(),
+        })
+    }
+
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        if self.is_keyspace {
+            return Ok(Vec::new());
+        }
+        let mut names: Vec<String> = self
+            .db
+            .tree_names()
+            .into_iter()
+            .flat_map(|name| String::from_utf8(name.to_vec()).ok())
+            .filter(|name| name.starts_with(KEYSPACE_PREFIX))
+            .map(|name| name[KEYSPACE_PREFIX.len()..].to_owned())
+            .collect();
+        names.sort_unstable();
+
+        Ok(names)
+    }
+
+    fn drop_keyspace(&self, name: &str) -> Result<()> {
+        check_keyspace_name(name)?;
+        let tree_name = format!("{}{}", KEYSPACE_PREFIX, name);
+        if self.is_keyspace || !self.db.drop_tree(tree_name.as_bytes())? {
+            return Err(KvError::InvalidKeyspace(name.to_owned()));
+        }
+        self.db.flush()?;
+        if let Some(feed) = self.feeds.lock().unwrap().remove(tree_name.as_bytes()) {
+            feed.close()?;
+        }
+        self.trees.lock().unwrap().remove(tree_name.as_bytes());
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.store.flush()?;
+
+        Ok(())
+    }
+
+    fn close(&self) -> Result<()> {
+        self.closed.store(true, Ordering::SeqCst);
+        self.flush()?;
+        // ends the subscriptions, as closing a `KvStore` does
+        for feed in self.feeds.lock().unwrap().values() {
+            feed.close()?;
+        }
+
+        Ok(())
+    }
+
+    fn materialized(&self) -> Option<&Materialized> {
+        Some(&self.materialized)
+    }
 }
@@ -0,0 +1,70 @@
+use kvs::{fsck, KvStore, KvsEngine, Result, SledEngine};
+use tempfile::TempDir;
+
+fn appends_extend_values<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.append("log".to_owned(), "a".to_owned())?;
+    engine.append("log".to_owned(), "bc".to_owned())?;
+    engine.append("log".to_owned(), "def".to_owned())?;
+    assert_eq!(engine.get("log".to_owned())?, Some("abcdef".to_owned()));
+
+    assert_eq!(engine.get_range("log".to_owned(), 1, 3)?, Some("bcd".to_owned()));
+    assert_eq!(engine.get_range("log".to_owned(), 4, 100)?, Some("ef".to_owned()));
+    assert_eq!(engine.get_range("log".to_owned(), 100, 1)?, Some("".to_owned()));
+    assert_eq!(engine.get_range("missing".to_owned(), 0, 1)?, None);
+
+    // a set replaces the appended value
+    engine.set("log".to_owned(), "x".to_owned())?;
+    engine.append("log".to_owned(), "y".to_owned())?;
+    assert_eq!(engine.get("log".to_owned())?, Some("xy".to_owned()));
+
+    // ranges must fall on character boundaries
+    engine.set("utf8".to_owned(), "é".to_owned())?;
+    assert!(engine.get_range("utf8".to_owned(), 1, 1).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn kvs_append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    appends_extend_values(KvStore::open(temp_dir.path())?)?;
+
+    // deltas are stitched together again after replaying the log
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("log".to_owned())?, Some("xy".to_owned()));
+    assert!(fsck::check(temp_dir.path())?.is_clean());
+
+    Ok(())
+}
+
+#[test]
+fn sled_append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    appends_extend_values(SledEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn compaction_merges_appends() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut expected = String::new();
+    for i in 0..20000 {
+        let delta = format!("{:05},", i);
+        store.append("log".to_owned(), delta.clone())?;
+        expected.push_str(&delta);
+    }
+    assert_eq!(store.get("log".to_owned())?.as_ref(), Some(&expected));
+    drop(store);
+
+    // the delta chain was merged, so the logs are much smaller than the deltas
+    let size: u64 = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert!(size < 1024 * 1024, "logs were not compacted: {} bytes", size);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("log".to_owned())?, Some(expected));
+
+    Ok(())
+}
@@ -0,0 +1,118 @@
+use kvs::async_server::AsyncKvServer;
+use kvs::changes::ChangeOp;
+use kvs::{AsyncKvsClient, KvError, KvRequest, KvResponse, KvStore, KvsClient, Result, Watched};
+use std::ops::Bound;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Should serve many persistent connections at once, and stream watches
+#[test]
+fn async_server_and_client() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let server = AsyncKvServer::new(KvStore::open(temp_dir.path())?, logger);
+    let shutdown = server.shutdown_handle();
+    let addr = "127.0.0.1:4116";
+    let runtime = tokio::runtime::Runtime::new()?;
+    let handle = runtime.spawn(server.run(addr));
+
+    runtime.block_on(async {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let watcher = AsyncKvsClient::connect(addr).await?;
+        let mut changes = watcher
+            .stream(KvRequest::Watch(Watched::Prefix("key".to_owned())))
+            .await?;
+        assert!(matches!(changes.next().await, Some(Ok(KvResponse::Success(None)))));
+
+        // every connection stays open until all of them have been used
+        let mut clients = Vec::new();
+        for _ in 0..200 {
+            let mut client = AsyncKvsClient::connect(addr).await?;
+            client.set_timeout(Some(Duration::from_secs(5)));
+            clients.push(client);
+        }
+        let tasks: Vec<_> = clients
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut client)| {
+                tokio::spawn(async move {
+                    client.set(format!("key{:03}", i), i.to_string()).await?;
+                    assert_eq!(client.get(format!("key{:03}", i)).await?, Some(i.to_string()));
+                    Ok::<_, KvError>(client)
+                })
+            })
+            .collect();
+        let mut clients = Vec::new();
+        for task in tasks {
+            clients.push(task.await.unwrap()?);
+        }
+
+        let client = &mut clients[0];
+        assert_eq!(client.get("missing".to_owned()).await?, None);
+        assert!(matches!(client.remove("missing".to_owned()).await, Err(KvError::KeyNotFound)));
+        let pairs = client
+            .scan(Bound::Included("key198".to_owned()), Bound::Unbounded, 10)
+            .await?;
+        assert_eq!(
+            pairs,
+            vec![
+                ("key198".to_owned(), "198".to_owned()),
+                ("key199".to_owned(), "199".to_owned())
+            ]
+        );
+        let results = client
+            .batch(vec![KvRequest::Incr("counter".to_owned(), 2), KvRequest::Rm("missing".to_owned())])
+            .await?;
+        assert_eq!(results[0].as_ref().ok(), Some(&Some("2".to_owned())));
+        assert!(matches!(results[1], Err(KvError::KeyNotFound)));
+
+        let mut seen = 0;
+        while seen < 200 {
+            match changes.next().await {
+                Some(Ok(KvResponse::Change(change))) => {
+                    assert!(matches!(change.op, ChangeOp::Set(_)));
+                    seen += 1;
+                }
+                other => panic!("unexpected response {:?}", other),
+            }
+        }
+
+        Ok::<_, KvError>(())
+    })?;
+
+    // the blocking client speaks the same protocol
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key042".to_owned())?, Some("42".to_owned()));
+
+    shutdown.shutdown();
+    runtime.block_on(handle).unwrap()
+}
+
+// Should take in a large value without parsing it over again as it arrives
+#[test]
+fn async_server_large_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let server = AsyncKvServer::new(KvStore::open(temp_dir.path())?, logger);
+    let shutdown = server.shutdown_handle();
+    let addr = "127.0.0.1:4123";
+    let runtime = tokio::runtime::Runtime::new()?;
+    let handle = runtime.spawn(server.run(addr));
+
+    runtime.block_on(async {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let mut client = AsyncKvsClient::connect(addr).await?;
+        client.set_timeout(Some(Duration::from_secs(10)));
+        // quotes and backslashes are escaped, so they must not end the value early
+        let value = "ab\"c\\{".repeat(512 * 1024);
+        client.set("large".to_owned(), value.clone()).await?;
+        assert_eq!(client.get("large".to_owned()).await?, Some(value));
+
+        Ok::<_, KvError>(())
+    })?;
+
+    shutdown.shutdown();
+    runtime.block_on(handle).unwrap()
+}
@@ -0,0 +1,191 @@
+use kvs::server::KvServer;
+use kvs::{KvError, KvRequest, KvStore, KvsClient, KvsEngine, Result, SharedQueueThreadPool, ThreadPool};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Should restore every key written before the backup, even with compactions running
+#[test]
+fn backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("store"))?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    // keep overwriting keys so that compaction runs during the backup
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let store = store.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                for i in 0..1000 {
+                    store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+                }
+            }
+        })
+    };
+    let manifest = store.backup_to(backup_dir.path().join("full"))?;
+    done.store(true, Ordering::SeqCst);
+    writer.join().unwrap();
+    assert!(!manifest.files.is_empty());
+
+    let restored_path = temp_dir.path().join("restored");
+    KvStore::restore(backup_dir.path().join("full"), &restored_path)?;
+    let restored = KvStore::open(&restored_path)?;
+    for i in 0..1000 {
+        assert_eq!(restored.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // restoring over an existing store is refused
+    assert!(KvStore::restore(backup_dir.path().join("full"), &restored_path).is_err());
+
+    Ok(())
+}
+
+// Should refuse to restore a backup that does not match its manifest
+#[test]
+fn restore_rejects_damaged_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("store"))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let manifest = store.backup_to(backup_dir.path())?;
+
+    let gen = manifest.files.last().unwrap().gen;
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(backup_dir.path().join(format!("{}.log", gen)))?;
+    log.write_all(b"garbage")?;
+    drop(log);
+
+    // only the bytes listed in the manifest are restored
+    KvStore::restore(backup_dir.path(), temp_dir.path().join("restored"))?;
+
+    let first = manifest.files.first().unwrap().gen;
+    let mut log = OpenOptions::new()
+        .write(true)
+        .open(backup_dir.path().join(format!("{}.log", first)))?;
+    log.write_all(b"{\"Sex\"")?;
+    drop(log);
+    assert!(KvStore::restore(backup_dir.path(), temp_dir.path().join("other")).is_err());
+    assert!(!temp_dir.path().join("other").exists());
+
+    Ok(())
+}
+
+// Should restore a base backup followed by a chain of incrementals
+#[test]
+fn incremental_backup_chain() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("store"))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let base = store.backup_to(backup_dir.path().join("base"))?;
+
+    store.remove("key1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let first = store.backup_incremental(backup_dir.path().join("base"), backup_dir.path().join("inc1"))?;
+    assert_eq!(first.since, Some(base.end));
+    assert_eq!(first.files.len(), 1);
+    assert_eq!(first.files[0].offset, base.end.offset);
+
+    // a reopened store writes to a new generation
+    drop(store);
+    let store = KvStore::open(temp_dir.path().join("store"))?;
+    store.set("key2".to_owned(), "value4".to_owned())?;
+    store.backup_incremental(backup_dir.path().join("inc1"), backup_dir.path().join("inc2"))?;
+
+    let restored_path = temp_dir.path().join("restored");
+    let chain = [
+        backup_dir.path().join("base"),
+        backup_dir.path().join("inc1"),
+        backup_dir.path().join("inc2"),
+    ];
+    KvStore::restore_chain(&chain, &restored_path)?;
+    let restored = KvStore::open(&restored_path)?;
+    assert_eq!(restored.get("key1".to_owned())?, None);
+    assert_eq!(restored.get("key2".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(restored.get("key3".to_owned())?, Some("value3".to_owned()));
+    // the restored store numbers its changes on from the last backup
+    assert_eq!(restored.last_sequence()?, 5);
+    let mut changes = restored.subscribe(6)?;
+    restored.set("key5".to_owned(), "value5".to_owned())?;
+    assert_eq!(changes.next().map(|change| change.seq), Some(6));
+
+    // a chain with a gap is refused
+    let gap = [backup_dir.path().join("base"), backup_dir.path().join("inc2")];
+    assert!(KvStore::restore_chain(&gap, temp_dir.path().join("other")).is_err());
+
+    Ok(())
+}
+
+// Should only write the backups clients request within the server's backup directory
+#[test]
+fn backup_requests_stay_in_backup_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path().join("store"))?,
+        SharedQueueThreadPool::new(2)?,
+        logger,
+    );
+    server.set_backup_dir(backup_dir.path());
+    let shutdown = server.shutdown_handle();
+    let addr = "127.0.0.1:4117";
+    let handle = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.request(KvRequest::Backup("base".to_owned()))?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.request(KvRequest::IncrementalBackup("base".to_owned(), "inc".to_owned()))?;
+    assert!(backup_dir.path().join("base").is_dir());
+    assert!(backup_dir.path().join("inc").is_dir());
+
+    for name in &["", "../escaped", "/tmp/escaped", "nested/backup"] {
+        let res = client.request(KvRequest::Backup(name.to_string()));
+        assert!(matches!(res, Err(KvError::MalformedRequest)), "{:?} was accepted", name);
+    }
+    let res = client.request(KvRequest::IncrementalBackup("base".to_owned(), "..".to_owned()));
+    assert!(matches!(res, Err(KvError::MalformedRequest)));
+    assert!(!temp_dir.path().join("escaped").exists());
+
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+
+    let chain = [backup_dir.path().join("base"), backup_dir.path().join("inc")];
+    KvStore::restore_chain(&chain, temp_dir.path().join("restored"))?;
+    let restored = KvStore::open(temp_dir.path().join("restored"))?;
+    assert_eq!(restored.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should refuse backup requests unless the server has a backup directory
+#[test]
+fn backup_requests_need_backup_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?, logger);
+    let shutdown = server.shutdown_handle();
+    let addr = "127.0.0.1:4118";
+    let handle = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    let res = client.request(KvRequest::Backup("base".to_owned()));
+    assert!(matches!(res, Err(KvError::Unsupported)));
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
@@ -0,0 +1,124 @@
+use kvs::binary_protocol::{self, Frame};
+use kvs::server::KvServer;
+use kvs::{KvError, KvRequest, KvResponse, KvStore, Result, SharedQueueThreadPool, ThreadPool};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Should decode every request and response to what was encoded
+#[test]
+fn frames_round_trip() -> Result<()> {
+    let requests = vec![
+        KvRequest::Get("key".to_owned()),
+        KvRequest::Set("key".to_owned(), "välue".to_owned()),
+        KvRequest::Set(String::new(), String::new()),
+        KvRequest::Rm("key".to_owned()),
+        KvRequest::Incr("counter".to_owned(), -3),
+    ];
+    for (id, request) in requests.into_iter().enumerate() {
+        let mut buf = Vec::new();
+        let expected = format!("{:?}", request);
+        binary_protocol::write_frame(&mut buf, &binary_protocol::encode_request(id as u64, &request, id % 2 == 0)?)?;
+        let frame = binary_protocol::read_frame(&mut buf.as_slice())?.unwrap();
+        let (decoded_id, decoded, unordered) = binary_protocol::decode_request(frame)?;
+        assert_eq!((decoded_id, format!("{:?}", decoded), unordered), (id as u64, expected, id % 2 == 0));
+    }
+
+    let responses = vec![
+        KvResponse::Success(None),
+        KvResponse::Success(Some(String::new())),
+        KvResponse::Error(KvError::KeyNotFound.into()),
+        KvResponse::Message("channel".to_owned(), "message".to_owned()),
+    ];
+    for response in responses {
+        let expected = format!("{:?}", response);
+        let frame = binary_protocol::encode_response(7, &response, binary_protocol::PROTOCOL_VERSION)?;
+        let (id, decoded) = binary_protocol::decode_response(frame)?;
+        assert_eq!((id, format!("{:?}", decoded)), (7, expected));
+    }
+
+    // version 1 sends errors as their message, which still reads back
+    let response = KvResponse::Error(KvError::KeyNotFound.into());
+    let frame = binary_protocol::encode_response(7, &response, 1)?;
+    assert_eq!(frame.payload, b"\"Key not found\"");
+    match binary_protocol::decode_response(frame)? {
+        (7, KvResponse::Error(err)) => assert!(matches!(KvError::from(err), KvError::KeyNotFound)),
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    // a truncated set payload is rejected rather than misread
+    let frame = Frame { opcode: binary_protocol::OP_SET, flags: 0, id: 0, payload: vec![0, 0, 0, 9, b'k'] };
+    assert!(binary_protocol::decode_request(frame).is_err());
+
+    // a length prefix is not trusted beyond the bytes that actually follow it
+    let mut truncated = binary_protocol::MAX_FRAME_LEN.to_be_bytes().to_vec();
+    truncated.extend_from_slice(&[binary_protocol::OP_GET, 0, 0, 0, 0, 0, 0, 0, 0, 0, b'k']);
+    assert!(binary_protocol::read_frame(&mut truncated.as_slice()).is_err());
+    let oversized = (binary_protocol::MAX_FRAME_LEN + 1).to_be_bytes();
+    assert!(binary_protocol::read_frame(&mut &oversized[..]).is_err());
+
+    Ok(())
+}
+
+// Should serve binary and JSON clients side by side
+#[test]
+fn server_detects_protocol() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?, logger);
+    let shutdown = server.shutdown_handle();
+    let addr = "127.0.0.1:4105";
+    let handle = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut connection = TcpStream::connect(addr)?;
+    assert_eq!(binary_protocol::client_handshake(&mut connection)?, binary_protocol::PROTOCOL_VERSION);
+    let mut writer = BufWriter::new(connection.try_clone()?);
+    let mut reader = BufReader::new(connection);
+    let requests = [
+        KvRequest::Set("key".to_owned(), "value".to_owned()),
+        KvRequest::Get("key".to_owned()),
+        KvRequest::Incr("counter".to_owned(), 5),
+        KvRequest::Rm("key".to_owned()),
+        KvRequest::Get("key".to_owned()),
+        KvRequest::Rm("key".to_owned()),
+    ];
+    for (id, request) in requests.iter().enumerate() {
+        binary_protocol::write_frame(&mut writer, &binary_protocol::encode_request(id as u64, request, false)?)?;
+    }
+    writer.flush()?;
+    let mut responses = Vec::new();
+    for _ in 0..requests.len() {
+        let frame = binary_protocol::read_frame(&mut reader)?.unwrap();
+        responses.push(binary_protocol::decode_response(frame)?);
+    }
+    assert_eq!(
+        format!("{:?}", responses),
+        format!(
+            "{:?}",
+            vec![
+                (0, KvResponse::Success(None)),
+                (1, KvResponse::Success(Some("value".to_owned()))),
+                (2, KvResponse::Success(Some("5".to_owned()))),
+                (3, KvResponse::Success(None)),
+                (4, KvResponse::Success(None)),
+                (5, KvResponse::Error(KvError::KeyNotFound.into())),
+            ]
+        )
+    );
+
+    let mut json = TcpStream::connect(addr)?;
+    serde_json::to_writer(&mut json, &KvRequest::Get("counter".to_owned()))?;
+    json.flush()?;
+    match serde_json::Deserializer::from_reader(&json).into_iter::<KvResponse>().next() {
+        Some(Ok(KvResponse::Success(Some(value)))) => assert_eq!(value, "5"),
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+
+    Ok(())
+}
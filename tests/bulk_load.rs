@@ -0,0 +1,76 @@
+use kvs::bulk_load::{bulk_load, bulk_load_sorted, keyspace_dir};
+use kvs::{KvStore, KvsEngine, Result};
+use tempfile::TempDir;
+
+// Should install a compacted generation over an existing store
+#[test]
+fn bulk_load_into_existing_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "old".to_owned())?;
+    store.set("other".to_owned(), "kept".to_owned())?;
+
+    // an open store would not see the loaded keys
+    assert!(bulk_load(temp_dir.path(), vec![Ok(("key0".to_owned(), "new".to_owned()))]).is_err());
+    drop(store);
+
+    let pairs = (0..1000).rev().map(|i| Ok((format!("key{}", i), format!("value{}", i))));
+    let report = bulk_load(temp_dir.path(), pairs)?;
+    assert_eq!(report.keys, 1000);
+    assert!(temp_dir.path().join(format!("{}.hint", report.gen)).exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+    assert_eq!(store.get("other".to_owned())?, Some("kept".to_owned()));
+
+    Ok(())
+}
+
+// Should keep the last value of repeated keys and refuse unsorted input
+#[test]
+fn bulk_load_sorted_input() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pairs = vec![("a", "1"), ("b", "2"), ("b", "3"), ("c", "4")];
+    let report = bulk_load_sorted(
+        temp_dir.path(),
+        pairs.into_iter().map(|(k, v)| Ok((k.to_owned(), v.to_owned()))),
+    )?;
+    assert_eq!(report.keys, 3);
+
+    let unsorted = vec![("d", "1"), ("a", "2")];
+    assert!(bulk_load_sorted(
+        temp_dir.path(),
+        unsorted.into_iter().map(|(k, v)| Ok((k.to_owned(), v.to_owned()))),
+    )
+    .is_err());
+    // just the loaded log, its hint and the lock
+    assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 3);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("b".to_owned())?, Some("3".to_owned()));
+    assert_eq!(store.get("d".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should load into a keyspace of the store and refuse invalid keyspace names
+#[test]
+fn bulk_load_into_keyspace() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(keyspace_dir(temp_dir.path(), "../users").is_err());
+
+    let pairs = vec![("alice", "1"), ("bob", "2")];
+    bulk_load(
+        keyspace_dir(temp_dir.path(), "users")?,
+        pairs.into_iter().map(|(k, v)| Ok((k.to_owned(), v.to_owned()))),
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("alice".to_owned())?, None);
+    let users = store.existing_keyspace("users")?;
+    assert_eq!(users.get("alice".to_owned())?, Some("1".to_owned()));
+    assert_eq!(users.get("bob".to_owned())?, Some("2".to_owned()));
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use kvs::changes::{Change, ChangeOp};
+use kvs::{KvError, KvStore, KvsEngine, Result};
+use std::thread;
+use tempfile::TempDir;
+
+#[test]
+fn subscribe_from_sequence() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.append("key1".to_owned(), "+".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let mut stream = store.subscribe(2)?;
+    assert_eq!(
+        stream.next(),
+        Some(Change { seq: 2, key: "key1".to_owned(), op: ChangeOp::Append("+".to_owned()) })
+    );
+    assert_eq!(stream.next().map(|c| (c.seq, c.op)), Some((3, ChangeOp::Remove)));
+
+    // later changes are delivered as they happen
+    let writer = store.clone();
+    let handle = thread::spawn(move || writer.set("key2".to_owned(), "value2".to_owned()));
+    assert_eq!(stream.next().map(|c| (c.seq, c.key)), Some((4, "key2".to_owned())));
+    handle.join().unwrap()?;
+
+    // closing the store ends the stream
+    store.close()?;
+    assert_eq!(stream.next(), None);
+    assert!(!stream.lagged());
+
+    Ok(())
+}
+
+#[test]
+fn slow_subscriber_lags() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut stream = store.subscribe(1)?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+
+    // the buffered changes are still delivered before the stream ends
+    let last = stream.by_ref().last().map(|change| change.seq);
+    assert!(last.unwrap() < 2000);
+    assert!(stream.lagged());
+
+    // resubscribing after the last change seen picks up where it stopped
+    let mut stream = store.subscribe(last.unwrap() + 1)?;
+    assert_eq!(stream.next().map(|change| change.seq), Some(last.unwrap() + 1));
+
+    Ok(())
+}
+
+#[test]
+fn sequence_survives_compaction_and_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..1000 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{:0200}", iter))?;
+        }
+    }
+    drop(store);
+
+    // only the tail is retained, but sequence numbers carry on
+    let store = KvStore::open(temp_dir.path())?;
+    match store.subscribe(1) {
+        Err(KvError::SequenceUnavailable(oldest)) => assert!(oldest > 1),
+        _ => panic!("old changes should not be retained"),
+    }
+    store.set("key0".to_owned(), "last".to_owned())?;
+    let mut stream = store.subscribe(10000)?;
+    assert_eq!(stream.next().map(|c| c.seq), Some(10000));
+    assert_eq!(stream.next().map(|c| (c.seq, c.op)), Some((10001, ChangeOp::Set("last".to_owned()))));
+
+    Ok(())
+}
+
+// Should keep the change log short while writers carry on
+#[test]
+fn change_log_is_rewritten() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..5000 {
+        store.set(format!("key{}", i % 10), i.to_string())?;
+    }
+    store.close()?;
+
+    let log = std::fs::read_to_string(temp_dir.path().join("changes"))?;
+    assert!(log.lines().count() < 5000);
+    let last: Change = serde_json::from_str(log.lines().last().unwrap())?;
+    assert_eq!(last.seq, 5000);
+
+    Ok(())
+}
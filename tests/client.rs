@@ -0,0 +1,83 @@
+use kvs::server::KvServer;
+use kvs::{KvError, KvRequest, KvStore, KvsClient, Result, SharedQueueThreadPool, ThreadPool};
+use std::net::TcpListener;
+use std::ops::Bound;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Should run requests against the server and report its errors as `KvError`s
+#[test]
+fn client_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?, logger);
+    let shutdown = server.shutdown_handle();
+    let addr = "127.0.0.1:4112";
+    let handle = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect_timeout(addr, Duration::from_secs(1))?;
+    client.set_timeout(Some(Duration::from_secs(5)))?;
+    for i in 0..5 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("missing".to_owned())?, None);
+    client.remove("key0".to_owned())?;
+    assert!(matches!(client.remove("key0".to_owned()), Err(KvError::KeyNotFound)));
+
+    let pairs = client.scan(Bound::Included("key2".to_owned()), Bound::Unbounded, 2)?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "value3".to_owned())
+        ]
+    );
+
+    let results = client.batch(vec![
+        KvRequest::Set("key5".to_owned(), "value5".to_owned()),
+        KvRequest::Incr("key5".to_owned(), 1),
+        KvRequest::Get("key5".to_owned()),
+    ])?;
+    assert!(matches!(results[0], Ok(None)));
+    assert!(matches!(results[1], Err(KvError::NotAnInteger)));
+    assert_eq!(results[2].as_ref().ok(), Some(&Some("value5".to_owned())));
+    // requests that belong to the connection or the whole store are refused
+    let results = client.batch(vec![
+        KvRequest::Publish("news".to_owned(), "hello".to_owned()),
+        KvRequest::Keyspace("tenant".to_owned(), Box::new(KvRequest::Changes(0))),
+        KvRequest::Backup("backup".to_owned()),
+        KvRequest::Get("key5".to_owned()),
+    ])?;
+    assert!(results[..3].iter().all(|res| matches!(res, Err(KvError::MalformedRequest))));
+    assert!(results[3].is_ok());
+
+    // reads do not create the keyspace, writes do
+    client.set_keyspace(Some("tenant"));
+    assert!(matches!(client.get("key1".to_owned()), Err(KvError::InvalidKeyspace(_))));
+    client.set("key1".to_owned(), "other".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("other".to_owned()));
+    client.set_keyspace(None);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!client.is_broken());
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+// Should give up on a server that does not answer and refuse further requests
+#[test]
+fn client_times_out() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:4113")?;
+    let mut client = KvsClient::connect("127.0.0.1:4113")?;
+    let (_silent, _) = listener.accept()?;
+    client.set_timeout(Some(Duration::from_millis(200)))?;
+
+    assert!(matches!(client.get("key".to_owned()), Err(KvError::Timeout)));
+    assert!(client.is_broken());
+    assert!(matches!(client.get("key".to_owned()), Err(KvError::Io(_))));
+
+    Ok(())
+}
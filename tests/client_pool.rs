@@ -0,0 +1,78 @@
+use kvs::server::KvServer;
+use kvs::{ClientPool, KvError, KvStore, Result, SharedQueueThreadPool, ThreadPool};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Should share a bounded number of connections between threads and replace
+// the ones the server closed
+#[test]
+fn pool_shares_and_replaces_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let mut server = KvServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(8)?, logger);
+    server.set_idle_timeout(Duration::from_millis(300));
+    let shutdown = server.shutdown_handle();
+    let addr = "127.0.0.1:4114";
+    let handle = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let pool = Arc::new(ClientPool::new(addr, 1, 3)?);
+    let workers: Vec<_> = (0..6)
+        .map(|t| {
+            let pool = pool.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    let key = format!("key{}-{}", t, i);
+                    pool.set(key.clone(), i.to_string())?;
+                    assert_eq!(pool.get(key)?, Some(i.to_string()));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap()?;
+    }
+    assert!(pool.open_connections() <= 3);
+
+    // the server closes the idle connections, which the pool then replaces
+    thread::sleep(Duration::from_millis(800));
+    assert_eq!(pool.get("key0-1".to_owned())?, Some("1".to_owned()));
+    pool.remove("key0-1".to_owned())?;
+    assert!(matches!(pool.remove("key0-1".to_owned()), Err(KvError::KeyNotFound)));
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+// Should retry idempotent requests on a new connection, but not other ones
+#[test]
+fn pool_retries_only_idempotent_requests() -> Result<()> {
+    // a server that hangs up on every connection without answering
+    let listener = TcpListener::bind("127.0.0.1:4115")?;
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            counter.fetch_add(1, Ordering::SeqCst);
+            drop(stream);
+        }
+    });
+
+    let mut pool = ClientPool::new("127.0.0.1:4115", 0, 2)?;
+    pool.set_retries(2, Duration::from_millis(10), Duration::from_millis(20));
+
+    assert!(pool.get("key".to_owned()).unwrap_err().is_retryable());
+    assert_eq!(accepted.swap(0, Ordering::SeqCst), 3);
+    assert!(pool.set("key".to_owned(), "value".to_owned()).is_err());
+    assert_eq!(accepted.swap(0, Ordering::SeqCst), 3);
+    assert!(pool.remove("key".to_owned()).is_err());
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    assert_eq!(pool.open_connections(), 0);
+
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use kvs::{Collections, KvError, KvStore, KvsEngine, Result, SledEngine, ValueType};
+use std::ops::Bound;
+use std::collections::{BTreeMap, BTreeSet};
+use tempfile::TempDir;
+
+fn composite_values<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.hset("user".to_owned(), "name".to_owned(), "alice".to_owned())?;
+    engine.hset("user".to_owned(), "email".to_owned(), "a@example.com".to_owned())?;
+    engine.hset("user".to_owned(), "name".to_owned(), "bob".to_owned())?;
+    assert_eq!(engine.hget("user".to_owned(), "name".to_owned())?, Some("bob".to_owned()));
+    assert_eq!(engine.hget("user".to_owned(), "age".to_owned())?, None);
+    let mut expected = BTreeMap::new();
+    expected.insert("email".to_owned(), "a@example.com".to_owned());
+    expected.insert("name".to_owned(), "bob".to_owned());
+    assert_eq!(engine.hgetall("user".to_owned())?, expected);
+
+    for element in &["a", "b", "c"] {
+        engine.lpush("queue".to_owned(), element.to_string())?;
+    }
+    assert_eq!(engine.lpush("queue".to_owned(), "d".to_owned())?, 4);
+    assert_eq!(engine.lrange("queue".to_owned(), 0, -1)?, vec!["d", "c", "b", "a"]);
+    assert_eq!(engine.lrange("queue".to_owned(), -2, 10)?, vec!["b", "a"]);
+    assert_eq!(engine.rpop("queue".to_owned())?, Some("a".to_owned()));
+    assert_eq!(engine.rpop("queue".to_owned())?, Some("b".to_owned()));
+    assert_eq!(engine.lrange("queue".to_owned(), 0, -1)?, vec!["d", "c"]);
+    assert_eq!(engine.rpop("empty".to_owned())?, None);
+
+    assert!(engine.sadd("tags".to_owned(), "red".to_owned())?);
+    assert!(engine.sadd("tags".to_owned(), "blue".to_owned())?);
+    assert!(!engine.sadd("tags".to_owned(), "red".to_owned())?);
+    let members: BTreeSet<_> = vec!["blue".to_owned(), "red".to_owned()].into_iter().collect();
+    assert_eq!(engine.smembers("tags".to_owned())?, members);
+
+    // operations must match the stored type
+    engine.set("plain".to_owned(), "value".to_owned())?;
+    for res in [
+        engine.hget("plain".to_owned(), "f".to_owned()).map(|_| ()),
+        engine.lpush("user".to_owned(), "x".to_owned()).map(|_| ()),
+        engine.sadd("queue".to_owned(), "x".to_owned()).map(|_| ()),
+        engine.smembers("user".to_owned()).map(|_| ()),
+        engine.get("user".to_owned()).map(|_| ()),
+        engine.append("queue".to_owned(), "x".to_owned()),
+        engine.incr_by("tags".to_owned(), 1).map(|_| ()),
+    ] {
+        match res {
+            Err(KvError::WrongType) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    // the type is not part of the value, no string can pass for a hash
+    engine.set("forged".to_owned(), "\u{0}kvs:hash\n{\"HSet\":[\"f\",\"v\"]}\n".to_owned())?;
+    assert!(matches!(engine.hgetall("forged".to_owned()), Err(KvError::WrongType)));
+    let strings = engine.scan(Bound::Unbounded, Bound::Unbounded, 10)?;
+    let keys: Vec<_> = strings.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, vec!["forged", "plain"]);
+    let typed = engine.scan_typed(Bound::Unbounded, Bound::Unbounded, 10)?;
+    assert_eq!(typed.len(), 5);
+    assert_eq!(typed[2].1, ValueType::List);
+
+    // a plain set replaces a value of any type
+    engine.set("tags".to_owned(), "none".to_owned())?;
+    assert_eq!(engine.get("tags".to_owned())?, Some("none".to_owned()));
+    assert!(matches!(engine.smembers("tags".to_owned()), Err(KvError::WrongType)));
+    engine.sadd("tags".to_owned(), "red".to_owned()).unwrap_err();
+    engine.remove("tags".to_owned())?;
+    assert!(engine.sadd("tags".to_owned(), "red".to_owned())?);
+
+    // repeated updates are rewritten rather than growing the value forever
+    for i in 0..100 {
+        engine.lpush("churn".to_owned(), i.to_string())?;
+        engine.rpop("churn".to_owned())?;
+    }
+    assert!(engine.get_typed("churn".to_owned(), ValueType::List)?.unwrap().len() < 100);
+
+    Ok(())
+}
+
+#[test]
+fn kvs_collections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    composite_values(KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.lrange("queue".to_owned(), 0, -1)?, vec!["d", "c"]);
+    assert_eq!(store.hget("user".to_owned(), "name".to_owned())?, Some("bob".to_owned()));
+    assert!(matches!(store.get("user".to_owned()), Err(KvError::WrongType)));
+    drop(store);
+
+    // types survive both a clean shutdown and a replay of the logs
+    let store = KvStore::open(temp_dir.path())?;
+    store.close()?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(store.lrange("user".to_owned(), 0, -1), Err(KvError::WrongType)));
+    assert_eq!(store.smembers("tags".to_owned())?.len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn sled_collections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    composite_values(SledEngine::open(temp_dir.path())?)
+}
+
+// Should not mistake the collection of one store for that of another
+#[test]
+fn collections_in_several_stores() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = KvStore::open(temp_dir.path().join("kvs"))?;
+    let sled = SledEngine::open(temp_dir.path().join("sled"))?;
+    for i in 0..10 {
+        assert_eq!(kvs.lpush("queue".to_owned(), i.to_string())?, i + 1);
+        assert_eq!(sled.lpush("queue".to_owned(), i.to_string())?, 1);
+        assert_eq!(sled.rpop("queue".to_owned())?, Some(i.to_string()));
+    }
+    assert_eq!(kvs.lrange("queue".to_owned(), 0, 1)?, vec!["9", "8"]);
+    assert_eq!(sled.lrange("queue".to_owned(), 0, -1)?, Vec::<String>::new());
+
+    // nor that of the same key in another keyspace
+    kvs.sadd("tags".to_owned(), "red".to_owned())?;
+    let other = kvs.keyspace("other")?;
+    other.sadd("tags".to_owned(), "blue".to_owned())?;
+    assert!(kvs.sadd("tags".to_owned(), "blue".to_owned())?);
+    assert_eq!(kvs.smembers("tags".to_owned())?.len(), 2);
+    assert_eq!(other.smembers("tags".to_owned())?.len(), 1);
+
+    Ok(())
+}
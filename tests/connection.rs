@@ -0,0 +1,145 @@
+use kvs::server::KvServer;
+use kvs::{KvRequest, KvResponse, KvStore, Result, SharedQueueThreadPool, ThreadPool, Watched};
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Should serve many requests over one connection and close it once idle
+#[test]
+fn connection_carries_requests_until_idle() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let mut server = KvServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?, logger);
+    server.set_idle_timeout(Duration::from_millis(500));
+    let shutdown = server.shutdown_handle();
+    let addr = "127.0.0.1:4103";
+    let handle = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut connection = TcpStream::connect(addr)?;
+    connection.set_nodelay(true)?;
+    let mut responses =
+        serde_json::Deserializer::from_reader(BufReader::new(connection.try_clone()?)).into_iter::<KvResponse>();
+    for i in 0..100 {
+        serde_json::to_writer(&mut connection, &KvRequest::Set(format!("key{}", i), format!("value{}", i)))?;
+        connection.flush()?;
+        match responses.next() {
+            Some(Ok(KvResponse::Success(None))) => {}
+            res => panic!("unexpected response {:?}", res),
+        }
+        serde_json::to_writer(&mut connection, &KvRequest::Get(format!("key{}", i)))?;
+        connection.flush()?;
+        match responses.next() {
+            Some(Ok(KvResponse::Success(Some(value)))) => assert_eq!(value, format!("value{}", i)),
+            res => panic!("unexpected response {:?}", res),
+        }
+    }
+
+    // the server hangs up on a connection that stays idle past the timeout
+    thread::sleep(Duration::from_millis(1000));
+    let mut rest = Vec::new();
+    connection.set_read_timeout(Some(Duration::from_secs(5)))?;
+    assert_eq!(connection.read_to_end(&mut rest)?, 0);
+
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+
+    Ok(())
+}
+
+// Should keep answering new clients while idle connections and streams outnumber the workers
+#[test]
+fn idle_connections_do_not_hold_workers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let mut server = KvServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?, logger);
+    server.set_resp_addr("127.0.0.1:4120")?;
+    let shutdown = server.shutdown_handle();
+    let addr = "127.0.0.1:4119";
+    let handle = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut idle = Vec::new();
+    for _ in 0..4 {
+        idle.push(TcpStream::connect(addr)?);
+        idle.push(TcpStream::connect("127.0.0.1:4120")?);
+        let mut watcher = TcpStream::connect(addr)?;
+        serde_json::to_writer(&mut watcher, &KvRequest::Watch(Watched::Prefix(String::new())))?;
+        idle.push(watcher);
+        let mut subscriber = TcpStream::connect(addr)?;
+        serde_json::to_writer(&mut subscriber, &KvRequest::Subscribe(vec!["*".to_owned()]))?;
+        idle.push(subscriber);
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    let mut connection = TcpStream::connect(addr)?;
+    connection.set_read_timeout(Some(Duration::from_secs(2)))?;
+    let mut responses =
+        serde_json::Deserializer::from_reader(BufReader::new(connection.try_clone()?)).into_iter::<KvResponse>();
+    serde_json::to_writer(&mut connection, &KvRequest::Set("key".to_owned(), "value".to_owned()))?;
+    match responses.next() {
+        Some(Ok(KvResponse::Success(None))) => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    let mut resp = TcpStream::connect("127.0.0.1:4120")?;
+    resp.set_read_timeout(Some(Duration::from_secs(2)))?;
+    resp.write_all(b"GET key\r\n")?;
+    let mut reply = [0; 11];
+    resp.read_exact(&mut reply)?;
+    assert_eq!(&reply, b"$5\r\nvalue\r\n");
+
+    drop(idle);
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+
+    Ok(())
+}
+
+// Should close connections beyond the maximum, on any front end, until one ends
+#[test]
+fn connections_beyond_the_maximum_are_closed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let mut server = KvServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?, logger);
+    server.set_resp_addr("127.0.0.1:4125")?;
+    server.set_max_connections(2);
+    let shutdown = server.shutdown_handle();
+    let addr = "127.0.0.1:4124";
+    let handle = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let first = TcpStream::connect(addr)?;
+    let mut resp = TcpStream::connect("127.0.0.1:4125")?;
+    resp.set_read_timeout(Some(Duration::from_secs(2)))?;
+    resp.write_all(b"PING\r\n")?;
+    let mut reply = [0; 7];
+    resp.read_exact(&mut reply)?;
+    assert_eq!(&reply, b"+PONG\r\n");
+
+    let mut refused = TcpStream::connect(addr)?;
+    refused.set_read_timeout(Some(Duration::from_secs(2)))?;
+    let mut rest = Vec::new();
+    assert_eq!(refused.read_to_end(&mut rest)?, 0);
+
+    // the slot of a connection is freed once the server notices it has gone
+    drop(first);
+    thread::sleep(Duration::from_millis(200));
+    let mut connection = TcpStream::connect(addr)?;
+    connection.set_read_timeout(Some(Duration::from_secs(2)))?;
+    let mut responses =
+        serde_json::Deserializer::from_reader(BufReader::new(connection.try_clone()?)).into_iter::<KvResponse>();
+    serde_json::to_writer(&mut connection, &KvRequest::Set("key".to_owned(), "value".to_owned()))?;
+    match responses.next() {
+        Some(Ok(KvResponse::Success(None))) => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    drop(resp);
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+
+    Ok(())
+}
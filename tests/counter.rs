@@ -0,0 +1,56 @@
+use kvs::{KvError, KvStore, KvsEngine, Result, SledEngine};
+use std::thread;
+use tempfile::TempDir;
+
+fn counters_are_atomic<E: KvsEngine>(engine: E) -> Result<()> {
+    assert_eq!(engine.incr_by("counter".to_owned(), 5)?, 5);
+    assert_eq!(engine.decr_by("counter".to_owned(), 2)?, 3);
+    assert_eq!(engine.get("counter".to_owned())?, Some("3".to_owned()));
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    engine.incr_by("counter".to_owned(), 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(engine.get("counter".to_owned())?, Some("803".to_owned()));
+
+    engine.set("text".to_owned(), "abc".to_owned())?;
+    match engine.incr_by("text".to_owned(), 1) {
+        Err(KvError::NotAnInteger) => {}
+        res => panic!("unexpected result {:?}", res),
+    }
+    assert_eq!(engine.get("text".to_owned())?, Some("abc".to_owned()));
+
+    engine.set("max".to_owned(), i64::MAX.to_string())?;
+    match engine.incr_by("max".to_owned(), 1) {
+        Err(KvError::IntegerOverflow) => {}
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn kvs_counters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    counters_are_atomic(KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("803".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn sled_counters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    counters_are_atomic(SledEngine::open(temp_dir.path())?)
+}
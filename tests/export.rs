@@ -0,0 +1,110 @@
+use kvs::export::{export, import, Format, KeyRange};
+use kvs::export::records;
+use kvs::{Collections, KvError, KvStore, KvsEngine, Result, SledEngine};
+use tempfile::TempDir;
+
+// Should round-trip every key through each format
+#[test]
+fn export_import_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("kvs"))?;
+    for i in 0..1500 {
+        store.set(format!("key{}", i), format!("value,\"{}\"\n", i))?;
+    }
+    store.hset("user".to_owned(), "name".to_owned(), "alice".to_owned())?;
+    store.lpush("queue".to_owned(), "job".to_owned())?;
+    store.sadd("tags".to_owned(), "red".to_owned())?;
+
+    for (name, format) in &[("jsonl", Format::JsonLines), ("csv", Format::Csv), ("bin", Format::Binary)] {
+        let mut dump = Vec::new();
+        assert_eq!(export(&store, &KeyRange::default(), *format, &mut dump)?, 1503);
+
+        let target = SledEngine::open(temp_dir.path().join(format!("sled-{}", name)))?;
+        assert_eq!(import(&target, *format, dump.as_slice())?, 1503);
+        for i in 0..1500 {
+            assert_eq!(target.get(format!("key{}", i))?, Some(format!("value,\"{}\"\n", i)));
+        }
+        assert_eq!(target.hget("user".to_owned(), "name".to_owned())?, Some("alice".to_owned()));
+
+        let target = KvStore::open(temp_dir.path().join(format!("kvs-{}", name)))?;
+        assert_eq!(import(&target, *format, dump.as_slice())?, 1503);
+        drop(target);
+        let target = KvStore::open(temp_dir.path().join(format!("kvs-{}", name)))?;
+        assert_eq!(target.get("key1499".to_owned())?, Some("value,\"1499\"\n".to_owned()));
+        assert_eq!(target.lrange("queue".to_owned(), 0, -1)?, vec!["job"]);
+        assert_eq!(target.smembers("tags".to_owned())?.len(), 1);
+
+        // bulk loads only take plain strings, and say so
+        assert!(records(*format, dump.as_slice())?.any(|record| matches!(record, Err(KvError::WrongType))));
+    }
+
+    Ok(())
+}
+
+// Should only export keys with the prefix
+#[test]
+fn export_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("user:2".to_owned(), "bob".to_owned())?;
+    store.set("users".to_owned(), "2".to_owned())?;
+
+    let range = KeyRange {
+        prefix: Some("user:".to_owned()),
+        ..KeyRange::default()
+    };
+    let mut dump = Vec::new();
+    assert_eq!(export(&store, &range, Format::JsonLines, &mut dump)?, 2);
+    assert_eq!(
+        String::from_utf8(dump).unwrap(),
+        "{\"key\":\"user:1\",\"value\":\"alice\"}\n{\"key\":\"user:2\",\"value\":\"bob\"}\n"
+    );
+
+    Ok(())
+}
+
+// Should refuse a binary dump that ends within a record
+#[test]
+fn import_truncated_binary() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let mut dump = Vec::new();
+    export(&store, &KeyRange::default(), Format::Binary, &mut dump)?;
+
+    let target = KvStore::open(temp_dir.path().join("target"))?;
+    dump.pop();
+    assert!(matches!(import(&target, Format::Binary, dump.as_slice()), Err(KvError::MalformedRequest)));
+    // a length far beyond the end of the file is not allocated up front
+    dump.truncate(dump.len() - 8);
+    dump.extend_from_slice(&u32::MAX.to_le_bytes());
+    assert!(matches!(import(&target, Format::Binary, dump.as_slice()), Err(KvError::MalformedRequest)));
+    dump.truncate(dump.len() - 2);
+    assert!(matches!(import(&target, Format::Binary, dump.as_slice()), Err(KvError::MalformedRequest)));
+
+    Ok(())
+}
+
+// Should read files written before values had types as plain strings
+#[test]
+fn import_untyped_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let jsonl = "{\"key\":\"a\",\"value\":\"1\"}\n";
+    let csv = "key,value\nb,2\n";
+    let mut bin = b"KVSDUMP1".to_vec();
+    for field in &["c", "3"] {
+        bin.extend_from_slice(&(field.len() as u32).to_le_bytes());
+        bin.extend_from_slice(field.as_bytes());
+    }
+    assert_eq!(import(&store, Format::JsonLines, jsonl.as_bytes())?, 1);
+    assert_eq!(import(&store, Format::Csv, csv.as_bytes())?, 1);
+    assert_eq!(import(&store, Format::Binary, bin.as_slice())?, 1);
+    for (key, value) in &[("a", "1"), ("b", "2"), ("c", "3")] {
+        assert_eq!(store.get(key.to_string())?, Some(value.to_string()));
+    }
+
+    Ok(())
+}
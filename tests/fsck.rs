@@ -0,0 +1,103 @@
+use kvs::fsck::{self, Problem};
+use kvs::{Collections, KvStore, KvsEngine, Result};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use tempfile::TempDir;
+
+// Should report the damaged record and salvage the rest
+#[test]
+fn fsck_detects_and_repairs_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    assert!(fsck::check(temp_dir.path())?.is_clean());
+
+    // overwrite the middle of the second record
+    let mut log = OpenOptions::new().write(true).open(temp_dir.path().join("1.log"))?;
+    log.seek(SeekFrom::Start(30))?;
+    log.write_all(b"\x00\x00\x00")?;
+    drop(log);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let out_dir = TempDir::new().expect("unable to create temporary working directory");
+    let report = fsck::repair(temp_dir.path(), out_dir.path())?;
+    assert_eq!(report.records, 2);
+    assert_eq!(report.live_keys, 2);
+    match report.problems.as_slice() {
+        [Problem::Corrupt { gen: 1, offset, .. }] => assert!(*offset > 0 && *offset <= 30),
+        problems => panic!("unexpected problems {:?}", problems),
+    }
+
+    let store = KvStore::open(out_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should check and repair the keyspaces of a store along with it
+#[test]
+fn fsck_checks_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let users = store.keyspace("users")?;
+    users.set("key1".to_owned(), "user1".to_owned())?;
+    users.set("key2".to_owned(), "user2".to_owned())?;
+    drop(users);
+    drop(store);
+    assert!(fsck::check(temp_dir.path())?.is_clean());
+
+    let log_path = temp_dir.path().join("keyspaces").join("users").join("1.log");
+    let mut log = OpenOptions::new().write(true).open(log_path)?;
+    log.seek(SeekFrom::Start(30))?;
+    log.write_all(b"\x00\x00\x00")?;
+    drop(log);
+
+    let report = fsck::check(temp_dir.path())?;
+    assert!(!report.is_clean());
+    assert!(report.problems.is_empty());
+    assert_eq!(report.keyspaces.len(), 1);
+    assert_eq!(report.keyspaces[0].0, "users");
+    assert_eq!(report.keyspaces[0].1.problems.len(), 1);
+
+    let out_dir = TempDir::new().expect("unable to create temporary working directory");
+    fsck::repair(temp_dir.path(), out_dir.path())?;
+    let store = KvStore::open(out_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.keyspace("users")?.get("key1".to_owned())?, Some("user1".to_owned()));
+    assert_eq!(store.keyspace("users")?.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should pick up again at a hash, list or set record after the damage
+#[test]
+fn fsck_salvages_typed_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.hset("user".to_owned(), "name".to_owned(), "alice".to_owned())?;
+    store.sadd("tags".to_owned(), "red".to_owned())?;
+    drop(store);
+
+    let mut log = OpenOptions::new().write(true).open(temp_dir.path().join("1.log"))?;
+    log.seek(SeekFrom::Start(30))?;
+    log.write_all(b"\x00\x00\x00")?;
+    drop(log);
+
+    let out_dir = TempDir::new().expect("unable to create temporary working directory");
+    let report = fsck::repair(temp_dir.path(), out_dir.path())?;
+    assert_eq!(report.records, 3);
+    let store = KvStore::open(out_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.hget("user".to_owned(), "name".to_owned())?, Some("alice".to_owned()));
+    assert_eq!(store.smembers("tags".to_owned())?.len(), 1);
+
+    Ok(())
+}
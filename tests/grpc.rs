@@ -0,0 +1,139 @@
+use kvs::grpc::proto::kvs_client::KvsClient;
+use kvs::grpc::proto::{
+    change, operation, operation_result, watch_request, BatchRequest, GetRequest, KeyValue, Operation,
+    RemoveRequest, ScanRequest, SetRequest, WatchRequest,
+};
+use kvs::server::KvServer;
+use kvs::{KvStore, Result, SharedQueueThreadPool, ThreadPool};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tonic::transport::Endpoint;
+use tonic::Code;
+
+fn set(key: &str, value: &str, keyspace: &str) -> SetRequest {
+    SetRequest {
+        key: key.to_owned(),
+        value: value.to_owned(),
+        keyspace: keyspace.to_owned(),
+    }
+}
+
+// Should serve unary calls and stream scans and watches over gRPC
+#[test]
+fn grpc_service() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let mut server = KvServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?, logger);
+    server.set_grpc_addr("127.0.0.1:4111")?;
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run("127.0.0.1:4110"));
+    thread::sleep(Duration::from_millis(500));
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let channel = Endpoint::from_static("http://127.0.0.1:4111").connect().await.unwrap();
+        let mut client = KvsClient::new(channel);
+
+        let mut watch = client
+            .watch(WatchRequest {
+                target: Some(watch_request::Target::Prefix("user/".to_owned())),
+                keyspace: String::new(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+
+        for (key, value) in &[("user/1", "alice"), ("user/2", "bob"), ("other", "x")] {
+            client.set(set(key, value, "")).await.unwrap();
+        }
+        client.set(set("user/1", "carol", "tenant")).await.unwrap();
+
+        let get = |key: &str, keyspace: &str| GetRequest {
+            key: key.to_owned(),
+            keyspace: keyspace.to_owned(),
+        };
+        let value = client.get(get("user/1", "")).await.unwrap().into_inner();
+        assert!(value.found);
+        assert_eq!(value.value, "alice");
+        let value = client.get(get("user/1", "tenant")).await.unwrap().into_inner();
+        assert_eq!(value.value, "carol");
+        assert!(!client.get(get("missing", "")).await.unwrap().into_inner().found);
+        // reading a keyspace that does not exist fails rather than creating it
+        let err = client.get(get("user/1", "typo")).await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        let err = client
+            .remove(RemoveRequest {
+                key: "missing".to_owned(),
+                keyspace: String::new(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+
+        let op = |op| Operation { op: Some(op) };
+        let results = client
+            .batch(BatchRequest {
+                operations: vec![
+                    op(operation::Op::Set(KeyValue {
+                        key: "user/3".to_owned(),
+                        value: "dave".to_owned(),
+                    })),
+                    op(operation::Op::Remove("other".to_owned())),
+                    op(operation::Op::Remove("other".to_owned())),
+                    op(operation::Op::Get("user/3".to_owned())),
+                ],
+                keyspace: String::new(),
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .results;
+        let results: Vec<_> = results.into_iter().map(|r| r.result.unwrap()).collect();
+        assert_eq!(results[0], operation_result::Result::Done(true));
+        assert_eq!(results[1], operation_result::Result::Done(true));
+        match &results[2] {
+            operation_result::Result::Error(err) => assert_eq!(err.code, Code::NotFound as i32),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match &results[3] {
+            operation_result::Result::Get(value) => assert_eq!(value.value, "dave"),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let mut scan = client
+            .scan(ScanRequest {
+                start: "user/2".to_owned(),
+                end: String::new(),
+                limit: 0,
+                keyspace: String::new(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let mut keys = Vec::new();
+        while let Some(pair) = scan.message().await.unwrap() {
+            keys.push(pair.key);
+        }
+        assert_eq!(keys, vec!["user/2", "user/3"]);
+
+        let mut watched = Vec::new();
+        for _ in 0..3 {
+            let change = watch.message().await.unwrap().unwrap();
+            watched.push((change.key, change.op.unwrap()));
+        }
+        assert_eq!(
+            watched,
+            vec![
+                ("user/1".to_owned(), change::Op::Set("alice".to_owned())),
+                ("user/2".to_owned(), change::Op::Set("bob".to_owned())),
+                ("user/3".to_owned(), change::Op::Set("dave".to_owned())),
+            ]
+        );
+
+    });
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
@@ -0,0 +1,95 @@
+use kvs::server::KvServer;
+use kvs::{KvStore, Result, SharedQueueThreadPool, ThreadPool};
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const HTTP_ADDR: &str = "127.0.0.1:4109";
+
+/// Sends a request and returns the status code with the body parsed as JSON,
+/// or null when there is no body
+fn send(method: &str, path: &str, body: Option<Value>) -> Result<(u16, Value)> {
+    let mut connection = TcpStream::connect(HTTP_ADDR)?;
+    let body = body.map(|b| b.to_string()).unwrap_or_default();
+    write!(
+        connection,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    )?;
+    let mut response = String::new();
+    connection.read_to_string(&mut response)?;
+
+    let status = response[9..12].parse().unwrap();
+    let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+    let body = if body.is_empty() { Value::Null } else { serde_json::from_str(body)? };
+
+    Ok((status, body))
+}
+
+// Should expose keys over HTTP with status codes matching the outcome
+#[test]
+fn http_gateway() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let mut server = KvServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?, logger);
+    server.set_http_addr(HTTP_ADDR)?;
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run("127.0.0.1:4108"));
+    thread::sleep(Duration::from_millis(500));
+
+    assert_eq!(send("PUT", "/keys/user%2F1", Some(json!({"value": "alice"})))?, (204, Value::Null));
+    assert_eq!(
+        send("GET", "/keys/user%2F1", None)?,
+        (200, json!({"key": "user/1", "value": "alice"}))
+    );
+    let (status, body) = send("GET", "/keys/missing", None)?;
+    assert_eq!((status, &body["error"]["code"]), (404, &json!("KeyNotFound")));
+    assert_eq!(send("DELETE", "/keys/missing", None)?.0, 404);
+    assert_eq!(send("PUT", "/keys/bad", Some(json!({"val": 1})))?.0, 400);
+
+    let (status, results) = send(
+        "POST",
+        "/batch",
+        Some(json!([
+            {"op": "set", "key": "user/2", "value": "bob"},
+            {"op": "set", "key": "user/3", "value": "carol"},
+            {"op": "set", "key": "zebra", "value": "stripes"},
+            {"op": "get", "key": "user/2"},
+            {"op": "get", "key": "nobody"},
+            {"op": "delete", "key": "nobody"},
+        ])),
+    )?;
+    assert_eq!(status, 200);
+    assert_eq!(results[3], json!({"value": "bob"}));
+    assert_eq!(results[4], json!({"value": null}));
+    assert_eq!(results[5]["error"]["code"], json!("KeyNotFound"));
+
+    let (status, page) = send("GET", "/keys?prefix=user%2F&limit=2", None)?;
+    assert_eq!(status, 200);
+    assert_eq!(page["items"], json!([{"key": "user/1", "value": "alice"}, {"key": "user/2", "value": "bob"}]));
+    assert_eq!(page["next"], json!("user/2"));
+    let (_, page) = send("GET", "/keys?prefix=user%2F&limit=2&after=user%2F2", None)?;
+    assert_eq!(page, json!({"items": [{"key": "user/3", "value": "carol"}], "next": null}));
+
+    // a keyspace is created by writing to it, not by reading from it
+    let (status, body) = send("GET", "/keys/user%2F1?keyspace=typo", None)?;
+    assert_eq!((status, &body["error"]["code"]), (400, &json!({"InvalidKeyspace": "typo"})));
+    assert!(!temp_dir.path().join("keyspaces").join("typo").exists());
+    assert_eq!(send("PUT", "/keys/k?keyspace=tenant", Some(json!({"value": "v"})))?.0, 204);
+    assert_eq!(send("GET", "/keys/k?keyspace=tenant", None)?.0, 200);
+
+    assert_eq!(send("DELETE", "/keys/user%2F1", None)?, (204, Value::Null));
+    assert_eq!(send("GET", "/keys/user%2F1", None)?.0, 404);
+    assert_eq!(send("POST", "/keys", None)?.0, 405);
+
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use kvs::{KvError, KvStore, KvsEngine, Result, SledEngine};
+use tempfile::TempDir;
+
+fn keyspaces_are_independent<E: KvsEngine>(engine: E) -> Result<()> {
+    let users = engine.keyspace("users")?;
+    let orders = engine.keyspace("orders")?;
+    engine.set("key1".to_owned(), "default".to_owned())?;
+    users.set("key1".to_owned(), "user".to_owned())?;
+    orders.set("key1".to_owned(), "order".to_owned())?;
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(users.get("key1".to_owned())?, Some("user".to_owned()));
+    assert_eq!(engine.keyspace("orders")?.get("key1".to_owned())?, Some("order".to_owned()));
+    assert_eq!(engine.keyspaces()?, vec!["orders".to_owned(), "users".to_owned()]);
+
+    // looking up a keyspace does not create it
+    assert_eq!(engine.existing_keyspace("users")?.get("key1".to_owned())?, Some("user".to_owned()));
+    assert!(matches!(engine.existing_keyspace("typo"), Err(KvError::InvalidKeyspace(_))));
+    assert_eq!(engine.keyspaces()?, vec!["orders".to_owned(), "users".to_owned()]);
+
+    // names must be safe and keyspaces do not nest
+    assert!(engine.keyspace("../escape").is_err());
+    assert!(users.keyspace("nested").is_err());
+
+    engine.drop_keyspace("orders")?;
+    assert_eq!(engine.keyspaces()?, vec!["users".to_owned()]);
+    assert_eq!(engine.keyspace("orders")?.get("key1".to_owned())?, None);
+    assert!(engine.drop_keyspace("missing").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn kvs_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    keyspaces_are_independent(KvStore::open(temp_dir.path())?)?;
+
+    // keyspaces persist across reopening
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keyspace("users")?.get("key1".to_owned())?, Some("user".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn sled_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    keyspaces_are_independent(SledEngine::open(temp_dir.path())?)
+}
+
+// Should back up and restore keyspaces with the store
+#[test]
+fn backup_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("store"))?;
+    store.keyspace("users")?.set("key1".to_owned(), "user".to_owned())?;
+    store.backup_to(temp_dir.path().join("base"))?;
+    store.keyspace("users")?.set("key2".to_owned(), "user2".to_owned())?;
+    store.keyspace("orders")?.set("key1".to_owned(), "order".to_owned())?;
+    store.backup_incremental(temp_dir.path().join("base"), temp_dir.path().join("inc"))?;
+
+    let chain = [temp_dir.path().join("base"), temp_dir.path().join("inc")];
+    KvStore::restore_chain(&chain, temp_dir.path().join("restored"))?;
+    let restored = KvStore::open(temp_dir.path().join("restored"))?;
+    assert_eq!(restored.keyspace("users")?.get("key2".to_owned())?, Some("user2".to_owned()));
+    assert_eq!(restored.keyspace("orders")?.get("key1".to_owned())?, Some("order".to_owned()));
+
+    Ok(())
+}
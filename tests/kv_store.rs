@@ -1,4 +1,4 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{KvError, KvStore, KvsEngine, Result, SledEngine};
 use std::sync::{Arc, Barrier};
 use std::thread;
 use tempfile::TempDir;
@@ -209,3 +209,59 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Should reopen from the clean shutdown marker and refuse writes after close
+#[test]
+fn close_and_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.flush()?;
+    store.close()?;
+    assert!(store.set("key3".to_owned(), "value3".to_owned()).is_err());
+    assert!(temp_dir.path().join("clean_shutdown").exists());
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!temp_dir.path().join("clean_shutdown").exists());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Without close the logs are replayed
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+fn writes_fail_after_close<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let users = engine.keyspace("users")?;
+    engine.close()?;
+
+    assert!(matches!(engine.set("key2".to_owned(), "value2".to_owned()), Err(KvError::StoreClosed)));
+    assert!(matches!(engine.set_batch(vec![("key2".to_owned(), "value2".to_owned())]), Err(KvError::StoreClosed)));
+    assert!(matches!(engine.append("key1".to_owned(), "+".to_owned()), Err(KvError::StoreClosed)));
+    assert!(matches!(engine.remove("key1".to_owned()), Err(KvError::StoreClosed)));
+    assert!(matches!(users.set("key1".to_owned(), "user".to_owned()), Err(KvError::StoreClosed)));
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should refuse writes after close, whatever the engine
+#[test]
+fn kvs_writes_fail_after_close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    writes_fail_after_close(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_writes_fail_after_close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    writes_fail_after_close(SledEngine::open(temp_dir.path())?)
+}
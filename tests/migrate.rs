@@ -0,0 +1,216 @@
+use kvs::changes::ChangeStream;
+use kvs::migrate::{migrate, verify, MigrationReport};
+use kvs::{Collections, KvError, KvStore, KvsEngine, Result, SledEngine, Update, ValueType};
+use std::fs::File;
+use std::io;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tempfile::TempDir;
+
+// Should copy every live key into the other engine and verify it
+#[test]
+fn migrate_kvs_to_sled() -> Result<()> {
+    let src_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dst_dir = TempDir::new().expect("unable to create temporary working directory");
+    let src = KvStore::open(src_dir.path())?;
+    for i in 0..2500 {
+        src.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    src.remove("key7".to_owned())?;
+    src.lpush("list".to_owned(), "element".to_owned())?;
+
+    let dst = SledEngine::open(dst_dir.path())?;
+    let checkpoint = dst_dir.path().join("migrate.checkpoint");
+    let report = migrate(&src, &dst, &checkpoint)?;
+    assert_eq!(report.count, 2500);
+    assert!(!checkpoint.exists());
+    // the migration closes the destination
+    drop(dst);
+    let dst = SledEngine::open(dst_dir.path())?;
+    assert_eq!(dst.get("key2000".to_owned())?, Some("value2000".to_owned()));
+    assert_eq!(dst.get("key7".to_owned())?, None);
+    assert_eq!(dst.lrange("list".to_owned(), 0, -1)?, vec!["element"]);
+
+    // an extra key in the destination fails verification
+    dst.set("extra".to_owned(), "value".to_owned())?;
+    assert!(verify(&dst, &report).is_err());
+
+    Ok(())
+}
+
+/// A store whose scans fail once a given number of keys has been read,
+/// standing in for a source that goes away during a migration
+#[derive(Clone)]
+struct FailingSource {
+    store: KvStore,
+    remaining: Arc<AtomicUsize>,
+}
+
+impl KvsEngine for FailingSource {
+    fn get_typed(&self, key: String, value_type: ValueType) -> Result<Option<String>> {
+        self.store.get_typed(key, value_type)
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.store.set(key, value)
+    }
+
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.store.set_batch(pairs)
+    }
+
+    fn update_typed<F>(&self, key: String, value_type: ValueType, f: F) -> Result<()>
+    where
+        F: FnMut(Option<&str>) -> Result<Update>,
+    {
+        self.store.update_typed(key, value_type, f)
+    }
+
+    fn append(&self, key: String, value: String) -> Result<()> {
+        self.store.append(key, value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.store.remove(key)
+    }
+
+    fn scan_typed(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, ValueType, String)>> {
+        let entries = self.store.scan_typed(start, end, limit)?;
+        let remaining = self.remaining.load(Ordering::SeqCst);
+        if entries.len() > remaining {
+            return Err(io::Error::new(io::ErrorKind::ConnectionReset, "source went away").into());
+        }
+        self.remaining.store(remaining - entries.len(), Ordering::SeqCst);
+
+        Ok(entries)
+    }
+
+    fn create_index(&self, name: &str, path: &str) -> Result<()> {
+        self.store.create_index(name, path)
+    }
+
+    fn drop_index(&self, name: &str) -> Result<()> {
+        self.store.drop_index(name)
+    }
+
+    fn query_index(&self, name: &str, value: &str) -> Result<Vec<String>> {
+        self.store.query_index(name, value)
+    }
+
+    fn subscribe(&self, from: u64) -> Result<ChangeStream> {
+        self.store.subscribe(from)
+    }
+
+    fn last_sequence(&self) -> Result<u64> {
+        self.store.last_sequence()
+    }
+
+    fn backup(&self, dir: &Path) -> Result<()> {
+        self.store.backup(dir)
+    }
+
+    fn backup_incremental(&self, previous: &Path, dir: &Path) -> Result<()> {
+        KvsEngine::backup_incremental(&self.store, previous, dir)
+    }
+
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        Ok(FailingSource {
+            store: self.store.keyspace(name)?,
+            remaining: self.remaining.clone(),
+        })
+    }
+
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        self.store.keyspaces()
+    }
+
+    fn drop_keyspace(&self, name: &str) -> Result<()> {
+        self.store.drop_keyspace(name)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.store.flush()
+    }
+
+    fn close(&self) -> Result<()> {
+        self.store.close()
+    }
+}
+
+// Should resume an interrupted migration after the last batch it copied
+#[test]
+fn migrate_resumes_from_checkpoint() -> Result<()> {
+    let src_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dst_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint = dst_dir.path().join("migrate.checkpoint");
+    let src = KvStore::open(src_dir.path())?;
+    for i in 0..2500 {
+        src.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    src.sadd("tags".to_owned(), "red".to_owned())?;
+
+    // the source fails within the second batch
+    let failing = FailingSource {
+        store: src.clone(),
+        remaining: Arc::new(AtomicUsize::new(1500)),
+    };
+    let dst = KvStore::open(dst_dir.path())?;
+    assert!(matches!(migrate(&failing, &dst, &checkpoint), Err(KvError::Io(_))));
+    let interrupted: MigrationReport = serde_json::from_reader(File::open(&checkpoint)?)?;
+    assert_eq!(interrupted.count, 1000);
+    assert_eq!(interrupted.last_key, Some("key0999".to_owned()));
+    drop(dst);
+
+    let dst = KvStore::open(dst_dir.path())?;
+    let report = migrate(&src, &dst, &checkpoint)?;
+    assert_eq!(report.count, 2501);
+    assert!(!checkpoint.exists());
+    drop(dst);
+    let dst = KvStore::open(dst_dir.path())?;
+    assert_eq!(
+        dst.scan_typed(Bound::Unbounded, Bound::Unbounded, usize::MAX)?,
+        src.scan_typed(Bound::Unbounded, Bound::Unbounded, usize::MAX)?
+    );
+    verify(&dst, &report)?;
+
+    Ok(())
+}
+
+// Should copy every keyspace along with the store
+#[test]
+fn migrate_keyspaces() -> Result<()> {
+    let src_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dst_dir = TempDir::new().expect("unable to create temporary working directory");
+    let src = KvStore::open(src_dir.path())?;
+    src.set("key1".to_owned(), "default".to_owned())?;
+    src.keyspace("users")?.set("key1".to_owned(), "user".to_owned())?;
+    src.keyspace("orders")?.set("key2".to_owned(), "order".to_owned())?;
+
+    let dst = SledEngine::open(dst_dir.path())?;
+    let checkpoint = dst_dir.path().join("migrate.checkpoint");
+    let report = migrate(&src, &dst, &checkpoint)?;
+    assert_eq!(report.count, 1);
+    assert_eq!(report.keyspaces["users"].count, 1);
+    assert_eq!(report.keyspaces["orders"].count, 1);
+    assert!(!dst_dir.path().join("migrate.checkpoint.users").exists());
+    drop(dst);
+    let dst = SledEngine::open(dst_dir.path())?;
+
+    assert_eq!(dst.keyspaces()?, vec!["orders".to_owned(), "users".to_owned()]);
+    assert_eq!(dst.keyspace("users")?.get("key1".to_owned())?, Some("user".to_owned()));
+    assert_eq!(dst.keyspace("orders")?.get("key2".to_owned())?, Some("order".to_owned()));
+    verify(&dst, &report)?;
+
+    // a keyspace that lost a key fails verification
+    dst.keyspace("users")?.remove("key1".to_owned())?;
+    assert!(verify(&dst, &report).is_err());
+
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use kvs::server::KvServer;
+use kvs::{KvRequest, KvResponse, KvStore, Result, SharedQueueThreadPool, TaggedRequest, TaggedResponse, ThreadPool};
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn tagged(id: u64, request: KvRequest, unordered: bool) -> TaggedRequest {
+    TaggedRequest { id, request, unordered }
+}
+
+// Should answer pipelined requests with their IDs, in order unless asked otherwise
+#[test]
+fn pipelined_requests_are_answered_by_id() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?, logger);
+    let shutdown = server.shutdown_handle();
+    let addr = "127.0.0.1:4104";
+    let handle = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let connection = TcpStream::connect(addr)?;
+    let mut writer = BufWriter::new(connection.try_clone()?);
+    let mut responses =
+        serde_json::Deserializer::from_reader(BufReader::new(connection)).into_iter::<TaggedResponse>();
+
+    // every request is sent before any response is read
+    for i in 0..100 {
+        serde_json::to_writer(&mut writer, &tagged(i, KvRequest::Set(format!("key{}", i), i.to_string()), false))?;
+    }
+    for i in 0..100 {
+        serde_json::to_writer(&mut writer, &tagged(100 + i, KvRequest::Get(format!("key{}", i)), false))?;
+    }
+    writer.flush()?;
+    for id in 0..200 {
+        let res = responses.next().unwrap()?;
+        assert_eq!(res.id, id);
+        match res.response {
+            KvResponse::Success(None) if id < 100 => {}
+            KvResponse::Success(Some(value)) => assert_eq!(value, (id - 100).to_string()),
+            res => panic!("unexpected response {:?}", res),
+        }
+    }
+
+    // unordered requests may complete in any order, but each exactly once
+    for i in 0..100 {
+        serde_json::to_writer(&mut writer, &tagged(200 + i, KvRequest::Get(format!("key{}", i)), true))?;
+    }
+    writer.flush()?;
+    let mut values = HashMap::new();
+    for _ in 0..100 {
+        let res = responses.next().unwrap()?;
+        match res.response {
+            KvResponse::Success(Some(value)) => assert!(values.insert(res.id, value).is_none()),
+            res => panic!("unexpected response {:?}", res),
+        }
+    }
+    for i in 0..100 {
+        assert_eq!(values[&(200 + i)], i.to_string());
+    }
+
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+
+    Ok(())
+}
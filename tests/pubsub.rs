@@ -0,0 +1,96 @@
+use kvs::server::KvServer;
+use kvs::{KvRequest, KvResponse, KvStore, Result, SharedQueueThreadPool, ThreadPool};
+use std::io::{BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn send(addr: &str, request: &KvRequest) -> Result<TcpStream> {
+    let mut connection = TcpStream::connect(addr)?;
+    serde_json::to_writer(&mut connection, request)?;
+    connection.flush()?;
+
+    Ok(connection)
+}
+
+fn publish(addr: &str, channel: &str, message: &str) -> Result<KvResponse> {
+    let connection = send(addr, &KvRequest::Publish(channel.to_owned(), message.to_owned()))?;
+    let mut responses = serde_json::Deserializer::from_reader(&connection).into_iter::<KvResponse>();
+
+    Ok(responses.next().unwrap()?)
+}
+
+#[test]
+fn messages_fan_out_to_matching_subscribers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(4)?, logger);
+    let shutdown = server.shutdown_handle();
+    let addr = "127.0.0.1:4102";
+    let handle = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut subscribers = Vec::new();
+    for patterns in &[vec!["news"], vec!["news.*", "news"]] {
+        let patterns = patterns.iter().map(|p| p.to_string()).collect();
+        let connection = send(addr, &KvRequest::Subscribe(patterns))?;
+        let mut responses =
+            serde_json::Deserializer::from_reader(BufReader::new(connection)).into_iter::<KvResponse>();
+        match responses.next() {
+            Some(Ok(KvResponse::Success(None))) => {}
+            res => panic!("subscription was not acknowledged: {:?}", res),
+        }
+        subscribers.push(responses);
+    }
+
+    match publish(addr, "news", "hello")? {
+        KvResponse::Success(Some(receivers)) => assert_eq!(receivers, "2"),
+        res => panic!("unexpected response {:?}", res),
+    }
+    match publish(addr, "news.sport", "goal")? {
+        KvResponse::Success(Some(receivers)) => assert_eq!(receivers, "1"),
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    let expected = [vec![("news", "hello")], vec![("news", "hello"), ("news.sport", "goal")]];
+    for (responses, expected) in subscribers.iter_mut().zip(expected.iter()) {
+        for &(channel, message) in expected {
+            match responses.next() {
+                Some(Ok(KvResponse::Message(c, m))) => assert_eq!((c.as_str(), m.as_str()), (channel, message)),
+                res => panic!("unexpected response {:?}", res),
+            }
+        }
+    }
+
+    // a pattern with many wildcards does not take exponential time to rule out
+    let _starred = send(addr, &KvRequest::Subscribe(vec![format!("{}x", "n*".repeat(30))]))?;
+    thread::sleep(Duration::from_millis(100));
+    match publish(addr, &"n".repeat(60), "hello")? {
+        KvResponse::Success(Some(receivers)) => assert_eq!(receivers, "0"),
+        res => panic!("unexpected response {:?}", res),
+    }
+    match publish(addr, "nxnx", "hello")? {
+        KvResponse::Success(Some(receivers)) => assert_eq!(receivers, "0"),
+        res => panic!("unexpected response {:?}", res),
+    }
+    match publish(addr, &format!("{}x", "n".repeat(30)), "hello")? {
+        KvResponse::Success(Some(receivers)) => assert_eq!(receivers, "1"),
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    // a subscriber that goes away from a quiet channel is unsubscribed
+    let quiet = send(addr, &KvRequest::Subscribe(vec!["quiet".to_owned()]))?;
+    thread::sleep(Duration::from_millis(100));
+    drop(quiet);
+    thread::sleep(Duration::from_millis(2500));
+    match publish(addr, "quiet", "hello")? {
+        KvResponse::Success(Some(receivers)) => assert_eq!(receivers, "0"),
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use kvs::server::KvServer;
+use kvs::{KvRequest, KvStore, KvsClient, Result, SharedQueueThreadPool, ThreadPool};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Sends a command as an array of bulk strings and checks the raw reply
+fn check(connection: &mut TcpStream, args: &[&str], expected: &str) -> Result<()> {
+    let mut command = format!("*{}\r\n", args.len());
+    for arg in args {
+        command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    connection.write_all(command.as_bytes())?;
+    let mut reply = vec![0; expected.len()];
+    connection.read_exact(&mut reply)?;
+    assert_eq!(String::from_utf8_lossy(&reply), expected, "reply to {:?}", args);
+
+    Ok(())
+}
+
+// Should map Redis commands onto the engine in both RESP versions
+#[test]
+fn resp_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let mut server = KvServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?, logger);
+    server.set_resp_addr("127.0.0.1:4107")?;
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run("127.0.0.1:4106"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut connection = TcpStream::connect("127.0.0.1:4107")?;
+    connection.write_all(b"PING\r\n")?;
+    let mut pong = [0; 7];
+    connection.read_exact(&mut pong)?;
+    assert_eq!(&pong, b"+PONG\r\n");
+
+    check(&mut connection, &["SET", "key", "value"], "+OK\r\n")?;
+    check(&mut connection, &["GET", "key"], "$5\r\nvalue\r\n")?;
+    check(&mut connection, &["GET", "missing"], "$-1\r\n")?;
+    check(&mut connection, &["SET", "key", "other", "NX"], "$-1\r\n")?;
+    check(&mut connection, &["SET", "new", "1", "XX"], "$-1\r\n")?;
+    check(&mut connection, &["INCR", "counter"], ":1\r\n")?;
+    check(&mut connection, &["INCRBY", "counter", "41"], ":42\r\n")?;
+    check(&mut connection, &["INCR", "key"], "-ERR value is not an integer or out of range\r\n")?;
+    check(&mut connection, &["EXISTS", "key", "counter", "missing"], ":2\r\n")?;
+    check(&mut connection, &["SCAN", "0", "COUNT", "1"], "*2\r\n$1\r\n1\r\n*1\r\n$7\r\ncounter\r\n")?;
+    // a scan may carry on over another connection, as pooled clients do
+    let mut other = TcpStream::connect("127.0.0.1:4107")?;
+    check(&mut other, &["SCAN", "1", "COUNT", "5"], "*2\r\n$1\r\n0\r\n*1\r\n$3\r\nkey\r\n")?;
+    // and may retry a step whose reply it lost
+    check(&mut connection, &["SCAN", "1", "COUNT", "5"], "*2\r\n$1\r\n0\r\n*1\r\n$3\r\nkey\r\n")?;
+    check(&mut connection, &["DEL", "counter", "missing"], ":1\r\n")?;
+    check(&mut connection, &["GET"], "-ERR wrong number of arguments for 'get' command\r\n")?;
+
+    // deadlines too far off to represent are refused
+    let forever = i64::MAX.to_string();
+    check(&mut connection, &["EXPIRE", "key", &forever], "-ERR invalid expire time in 'expire' command\r\n")?;
+    check(&mut connection, &["SET", "key", "value", "EX", &forever], "-ERR invalid expire time in 'set' command\r\n")?;
+    check(&mut connection, &["TTL", "key"], ":-1\r\n")?;
+
+    // a key written through another protocol loses its deadline
+    check(&mut connection, &["SET", "shared", "old", "PX", "100"], "+OK\r\n")?;
+    KvsClient::connect("127.0.0.1:4106")?.set("shared".to_owned(), "new".to_owned())?;
+    check(&mut connection, &["TTL", "shared"], ":-1\r\n")?;
+    thread::sleep(Duration::from_millis(200));
+    check(&mut connection, &["GET", "shared"], "$3\r\nnew\r\n")?;
+
+    // a hash, list or set exists as any key does, and SET replaces it
+    let mut client = KvsClient::connect("127.0.0.1:4106")?;
+    client.request(KvRequest::HSet("hash".to_owned(), "field".to_owned(), "value".to_owned()))?;
+    client.request(KvRequest::SAdd("set".to_owned(), "member".to_owned()))?;
+    check(&mut connection, &["EXISTS", "hash", "set"], ":2\r\n")?;
+    check(&mut connection, &["TTL", "hash"], ":-1\r\n")?;
+    check(&mut connection, &["EXPIRE", "hash", "100"], ":1\r\n")?;
+    check(&mut connection, &["TTL", "hash"], ":99\r\n")?;
+    check(&mut connection, &["SET", "hash", "other", "NX"], "$-1\r\n")?;
+    check(&mut connection, &["SET", "hash", "other", "XX"], "+OK\r\n")?;
+    check(&mut connection, &["GET", "hash"], "$5\r\nother\r\n")?;
+    check(&mut connection, &["TTL", "hash"], ":-1\r\n")?;
+    check(&mut connection, &["SET", "set", "other"], "+OK\r\n")?;
+    check(&mut connection, &["GET", "set"], "$5\r\nother\r\n")?;
+
+    check(&mut connection, &["PEXPIRE", "key", "100"], ":1\r\n")?;
+    check(&mut connection, &["TTL", "key"], ":0\r\n")?;
+    thread::sleep(Duration::from_millis(200));
+    check(&mut connection, &["EXISTS", "key"], ":0\r\n")?;
+    check(&mut connection, &["TTL", "key"], ":-2\r\n")?;
+
+    // RESP3 has a null of its own
+    let mut hello = Vec::new();
+    connection.write_all(b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n")?;
+    let mut byte = [0; 1];
+    while !hello.ends_with(b"*0\r\n") {
+        connection.read_exact(&mut byte)?;
+        hello.push(byte[0]);
+    }
+    assert!(hello.starts_with(b"%6\r\n"));
+    check(&mut connection, &["GET", "key"], "_\r\n")?;
+    check(&mut connection, &["QUIT"], "+OK\r\n")?;
+
+    // lengths beyond the limits are refused before anything is allocated for them
+    for header in &["*1\r\n$33554432\r\n", "*1048576\r\n"] {
+        let mut connection = TcpStream::connect("127.0.0.1:4107")?;
+        connection.write_all(header.as_bytes())?;
+        let mut reply = String::new();
+        connection.read_to_string(&mut reply)?;
+        assert_eq!(reply, "-ERR Protocol error\r\n");
+    }
+
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+
+    Ok(())
+}
@@ -0,0 +1,68 @@
+use kvs::{KvStore, KvsEngine, Result, SledEngine};
+use tempfile::TempDir;
+
+#[test]
+fn index_follows_sets_and_removes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.keyspace("users")?;
+    users.set("1".to_owned(), r#"{"email": "a@example.com", "age": 30}"#.to_owned())?;
+    users.set("2".to_owned(), r#"{"email": "b@example.com", "age": 30}"#.to_owned())?;
+    users.set("3".to_owned(), "not json".to_owned())?;
+
+    // existing values are indexed when the index is declared
+    users.create_index("email", "$.email")?;
+    users.create_index("age", "$.age")?;
+    assert_eq!(users.query_index("email", "a@example.com")?, vec!["1"]);
+    assert_eq!(users.query_index("age", "30")?, vec!["1", "2"]);
+
+    users.set("1".to_owned(), r#"{"email": "c@example.com", "age": 31}"#.to_owned())?;
+    users.remove("2".to_owned())?;
+    users.set_batch(vec![("4".to_owned(), r#"{"email": "a@example.com"}"#.to_owned())])?;
+    assert_eq!(users.query_index("email", "c@example.com")?, vec!["1"]);
+    assert_eq!(users.query_index("email", "a@example.com")?, vec!["4"]);
+    assert!(users.query_index("age", "30")?.is_empty());
+
+    assert!(users.create_index("email", "$.email").is_err());
+    assert!(users.create_index("bad", "email").is_err());
+    assert!(users.query_index("missing", "x").is_err());
+    users.drop_index("age")?;
+    assert!(users.query_index("age", "31").is_err());
+    drop(users);
+    drop(store);
+
+    // definitions persist and the index is rebuilt from the logs
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.keyspace("users")?;
+    assert_eq!(users.query_index("email", "c@example.com")?, vec!["1"]);
+    assert!(users.query_index("age", "31").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn index_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.create_index("city", "$.address.city")?;
+    for iter in 0..500 {
+        for key_id in 0..100 {
+            let city = if iter % 2 == 0 { "paris" } else { "rome" };
+            let value = format!(r#"{{"address": {{"city": "{}"}}, "iter": {}}}"#, city, iter);
+            store.set(format!("key{}", key_id), value)?;
+        }
+    }
+    assert_eq!(store.query_index("city", "rome")?.len(), 100);
+    assert!(store.query_index("city", "paris")?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn sled_indexes_are_unsupported() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledEngine::open(temp_dir.path())?;
+    assert!(engine.create_index("email", "$.email").is_err());
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use kvs::server::KvServer;
+use kvs::{
+    ErrorCode, KvError, KvRequest, KvStore, KvsClient, Result, ServerError, SharedQueueThreadPool, ThreadPool,
+    JSON_PROTOCOL_VERSION,
+};
+use serde_json::{json, Value};
+use std::io::{BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Should rebuild the server's error on the client side from its wire form
+#[test]
+fn errors_survive_the_wire() -> Result<()> {
+    let errors = vec![
+        KvError::KeyNotFound,
+        KvError::SequenceUnavailable(42),
+        KvError::InvalidIndex("by_email".to_owned()),
+        KvError::CorruptLog { gen: 3, offset: 128 },
+        KvError::StoreClosed,
+    ];
+    for err in errors {
+        let expected = format!("{:?}", err);
+        let message = err.to_string();
+        let json = serde_json::to_string(&ServerError::from(err))?;
+        let received: ServerError = serde_json::from_str(&json)?;
+        assert_eq!(received.message, message);
+        assert_eq!(format!("{:?}", KvError::from(received)), expected);
+    }
+
+    let not_found = ServerError::from(KvError::KeyNotFound);
+    assert_eq!(not_found.code, ErrorCode::KeyNotFound);
+    assert!(!not_found.retryable);
+    assert!(ServerError::from(KvError::StoreClosed).retryable);
+
+    // errors from libraries keep the server's message
+    let io = ServerError::from(KvError::Io(std::io::Error::other("disk full")));
+    assert!(io.retryable);
+    match KvError::from(io) {
+        KvError::Io(err) => assert_eq!(err.to_string(), "disk full"),
+        err => panic!("unexpected error {:?}", err),
+    }
+
+    Ok(())
+}
+
+// Should keep sending errors as plain messages to clients that do not ask for typed ones
+#[test]
+fn old_json_clients_get_messages() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?, logger);
+    let shutdown = server.shutdown_handle();
+    let addr = "127.0.0.1:4121";
+    let handle = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut connection = TcpStream::connect(addr)?;
+    let mut responses = serde_json::Deserializer::from_reader(BufReader::new(connection.try_clone()?)).into_iter::<Value>();
+    serde_json::to_writer(&mut connection, &KvRequest::Rm("missing".to_owned()))?;
+    connection.flush()?;
+    assert_eq!(responses.next().unwrap()?, json!({ "Error": "Key not found" }));
+
+    // the same connection switches to typed errors once it asks for them
+    serde_json::to_writer(&mut connection, &KvRequest::Hello(JSON_PROTOCOL_VERSION))?;
+    serde_json::to_writer(&mut connection, &KvRequest::Rm("missing".to_owned()))?;
+    connection.flush()?;
+    assert_eq!(responses.next().unwrap()?, json!({ "Success": JSON_PROTOCOL_VERSION.to_string() }));
+    let err: ServerError = serde_json::from_value(responses.next().unwrap()?["Error"].take())?;
+    assert_eq!(err.code, ErrorCode::KeyNotFound);
+
+    let mut client = KvsClient::connect(addr)?;
+    match client.remove("missing".to_owned()) {
+        Err(KvError::KeyNotFound) => {}
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    // a plain message from an older server still reads as an error
+    let legacy: ServerError = serde_json::from_str("\"Key not found\"")?;
+    assert_eq!(legacy.code, ErrorCode::KeyNotFound);
+    let legacy: ServerError = serde_json::from_str("\"Unable to parse request\"")?;
+    assert_eq!((legacy.code, legacy.message.as_str()), (ErrorCode::InternalError, "Unable to parse request"));
+
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use kvs::changes::ChangeOp;
+use kvs::server::KvServer;
+use kvs::{
+    KvRequest, KvResponse, KvStore, KvsEngine, Result, SharedQueueThreadPool, SledEngine, ThreadPool, Watched,
+};
+use std::io::{BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn watch_streams_matching_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvServer::new(store.clone(), SharedQueueThreadPool::new(2)?, logger);
+    let shutdown = server.shutdown_handle();
+    let addr = "127.0.0.1:4101";
+    let handle = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut connection = TcpStream::connect(addr)?;
+    serde_json::to_writer(&mut connection, &KvRequest::Watch(Watched::Prefix("config/".to_owned())))?;
+    connection.flush()?;
+    let mut responses =
+        serde_json::Deserializer::from_reader(BufReader::new(&connection)).into_iter::<KvResponse>();
+    match responses.next() {
+        Some(Ok(KvResponse::Success(None))) => {}
+        res => panic!("watch was not acknowledged: {:?}", res),
+    }
+
+    store.set("other".to_owned(), "ignored".to_owned())?;
+    store.set("config/a".to_owned(), "1".to_owned())?;
+    store.remove("config/a".to_owned())?;
+    for expected in [ChangeOp::Set("1".to_owned()), ChangeOp::Remove] {
+        match responses.next() {
+            Some(Ok(KvResponse::Change(change))) => {
+                assert_eq!(change.key, "config/a");
+                assert_eq!(change.op, expected);
+            }
+            res => panic!("unexpected response {:?}", res),
+        }
+    }
+
+    // shutting down closes the engine, which ends the stream
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    assert!(responses.next().is_none());
+
+    Ok(())
+}
+
+#[test]
+fn watch_on_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledEngine::open(temp_dir.path())?;
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvServer::new(engine.clone(), SharedQueueThreadPool::new(2)?, logger);
+    let shutdown = server.shutdown_handle();
+    let addr = "127.0.0.1:4122";
+    let handle = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut connection = TcpStream::connect(addr)?;
+    serde_json::to_writer(&mut connection, &KvRequest::Watch(Watched::Key("config".to_owned())))?;
+    connection.flush()?;
+    let mut responses =
+        serde_json::Deserializer::from_reader(BufReader::new(&connection)).into_iter::<KvResponse>();
+    match responses.next() {
+        Some(Ok(KvResponse::Success(None))) => {}
+        res => panic!("watch was not acknowledged: {:?}", res),
+    }
+
+    engine.set("other".to_owned(), "ignored".to_owned())?;
+    engine.set("config".to_owned(), "1".to_owned())?;
+    engine.remove("config".to_owned())?;
+    for expected in [ChangeOp::Set("1".to_owned()), ChangeOp::Remove] {
+        match responses.next() {
+            Some(Ok(KvResponse::Change(change))) => {
+                assert_eq!(change.key, "config");
+                assert_eq!(change.op, expected);
+            }
+            res => panic!("unexpected response {:?}", res),
+        }
+    }
+
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    assert!(responses.next().is_none());
+
+    Ok(())
+}